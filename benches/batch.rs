@@ -0,0 +1,75 @@
+//! Compares patching many locations with a single [`BatchPatcher`] against one [`PermissionWrapper`] per patch
+//!
+//! Run with `cargo +nightly bench`.
+
+#![feature(test)]
+
+extern crate test;
+
+use region::Protection;
+use test::Bencher;
+
+use libhook::patcher::batch::BatchPatcher;
+use libhook::patcher::byte::BytePatcher;
+use libhook::patcher::mem::PermissionWrapper;
+use libhook::patcher::Patcher;
+
+/// Number of patches applied per iteration
+const PATCHES: usize = 64;
+/// Number of pages the patches are spread over
+const PAGES: usize = 4;
+
+/// Read-only pages with [`PATCHES`] 4-byte locations spread over them
+struct Target {
+    /// Pages being patched
+    memory: region::Allocation,
+    /// Locations to patch
+    locations: Vec<*mut u8>,
+}
+impl Target {
+    /// Allocates the pages and picks the locations
+    fn new() -> Self {
+        let page_size = region::page::size();
+        let mut memory = region::alloc(page_size * PAGES, Protection::READ).unwrap();
+        let base = memory.as_mut_ptr::<u8>();
+        let stride = page_size * PAGES / PATCHES;
+        let locations = (0..PATCHES)
+            .map(|i| unsafe { base.add(i * stride) })
+            .collect();
+        Self { memory, locations }
+    }
+}
+
+#[bench]
+/// Patches and restores every location through one [`PermissionWrapper`] each, changing permissions twice per patch
+fn bench_permission_wrapper(b: &mut Bencher) {
+    let target = Target::new();
+    let patcher = PermissionWrapper::new(BytePatcher::new());
+    b.iter(|| {
+        let guards: Vec<_> = target
+            .locations
+            .iter()
+            .map(|&location| unsafe { patcher.patch(location, &[0xcc; 4]) }.unwrap())
+            .collect();
+        drop(guards);
+    });
+    drop(target.memory);
+}
+
+#[bench]
+/// Patches and restores every location with a single [`BatchPatcher`], changing permissions twice per page group
+fn bench_batch_patcher(b: &mut Bencher) {
+    let target = Target::new();
+    let patcher = BatchPatcher::new(BytePatcher::new());
+    let patch = [0xcc; 4];
+    let patches: Vec<_> = target
+        .locations
+        .iter()
+        .map(|&location| (location, &patch[..]))
+        .collect();
+    b.iter(|| {
+        let guard = unsafe { patcher.patch(&patches) }.unwrap();
+        drop(guard);
+    });
+    drop(target.memory);
+}
@@ -0,0 +1,64 @@
+//! Fuzzes the decode/relocate path of `CodePatcher::new`
+//!
+//! The first byte of the input selects the patch length, the rest is used as the prologue being relocated.
+//! `CodePatcher::new` must either build a trampoline or return a `CodeError`, never panic.
+//! When it succeeds, the relocated code and the trampoline must stay within their bounds:
+//! - the relocated code is the patch rounded up to the next instruction boundary, so shorter than `patch_len + max_instr_len`
+//! - the trampoline is the entry marker, the relocated instructions and the jump back, where each relocated instruction can be widened by at most `max_instr_len`
+
+#![no_main]
+
+use iced_x86::{Decoder, DecoderOptions};
+use libfuzzer_sys::fuzz_target;
+use libhook::code::x64::ENDBR64;
+use libhook::code::JMP_REL32_LEN;
+use libhook::patcher::byte::BytePatcher;
+use libhook::patcher::code::{Architecture, X64Patcher, X86_64};
+
+/// Largest patch length to try (a little over the size of `jmp_abs`)
+const MAX_PATCH_LEN: usize = 32;
+
+fuzz_target!(|data: &[u8]| {
+    let (patch_len, prologue) = match data.split_first() {
+        Some((len, prologue)) => (*len as usize % MAX_PATCH_LEN + 1, prologue),
+        None => return,
+    };
+
+    // `CodePatcher::new` reads up to `patch_len + max_instr_len` bytes, so pad the prologue with `int3`s to keep the reads in-bounds
+    let mut code = prologue.to_vec();
+    code.resize(code.len().max(patch_len + X86_64::max_instr_len()), 0xcc);
+
+    let patch = vec![0xccu8; patch_len];
+
+    // Safety: `code` is valid for the full length read by the decoder, and the patcher is never applied
+    if let Ok(patcher) = unsafe { X64Patcher::new(BytePatcher::new(), code.as_ptr(), &patch) } {
+        assert!(patcher.original().is_some());
+
+        let relocated = patcher.patch_bytes().len();
+        assert!(
+            (patch_len..patch_len + X86_64::max_instr_len()).contains(&relocated),
+            "relocated {} bytes for a {} byte patch",
+            relocated,
+            patch_len
+        );
+
+        let instructions = Decoder::with_ip(
+            X86_64::bitness(),
+            &code[..relocated],
+            code.as_ptr() as u64,
+            DecoderOptions::NONE,
+        )
+        .into_iter()
+        .count();
+        let max_trampoline_len =
+            ENDBR64.len() + relocated + instructions * X86_64::max_instr_len() + JMP_REL32_LEN;
+        assert!(
+            patcher.trampoline_bytes().len() <= max_trampoline_len,
+            "trampoline is {} bytes for {} relocated bytes ({} instructions), expected at most {}",
+            patcher.trampoline_bytes().len(),
+            relocated,
+            instructions,
+            max_trampoline_len
+        );
+    }
+});
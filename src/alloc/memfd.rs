@@ -0,0 +1,219 @@
+//! Memfd-backed executable memory
+//!
+//! Some sandboxed Linux environments refuse to map anonymous memory as writable and executable, or to `mprotect` it to executable.
+//! [`MemfdAllocator`] works around this by creating a memfd and mapping it twice: once read/write for writing code, and once read/execute close to the origin for running it.
+//! Neither mapping is ever writable and executable at the same time.
+//!
+//! Memory returned by this allocator is written through [`ExecutableMemory`]'s `Deref` impls, but must be executed from [`ExecutableMemory::exec_ptr`].
+
+use std::io;
+use std::ops::Range;
+use std::ptr;
+use std::slice;
+
+use region::Protection;
+
+use super::proximity::ProximityError;
+use super::search::{self as region_search, SearchStrategy};
+use super::{Backing, ExecutableMemory};
+
+/// Allocator that creates executable memory backed by a memfd
+pub struct MemfdAllocator {
+    /// Max distance away from the origin that the executable mapping can be
+    max_distance: usize,
+    /// Order in which free regions around the origin are tried
+    strategy: SearchStrategy,
+}
+impl MemfdAllocator {
+    /// Creates a new memfd allocator
+    pub fn new(max_distance: usize) -> Self {
+        Self::with_strategy(max_distance, SearchStrategy::default())
+    }
+    /// Creates a new memfd allocator that places the executable mapping according to `strategy`
+    pub fn with_strategy(max_distance: usize, strategy: SearchStrategy) -> Self {
+        Self {
+            max_distance,
+            strategy,
+        }
+    }
+    /// Allocates memory that's executable close to `origin` and writable through a separate mapping.
+    ///
+    /// Each allocation is rounded up to a whole number of pages and has its own memfd.
+    pub fn allocate(&self, origin: usize, size: usize) -> Result<ExecutableMemory, ProximityError> {
+        let range =
+            (origin.saturating_sub(self.max_distance))..(origin.saturating_add(self.max_distance));
+        MemfdMapping::new(&range, origin, size, self.strategy).map(|mapping| {
+            ExecutableMemory::from_backing(Backing::Memfd(mapping), Protection::READ_WRITE)
+        })
+    }
+}
+
+/// A memfd mapped read/write and read/execute
+pub(super) struct MemfdMapping {
+    /// Read/write view of the memfd
+    write: *mut u8,
+    /// Read/execute view of the memfd
+    exec: *const u8,
+    /// Requested size of the allocation
+    len: usize,
+    /// Size of each mapping (page aligned)
+    map_len: usize,
+}
+impl MemfdMapping {
+    /// Creates a memfd of at least `size` bytes and maps it, placing the executable view within `range`
+    fn new(
+        range: &Range<usize>,
+        origin: usize,
+        size: usize,
+        strategy: SearchStrategy,
+    ) -> Result<Self, ProximityError> {
+        let page_size = region::page::size();
+        let map_len = size.max(1).div_ceil(page_size) * page_size;
+
+        // Safety: the fd is owned by this function and closed before returning. The mappings keep the memory alive.
+        unsafe {
+            let fd = libc::memfd_create(c"libhook".as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(ProximityError::MemfdError(io::Error::last_os_error()));
+            }
+            let result = Self::map(fd, range, origin, size, map_len, strategy);
+            libc::close(fd);
+            result
+        }
+    }
+    /// Maps both views of `fd`
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a valid memfd
+    unsafe fn map(
+        fd: libc::c_int,
+        range: &Range<usize>,
+        origin: usize,
+        len: usize,
+        map_len: usize,
+        strategy: SearchStrategy,
+    ) -> Result<Self, ProximityError> {
+        if libc::ftruncate(fd, map_len as libc::off_t) != 0 {
+            return Err(ProximityError::MemfdError(io::Error::last_os_error()));
+        }
+
+        let write = libc::mmap(
+            ptr::null_mut(),
+            map_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if write == libc::MAP_FAILED {
+            return Err(ProximityError::MemfdError(io::Error::last_os_error()));
+        }
+
+        let exec = region_search::around(origin, Some(range.clone()), strategy)
+            .find_map(|result| match result {
+                Ok(address) => Self::map_exec(fd, address, map_len).map(Ok),
+                Err(error) => Some(Err(ProximityError::RegionError(error))),
+            })
+            .unwrap_or(Err(ProximityError::OutOfMemory));
+
+        match exec {
+            Ok(exec) => Ok(Self {
+                write: write as _,
+                exec,
+                len,
+                map_len,
+            }),
+            Err(e) => {
+                libc::munmap(write, map_len);
+                Err(e)
+            }
+        }
+    }
+    /// Tries to map the executable view of `fd` at exactly `address`
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a valid memfd of at least `map_len` bytes
+    unsafe fn map_exec(fd: libc::c_int, address: *const (), map_len: usize) -> Option<*const u8> {
+        let exec = libc::mmap(
+            address as _,
+            map_len,
+            libc::PROT_READ | libc::PROT_EXEC,
+            libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE,
+            fd,
+            0,
+        );
+        if exec == libc::MAP_FAILED {
+            return None;
+        }
+        if exec as *const () != address {
+            // Older kernels treat `MAP_FIXED_NOREPLACE` as a hint, so the mapping may have been placed elsewhere
+            libc::munmap(exec, map_len);
+            return None;
+        }
+        Some(exec as _)
+    }
+    /// Gets the address of the executable view
+    pub(super) fn exec_ptr(&self) -> *const u8 {
+        self.exec
+    }
+    /// Get a slice of the writable view
+    pub(super) fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.write, self.len) }
+    }
+    /// Get a mutable slice of the writable view
+    pub(super) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.write, self.len) }
+    }
+}
+impl Drop for MemfdMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.write as _, self.map_len);
+            libc::munmap(self.exec as _, self.map_len);
+        }
+    }
+}
+
+unsafe impl Send for MemfdMapping {}
+unsafe impl Sync for MemfdMapping {}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use region::Protection;
+
+    use crate::alloc::memfd::MemfdAllocator;
+    use crate::alloc::DETOUR_RANGE;
+
+    #[test]
+    /// Tests that code written through the writable view runs from the executable view
+    fn test_allocate() {
+        let origin = test_allocate as *const () as usize;
+        let mut memory = MemfdAllocator::new(DETOUR_RANGE)
+            .allocate(origin, 6)
+            .unwrap();
+
+        // mov eax, 42; ret
+        memory.copy_from_slice(&[0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3]);
+
+        let exec = memory.exec_ptr();
+        assert_ne!(exec, memory.as_ptr());
+        assert!((exec as usize).abs_diff(origin) < DETOUR_RANGE);
+
+        // the views are never writable and executable at the same time
+        assert_eq!(
+            region::query(exec).unwrap().protection(),
+            Protection::READ_EXECUTE
+        );
+        assert_eq!(
+            region::query(memory.as_ptr()).unwrap().protection(),
+            Protection::READ_WRITE
+        );
+
+        let function: extern "C" fn() -> u32 = unsafe { mem::transmute(exec) };
+        assert_eq!(function(), 42);
+    }
+}
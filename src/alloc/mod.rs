@@ -1,5 +1,8 @@
 //! Allocates buffers near a given address
 //!
+//! Buffers are read/write, not executable, until they're [finalized](ExecutableMemory::finalize), and read/execute afterwards.
+//! Code written into a buffer must be finalized before it's run; jumping into a buffer that's still writable faults.
+//!
 //! Taken from detour-rs with slight modifications: https://github.com/darfink/detour-rs
 
 // detour-rs - A cross-platform detour library written in Rust
@@ -57,11 +60,18 @@
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use lazy_static::lazy_static;
-use std::ops::{Deref, DerefMut};
+use region::Protection;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr;
 use std::sync::{Arc, Mutex};
 
-use self::proximity::ProximityError;
+use self::proximity::{ProximityError, RetryPolicy};
+use self::search::SearchStrategy;
 
+#[cfg(all(target_os = "linux", feature = "memfd"))]
+pub mod memfd;
 pub mod proximity;
 pub mod search;
 
@@ -72,50 +82,330 @@ pub struct ThreadAllocator(Arc<Mutex<proximity::ProximityAllocator>>);
 impl ThreadAllocator {
     /// Creates a new proximity memory allocator.
     pub fn new(max_distance: usize) -> Self {
+        Self::with_strategy(max_distance, SearchStrategy::default())
+    }
+
+    /// Creates a new proximity memory allocator that places new pools according to `strategy`.
+    pub fn with_strategy(max_distance: usize, strategy: SearchStrategy) -> Self {
         ThreadAllocator(Arc::new(Mutex::new(proximity::ProximityAllocator {
             max_distance,
             pools: Vec::new(),
+            strategy,
+            writable: Vec::new(),
+            finalized: Vec::new(),
+            retry: RetryPolicy::default(),
+            guard_pages: 0,
+            on_pool_alloc: None,
+            on_pool_release: None,
+            budget: None,
+            mapped: 0,
         })))
     }
 
-    /// Allocates read-, write- & executable memory close to `origin`.
+    /// Sets how many addresses are tried when mapping a new pool, and how often allocations are retried.
+    ///
+    /// The allocator is unlocked while waiting to retry, so other threads can keep allocating and freeing memory in the meantime.
+    pub fn set_retry_policy(&self, retry: RetryPolicy) {
+        self.0.lock().unwrap().retry = retry;
+    }
+
+    /// Sets how many inaccessible pages surround each new allocation, for catching execution that runs off the end of a trampoline.
+    ///
+    /// Allocations made before this is called keep their current layout. See [`ProximityAllocator::guard_pages`](proximity::ProximityAllocator::guard_pages).
+    pub fn set_guard_pages(&self, pages: usize) {
+        self.0.lock().unwrap().guard_pages = pages;
+    }
+
+    /// Sets a callback for every new memory pool this allocator maps, called with the pool's address and size.
+    ///
+    /// Together with [`ThreadAllocator::set_on_pool_release`], this lets a host account for the executable memory mapped on its behalf.
+    /// The callback runs while the allocator is locked, so it must not allocate from this allocator.
+    pub fn set_on_pool_alloc(&self, callback: impl Fn(*const u8, usize) + Send + Sync + 'static) {
+        self.0.lock().unwrap().on_pool_alloc = Some(Box::new(callback));
+    }
+
+    /// Sets a callback for every memory pool this allocator releases, called with the pool's address and size.
+    ///
+    /// The callback runs while the allocator is locked, so it must not allocate from this allocator.
+    pub fn set_on_pool_release(&self, callback: impl Fn(*const u8, usize) + Send + Sync + 'static) {
+        self.0.lock().unwrap().on_pool_release = Some(Box::new(callback));
+    }
+
+    /// Sets the maximum number of bytes this allocator may map across all of its pools, or `None` for no limit.
+    ///
+    /// Allocations that would need a new pool beyond the budget fail with [`ProximityError::OutOfMemory`]. Pools that are already mapped are kept even if they exceed a new budget.
+    pub fn set_budget(&self, budget: Option<usize>) {
+        self.0.lock().unwrap().budget = budget;
+    }
+
+    /// Gets the number of bytes currently mapped across all of this allocator's pools, in whole pages.
+    pub fn mapped(&self) -> usize {
+        self.0.lock().unwrap().mapped
+    }
+
+    /// Allocates memory close to `origin`.
+    ///
+    /// The memory is read/write and can't be executed until it's [finalized](ExecutableMemory::finalize).
     pub fn allocate(&self, origin: usize, size: usize) -> Result<ExecutableMemory, ProximityError> {
-        let mut allocator = self.0.lock().unwrap();
-        allocator
-            .allocate(origin, size)
-            .map(|data| ExecutableMemory {
-                allocator: self.0.clone(),
-                data,
+        self.with_retry(|allocator| allocator.allocate(origin, size))
+            .map(|data| {
+                ExecutableMemory::from_backing(
+                    Backing::Pool {
+                        allocator: self.0.clone(),
+                        data,
+                    },
+                    Protection::READ_WRITE,
+                )
             })
     }
+
+    /// Allocates memory no further than `max_distance` from `origin`.
+    ///
+    /// Use this when the memory has to be closer than the allocator's own maximum distance.
+    /// Like [`ThreadAllocator::allocate`], the memory is writable until it's [finalized](ExecutableMemory::finalize) and only executable afterwards.
+    pub fn allocate_within(
+        &self,
+        origin: usize,
+        size: usize,
+        max_distance: usize,
+    ) -> Result<ExecutableMemory, ProximityError> {
+        self.with_retry(|allocator| allocator.allocate_within(origin, size, max_distance))
+            .map(|data| {
+                ExecutableMemory::from_backing(
+                    Backing::Pool {
+                        allocator: self.0.clone(),
+                        data,
+                    },
+                    Protection::READ_WRITE,
+                )
+            })
+    }
+
+    /// Allocates memory close to `origin`, preferring memory at or near `hint`.
+    ///
+    /// Use this to keep allocations close to each other (e.g. in a region set aside for trampolines), not just to `origin`.
+    /// See [`ProximityAllocator::allocate_hint`](proximity::ProximityAllocator::allocate_hint) for how the hint is used.
+    /// The memory is writable until it's [finalized](ExecutableMemory::finalize), and can't be executed before then.
+    pub fn allocate_hint(
+        &self,
+        origin: usize,
+        size: usize,
+        hint: usize,
+    ) -> Result<ExecutableMemory, ProximityError> {
+        self.with_retry(|allocator| allocator.allocate_hint(origin, size, hint))
+            .map(|data| {
+                ExecutableMemory::from_backing(
+                    Backing::Pool {
+                        allocator: self.0.clone(),
+                        data,
+                    },
+                    Protection::READ_WRITE,
+                )
+            })
+    }
+
+    /// Allocates memory close to `origin` for each of `sizes`.
+    ///
+    /// The allocations are made under a single lock and share memory maps where possible, so this maps fewer pages than allocating each one separately.
+    /// Each allocation is returned as its own [`ExecutableMemory`], in the same order as `sizes`, and is writable until it's [finalized](ExecutableMemory::finalize).
+    /// Finalize each one once its code has been written; they can't be executed before that.
+    pub fn allocate_batch(
+        &self,
+        origin: usize,
+        sizes: &[usize],
+    ) -> Result<Vec<ExecutableMemory>, ProximityError> {
+        self.with_retry(|allocator| allocator.allocate_batch(origin, sizes))
+            .map(|allocations| {
+                allocations
+                    .into_iter()
+                    .map(|data| {
+                        ExecutableMemory::from_backing(
+                            Backing::Pool {
+                                allocator: self.0.clone(),
+                                data,
+                            },
+                            Protection::READ_WRITE,
+                        )
+                    })
+                    .collect()
+            })
+    }
+
+    /// Calls `allocate` with the allocator locked, retrying according to its [`RetryPolicy`]
+    ///
+    /// The lock is only held for each call, never while waiting between them.
+    fn with_retry<T>(
+        &self,
+        mut allocate: impl FnMut(&mut proximity::ProximityAllocator) -> Result<T, ProximityError>,
+    ) -> Result<T, ProximityError> {
+        let retry = self.0.lock().unwrap().retry;
+        retry.run(|| allocate(&mut self.0.lock().unwrap()))
+    }
 }
 
+/// State of [`ExecutableMemory`] that can still be written to
+pub struct Writable;
+/// State of [`ExecutableMemory`] that has been made read-only by [`ExecutableMemory::finalize`]
+pub struct Finalized;
+
 /// A handle for allocated proximity memory.
-pub struct ExecutableMemory {
-    /// Proximity allocator for the executable code to reside
-    allocator: Arc<Mutex<proximity::ProximityAllocator>>,
-    /// Actual allocation where the executable code resides
-    data: proximity::Allocation,
+///
+/// Memory starts out [`Writable`] and can be made read/execute-only with [`ExecutableMemory::finalize`] once the code has been written.
+pub struct ExecutableMemory<S = Writable> {
+    /// Memory backing the allocation
+    backing: Backing,
+    /// Access the memory currently allows. Updated on every transition
+    protection: Protection,
+    /// Whether the memory can still be written to
+    _state: PhantomData<S>,
+}
+
+/// Memory backing an [`ExecutableMemory`]
+enum Backing {
+    /// Allocation from a proximity memory pool
+    Pool {
+        /// Proximity allocator for the executable code to reside
+        allocator: Arc<Mutex<proximity::ProximityAllocator>>,
+        /// Actual allocation where the executable code resides
+        data: proximity::Allocation,
+    },
+    /// Memfd mapped twice, once for writing and once for executing
+    #[cfg(all(target_os = "linux", feature = "memfd"))]
+    Memfd(memfd::MemfdMapping),
+}
+
+impl<S> ExecutableMemory<S> {
+    /// Wraps `backing`, which currently allows `protection`, in a handle
+    fn from_backing(backing: Backing, protection: Protection) -> Self {
+        Self {
+            backing,
+            protection,
+            _state: PhantomData,
+        }
+    }
+
+    /// Gets the access this memory currently allows
+    ///
+    /// This is read/write until the memory is finalized, and read/execute afterwards.
+    /// Pool allocations share pages, so the pages themselves may briefly allow more than this while a neighbouring allocation is being written.
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+
+    /// Gets the address that code in this memory is executed from
+    ///
+    /// For most allocations this is the same as [`as_ptr`](slice::as_ptr), but memfd-backed memory is written through a different mapping than the one it's executed from.
+    /// Always use this address when generating code that will run from this memory.
+    pub fn exec_ptr(&self) -> *const u8 {
+        match &self.backing {
+            Backing::Pool { data, .. } => data.as_ptr(),
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(mapping) => mapping.exec_ptr(),
+        }
+    }
+
+    /// Gets the address that code in this memory is executed from, for jump and displacement math
+    ///
+    /// This is [`exec_ptr`](Self::exec_ptr) as an integer.
+    pub fn addr(&self) -> usize {
+        self.exec_ptr() as usize
+    }
+
+    /// Gets the range of addresses that code in this memory is executed from
+    pub fn range(&self) -> Range<usize> {
+        self.addr()..self.addr() + self.len()
+    }
+}
+
+impl<S> ExecutableMemory<S> {
+    /// Frees the memory, reporting failures that dropping it would only log
+    pub fn release(self) -> Result<(), ProximityError> {
+        // Move the backing out so it isn't released again on drop
+        let this = ManuallyDrop::new(self);
+        // Safety: `this` is never used or dropped again
+        let backing = unsafe { ptr::read(&this.backing) };
+
+        match backing {
+            Backing::Pool { allocator, data } => allocator
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .try_release(&data),
+            // The mappings are released when the backing is dropped
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(_) => Ok(()),
+        }
+    }
+}
+
+impl ExecutableMemory<Writable> {
+    /// Makes the memory read/execute-only now that the code has been written
+    ///
+    /// Pool allocations can share pages with each other, so pages shared with an allocation that hasn't been finalized yet stay writable until that allocation is finalized or freed.
+    /// Memfd-backed memory is never writable and executable through the same mapping, so finalizing it only gives up write access.
+    pub fn finalize(self) -> Result<ExecutableMemory<Finalized>, region::Error> {
+        // Move the backing out without releasing it
+        let this = ManuallyDrop::new(self);
+        // Safety: `this` is never used or dropped again
+        let backing = unsafe { ptr::read(&this.backing) };
+
+        let result = match &backing {
+            Backing::Pool { allocator, data } => allocator.lock().unwrap().finalize(data),
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(_) => Ok(()),
+        };
+        if let Err(e) = result {
+            drop(ExecutableMemory::<Writable>::from_backing(
+                backing,
+                this.protection,
+            ));
+            return Err(e);
+        }
+
+        Ok(ExecutableMemory::from_backing(
+            backing,
+            Protection::READ_EXECUTE,
+        ))
+    }
 }
 
-impl Drop for ExecutableMemory {
+impl<S> Drop for ExecutableMemory<S> {
     fn drop(&mut self) {
-        // Release the associated memory map (if unique)
-        self.allocator.lock().unwrap().release(&self.data);
+        match &self.backing {
+            Backing::Pool { allocator, data } => {
+                // The allocation holds its own reference to the allocator, so this is safe even if every other handle (or the global pool) is gone, e.g. during shutdown
+                // Release the associated memory map (if unique)
+                // A poisoned lock must not panic here, since this may already be running during a panic
+                allocator
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .release(data);
+            }
+            // The mappings are released when the backing is dropped
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(_) => {}
+        }
     }
 }
 
-impl Deref for ExecutableMemory {
+impl<S> Deref for ExecutableMemory<S> {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        self.data.deref()
+        match &self.backing {
+            Backing::Pool { data, .. } => data.deref(),
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(mapping) => mapping.as_slice(),
+        }
     }
 }
 
-impl DerefMut for ExecutableMemory {
+impl DerefMut for ExecutableMemory<Writable> {
     fn deref_mut(&mut self) -> &mut [u8] {
-        self.data.deref_mut()
+        match &mut self.backing {
+            Backing::Pool { data, .. } => data.deref_mut(),
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(mapping) => mapping.as_mut_slice(),
+        }
     }
 }
 
@@ -124,12 +414,390 @@ impl DerefMut for ExecutableMemory {
 pub const DETOUR_RANGE: usize = 0x8000_0000;
 
 lazy_static! {
+    /// Allocator behind [`allocate_executable`] and friends. Statics are never dropped, so memory freed while the process exits still has a live pool to return to
     static ref POOL: ThreadAllocator = ThreadAllocator::new(DETOUR_RANGE);
 }
 
 /// Allocates an executable buffer
 ///
+/// The buffer is writable but not executable until it's [finalized](ExecutableMemory::finalize). See [`ThreadAllocator::allocate`].
+///
 /// Note: When the executable buffer returns, the buffer's data is undefined, but valid u8 values
 pub fn allocate_executable(origin: usize, size: usize) -> Result<ExecutableMemory, ProximityError> {
     POOL.allocate(origin, size)
 }
+
+/// Allocates an executable buffer no further than `max_distance` from `origin`
+///
+/// See [`ThreadAllocator::allocate_within`].
+pub fn allocate_executable_within(
+    origin: usize,
+    size: usize,
+    max_distance: usize,
+) -> Result<ExecutableMemory, ProximityError> {
+    POOL.allocate_within(origin, size, max_distance)
+}
+
+/// Allocates an executable buffer close to `origin`, preferring memory at or near `hint`
+///
+/// See [`ThreadAllocator::allocate_hint`].
+pub fn allocate_executable_hint(
+    origin: usize,
+    size: usize,
+    hint: usize,
+) -> Result<ExecutableMemory, ProximityError> {
+    POOL.allocate_hint(origin, size, hint)
+}
+
+/// Gets the total size of the free regions within `max_distance` of `origin`
+///
+/// Use this to estimate how much executable memory can still be placed near `origin`, e.g. how many trampolines will fit.
+/// Nothing is mapped, so the space may be taken by the time it's allocated.
+/// Returns 0 if the address space can't be queried.
+pub fn free_space_near(origin: usize, max_distance: usize) -> usize {
+    let range = origin.saturating_sub(max_distance)..origin.saturating_add(max_distance);
+    match search::free_regions(range) {
+        Ok(regions) => regions.iter().map(ExactSizeIterator::len).sum(),
+        Err(e) => {
+            log::warn!("unable to query free regions near {origin:#x}: {e}");
+            0
+        }
+    }
+}
+
+/// Allocates an executable buffer for each of `sizes` in one operation
+///
+/// See [`ThreadAllocator::allocate_batch`].
+pub fn allocate_executable_batch(
+    origin: usize,
+    sizes: &[usize],
+) -> Result<Vec<ExecutableMemory>, ProximityError> {
+    POOL.allocate_batch(origin, sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::ManuallyDrop;
+    use std::ptr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use region::Protection;
+
+    use crate::alloc::proximity::{ProximityError, RetryPolicy};
+    use crate::alloc::{
+        allocate_executable, free_space_near, search, Backing, ExecutableMemory, ThreadAllocator,
+        Writable, DETOUR_RANGE,
+    };
+
+    #[test]
+    /// Tests that finalized pages only become read/execute-only once no allocation on them is still writable
+    fn test_finalize() {
+        // use a separate allocator so no other test shares its pages
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_finalize as *const () as usize;
+        let protection = |ptr: *const u8| region::query(ptr).unwrap().protection();
+
+        let mut first = allocator.allocate(origin, 16).unwrap();
+        let mut second = allocator.allocate(origin, 16).unwrap();
+        assert_eq!(protection(first.exec_ptr()), Protection::READ_WRITE);
+        assert_eq!(first.protection(), Protection::READ_WRITE);
+        first.fill(0xc3);
+        second.fill(0xc3);
+
+        // `second` shares the page and is still being written
+        let first = first.finalize().unwrap();
+        assert_eq!(protection(first.exec_ptr()), Protection::READ_WRITE_EXECUTE);
+        assert_eq!(first.protection(), Protection::READ_EXECUTE);
+
+        let second = second.finalize().unwrap();
+        assert_eq!(protection(first.exec_ptr()), Protection::READ_EXECUTE);
+        assert_eq!(&second[..], [0xc3; 16]);
+
+        // new allocations on the page make it writable again
+        let third = allocator.allocate(origin, 16).unwrap();
+        assert_eq!(protection(third.exec_ptr()), Protection::READ_WRITE_EXECUTE);
+    }
+
+    #[test]
+    /// Tests that memory can't be executed until it's finalized
+    fn test_not_executable() {
+        // use a separate allocator so no finalized allocation shares its pages
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_not_executable as *const () as usize;
+        let protection = |ptr: *const u8| region::query(ptr).unwrap().protection();
+
+        let mut memory = allocator.allocate(origin, 16).unwrap();
+        memory.fill(0xc3);
+        assert!(!protection(memory.exec_ptr()).contains(Protection::EXECUTE));
+        assert!(!memory.protection().contains(Protection::EXECUTE));
+
+        let memory = memory.finalize().unwrap();
+        assert!(protection(memory.exec_ptr()).contains(Protection::EXECUTE));
+    }
+
+    #[test]
+    /// Tests that the address range covers the allocation and is reachable from the origin
+    fn test_range() {
+        let origin = test_range as *const () as usize;
+        let memory = allocate_executable(origin, 16).unwrap();
+
+        assert_eq!(memory.addr(), memory.exec_ptr() as usize);
+        assert_eq!(memory.range(), memory.addr()..memory.addr() + 16);
+        assert!(memory
+            .range()
+            .all(|address| address.abs_diff(origin) < DETOUR_RANGE));
+    }
+
+    #[test]
+    /// Tests that memory can be freed after the allocator it came from is dropped, as can happen with statics during shutdown
+    fn test_outlives_allocator() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_outlives_allocator as *const () as usize;
+        let mut memory = allocator.allocate(origin, 16).unwrap();
+        drop(allocator);
+
+        memory.copy_from_slice(&[0xc3; 16]);
+        let memory = memory.finalize().unwrap();
+        assert_eq!(&memory[..], [0xc3; 16]);
+        drop(memory);
+    }
+
+    #[test]
+    /// Tests that guarded allocations get their own pages, flanked by inaccessible ones
+    fn test_guard_pages() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        allocator.set_guard_pages(1);
+        let origin = test_guard_pages as *const () as usize;
+        let page_size = region::page::size();
+        let protection = |address: usize| region::query(address as *const u8).unwrap().protection();
+
+        let first = allocator.allocate(origin, 16).unwrap();
+        let second = allocator.allocate(origin, 16).unwrap();
+        assert_ne!(first.addr() / page_size, second.addr() / page_size);
+
+        // the allocation ends right at the upper guard page
+        let range = first.range();
+        assert_eq!(range.end % page_size, 0);
+        assert_eq!(protection(range.end), Protection::NONE);
+        assert_eq!(
+            protection(range.start - range.start % page_size - 1),
+            Protection::NONE
+        );
+
+        // the slack before the allocation traps
+        let slack = unsafe {
+            std::slice::from_raw_parts(
+                (range.start - range.start % page_size) as *const u8,
+                range.start % page_size,
+            )
+        };
+        assert!(slack.iter().all(|&byte| byte == 0xcc));
+
+        let first = first.finalize().unwrap();
+        assert_eq!(protection(first.addr()), Protection::READ_EXECUTE);
+        assert_eq!(protection(first.range().end), Protection::NONE);
+    }
+
+    #[test]
+    /// Tests that allocations stay within a distance tighter than the allocator's
+    fn test_allocate_within() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_allocate_within as *const () as usize;
+        let max_distance = 0x1000_0000;
+
+        let memory = allocator.allocate_within(origin, 16, max_distance).unwrap();
+        assert!(memory
+            .range()
+            .all(|address| address.abs_diff(origin) <= max_distance));
+    }
+
+    #[test]
+    /// Tests that hinted allocations are placed at the hint and next to each other
+    fn test_allocate_hint() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_allocate_hint as *const () as usize;
+        let page_size = region::page::size();
+
+        // somewhere far enough from the test binary that no other test allocates there
+        let hint = search::after(origin + 0x4000_0000, None)
+            .next()
+            .unwrap()
+            .unwrap() as usize;
+        let hint = hint - hint % page_size;
+
+        let first = allocator.allocate_hint(origin, 16, hint).unwrap();
+        let second = allocator.allocate_hint(origin, 16, hint).unwrap();
+        assert!((hint..hint + page_size).contains(&first.addr()));
+        assert!((hint..hint + page_size).contains(&second.addr()));
+
+        // a hint that's out of range is ignored
+        let memory = allocator.allocate_hint(origin, 16, 0).unwrap();
+        assert!(memory.addr().abs_diff(origin) < DETOUR_RANGE);
+    }
+
+    #[test]
+    /// Tests that the host is told about each pool that's mapped
+    fn test_on_pool_alloc() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_on_pool_alloc as *const () as usize;
+        let pools = Arc::new(Mutex::new(Vec::new()));
+        let reported = pools.clone();
+        allocator.set_on_pool_alloc(move |address, size| {
+            reported
+                .lock()
+                .unwrap()
+                .push(address as usize..address as usize + size)
+        });
+
+        // the second allocation fits in the first pool
+        let first = allocator.allocate(origin, 16).unwrap();
+        let second = allocator.allocate(origin, 16).unwrap();
+        let pools = pools.lock().unwrap();
+        assert_eq!(pools.len(), 1);
+        assert!(pools[0].contains(&first.addr()) && pools[0].contains(&second.addr()));
+    }
+
+    #[test]
+    /// Tests that no pools are mapped beyond the budget
+    fn test_budget() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_budget as *const () as usize;
+        let page_size = region::page::size();
+        allocator.set_budget(Some(page_size));
+
+        // the second allocation fits in the first pool, so it doesn't need more memory
+        let _first = allocator.allocate(origin, 16).unwrap();
+        let _second = allocator.allocate(origin, 16).unwrap();
+        assert_eq!(allocator.mapped(), page_size);
+        assert!(matches!(
+            allocator.allocate(origin, page_size),
+            Err(ProximityError::OutOfMemory)
+        ));
+        assert_eq!(allocator.mapped(), page_size);
+
+        allocator.set_budget(Some(page_size * 2));
+        let _third = allocator.allocate(origin, page_size).unwrap();
+        assert_eq!(allocator.mapped(), page_size * 2);
+    }
+
+    #[test]
+    /// Tests that the allocator isn't locked while an allocation waits to retry
+    fn test_retry_unlocked() {
+        let allocator = Arc::new(ThreadAllocator::new(DETOUR_RANGE));
+        let origin = test_retry_unlocked as *const () as usize;
+        // nothing can be mapped, so every attempt runs out of memory
+        allocator.set_budget(Some(0));
+        allocator.set_retry_policy(RetryPolicy {
+            retries: 1,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_millis(500),
+            ..Default::default()
+        });
+
+        let thread = thread::spawn({
+            let allocator = allocator.clone();
+            move || {
+                let result = allocator.allocate(origin, 16);
+                matches!(result, Err(ProximityError::OutOfMemory))
+            }
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        // the allocating thread is waiting to retry, which must not block other users of the allocator
+        let start = Instant::now();
+        assert_eq!(allocator.mapped(), 0);
+        assert!(start.elapsed() < Duration::from_millis(250));
+
+        assert!(thread.join().unwrap());
+    }
+
+    #[test]
+    /// Tests that releasing an allocation without a pool doesn't panic
+    fn test_release_unknown() {
+        let first = ThreadAllocator::new(DETOUR_RANGE);
+        let second = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_release_unknown as *const () as usize;
+
+        let memory = first.allocate(origin, 16).unwrap();
+        let data = match &memory.backing {
+            Backing::Pool { data, .. } => data,
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(_) => unreachable!("proximity allocations are pool-backed"),
+        };
+
+        // `second` never allocated this, so it has no pool for it
+        second.0.lock().unwrap().release(data);
+    }
+
+    #[test]
+    /// Tests that releasing explicitly reports allocations without a pool
+    fn test_release() {
+        let first = ThreadAllocator::new(DETOUR_RANGE);
+        let second = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_release as *const () as usize;
+
+        first.allocate(origin, 16).unwrap().release().unwrap();
+
+        // hand the allocation to an allocator that never made it
+        let memory = ManuallyDrop::new(first.allocate(origin, 16).unwrap());
+        // Safety: `memory` is never used or dropped again
+        let backing = unsafe { ptr::read(&memory.backing) };
+        let data = match backing {
+            Backing::Pool { data, .. } => data,
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Backing::Memfd(_) => unreachable!("proximity allocations are pool-backed"),
+        };
+        let address = data.as_ptr();
+        let memory = ExecutableMemory::<Writable>::from_backing(
+            Backing::Pool {
+                allocator: second.0.clone(),
+                data,
+            },
+            Protection::READ_WRITE,
+        );
+        assert!(matches!(
+            memory.release(),
+            Err(ProximityError::UnknownAllocation(unknown)) if unknown == address as _
+        ));
+    }
+
+    #[test]
+    /// Tests that free space is only counted where nothing is mapped
+    fn test_free_space_near() {
+        let page_size = region::page::size();
+        let memory = region::alloc(page_size * 4, Protection::READ_WRITE).unwrap();
+        let middle = memory.as_ptr::<u8>() as usize + page_size * 2;
+
+        assert_eq!(free_space_near(middle, page_size * 2), 0);
+
+        // nothing is ever mapped this low, and the first page is never free
+        assert_eq!(
+            free_space_near(page_size * 8, page_size * 8),
+            page_size * 15
+        );
+    }
+
+    #[test]
+    /// Tests that a batch shares one new pool and each allocation can be dropped separately
+    fn test_allocate_batch() {
+        let allocator = ThreadAllocator::new(DETOUR_RANGE);
+        let origin = test_allocate_batch as *const () as usize;
+
+        let mut memory = allocator.allocate_batch(origin, &[16, 32, 64]).unwrap();
+        assert_eq!(allocator.0.lock().unwrap().pools.len(), 1);
+        assert_eq!(
+            memory.iter().map(|memory| memory.len()).collect::<Vec<_>>(),
+            [16, 32, 64]
+        );
+        for (i, memory) in memory.iter_mut().enumerate() {
+            memory.fill(i as u8);
+        }
+
+        // dropping some allocations doesn't affect the others
+        let last = memory.pop().unwrap();
+        drop(memory);
+        assert_eq!(&last[..], [2; 64]);
+    }
+}
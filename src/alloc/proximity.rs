@@ -57,12 +57,13 @@
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use std::error::Error;
-use std::slice;
+use std::time::Duration;
 use std::{fmt::Display, ops::Range};
+use std::{slice, thread};
 
 use slice_pool::sync::{SliceBox, SlicePool};
 
-use super::search as region_search;
+use super::search::{self as region_search, SearchStrategy};
 
 /// Defines the allocation type.
 pub type Allocation = SliceBox<u8>;
@@ -76,6 +77,11 @@ pub enum ProximityError {
     MmapError(mmap::MapError),
     /// Error while querying a memory region
     RegionError(region::Error),
+    /// Error while creating or mapping a memfd
+    #[cfg(all(target_os = "linux", feature = "memfd"))]
+    MemfdError(std::io::Error),
+    /// The allocation being released doesn't belong to any of the allocator's pools
+    UnknownAllocation(*const ()),
 }
 impl Display for ProximityError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -86,27 +92,310 @@ impl Display for ProximityError {
             ),
             Self::MmapError(e) => write!(f, "{e}"),
             Self::RegionError(e) => write!(f, "{e}"),
+            #[cfg(all(target_os = "linux", feature = "memfd"))]
+            Self::MemfdError(e) => write!(f, "{e}"),
+            Self::UnknownAllocation(address) => {
+                write!(
+                    f,
+                    "Unable to find the memory pool for allocation at {address:?}"
+                )
+            }
         }
     }
 }
 impl Error for ProximityError {}
 
+/// Controls how many fixed-address mappings are attempted when allocating a new pool
+///
+/// Candidate addresses can be taken by another thread between finding them and mapping them, in which case the next candidate is tried.
+/// Once every candidate has failed, [`ThreadAllocator`](super::ThreadAllocator) can search the address space again after a delay, giving racing threads time to finish mapping.
+/// It waits with its lock released, so a backoff never holds up other allocations or releases.
+/// The default tries every free region in range once without waiting, which is the allocator's original behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryPolicy {
+    /// Maximum number of candidate addresses to try per search, or `None` to try every free region in range
+    pub max_attempts: Option<usize>,
+    /// Number of times an allocation is retried after running out of candidates
+    pub retries: usize,
+    /// Delay before the first retry. Doubled before each further retry
+    pub backoff: Duration,
+    /// Upper bound for the delay between retries
+    pub max_backoff: Duration,
+}
+impl RetryPolicy {
+    /// Calls `f` with each candidate address until it succeeds or the policy runs out of attempts
+    fn find_map<T>(
+        &self,
+        candidates: impl Iterator<Item = Result<*const (), region::Error>>,
+        mut f: impl FnMut(*const ()) -> Option<T>,
+    ) -> Result<T, ProximityError> {
+        for (attempt, candidate) in candidates.enumerate() {
+            if self.max_attempts.is_some_and(|max| attempt >= max) {
+                break;
+            }
+            let address = candidate.map_err(ProximityError::RegionError)?;
+            if let Some(value) = f(address) {
+                return Ok(value);
+            }
+        }
+        Err(ProximityError::OutOfMemory)
+    }
+
+    /// Calls `allocate` until it doesn't run out of memory or the policy runs out of retries, waiting between calls
+    ///
+    /// `allocate` must not hold any locks once it returns, so that they're released while waiting.
+    pub(crate) fn run<T>(
+        &self,
+        mut allocate: impl FnMut() -> Result<T, ProximityError>,
+    ) -> Result<T, ProximityError> {
+        let mut delay = self.backoff;
+        for _ in 0..self.retries {
+            match allocate() {
+                Err(ProximityError::OutOfMemory) => {}
+                result => return result,
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(self.max_backoff);
+        }
+        allocate()
+    }
+}
+
+/// Callback for pools being mapped or released, called with the address and size of the pool's memory
+///
+/// Callbacks run while the allocator is locked, so they must not allocate from the same allocator.
+pub type PoolCallback = Box<dyn Fn(*const u8, usize) + Send + Sync>;
+
 /// Shared instance containing all pools
 pub struct ProximityAllocator {
     /// Max distance away from the origin that the pool can be
     pub max_distance: usize,
     /// Memory pools used for allocations
     pub pools: Vec<SlicePool<u8>>,
+    /// Order in which free regions around the origin are tried for new pools
+    pub strategy: SearchStrategy,
+    /// Allocations that haven't been finalized yet. Pages containing these must stay writable
+    pub writable: Vec<Range<usize>>,
+    /// Allocations that have been finalized. Pages containing these must stay executable
+    pub finalized: Vec<Range<usize>>,
+    /// How many addresses are tried when mapping a new pool
+    ///
+    /// Only [`RetryPolicy::max_attempts`] is used here. Retries are left to [`ThreadAllocator`](super::ThreadAllocator), which can wait for them without holding its lock.
+    pub retry: RetryPolicy,
+    /// Number of inaccessible pages mapped on either side of each allocation, or 0 to pack allocations into shared pools
+    ///
+    /// Each allocation gets its own pool, placed so it ends right at the upper guard page. The bytes between the lower guard page and the allocation are filled with `int3`.
+    /// Execution that runs off either end of an allocation faults immediately, at the cost of at least `guard_pages * 2 + 1` pages of address space per allocation.
+    pub guard_pages: usize,
+    /// Called after a new pool is mapped
+    pub on_pool_alloc: Option<PoolCallback>,
+    /// Called before a pool is released. Pools still alive when the allocator is dropped aren't reported
+    pub on_pool_release: Option<PoolCallback>,
+    /// Maximum number of bytes all pools together may map, or `None` for no limit
+    ///
+    /// Pools are counted in whole pages, not counting guard pages. Mapping a pool that would exceed the budget fails with [`ProximityError::OutOfMemory`].
+    pub budget: Option<usize>,
+    /// Number of bytes currently mapped by all pools, counted the same way as `budget`
+    pub mapped: usize,
 }
 
 impl ProximityAllocator {
     /// Allocates a slice in an eligible memory map.
     pub fn allocate(&mut self, origin: usize, size: usize) -> Result<Allocation, ProximityError> {
+        self.allocate_within(origin, size, self.max_distance)
+    }
+
+    /// Allocates a slice in an eligible memory map no further than `max_distance` from `origin`.
+    ///
+    /// The allocator's own `max_distance` still applies if it's smaller.
+    pub fn allocate_within(
+        &mut self,
+        origin: usize,
+        size: usize,
+        max_distance: usize,
+    ) -> Result<Allocation, ProximityError> {
+        let max_distance = max_distance.min(self.max_distance);
+        let allocation = self.allocate_unprotected(origin, size, max_distance)?;
+        self.make_writable(&allocation)?;
+        Ok(allocation)
+    }
+
+    /// Allocates a slice in an eligible memory map, preferring memory at or near `hint`.
+    ///
+    /// A pool that already contains `hint` is used first, so allocations sharing a hint end up next to each other.
+    /// Otherwise a new pool is mapped at the first free region around `hint`. The result is still within `max_distance` of `origin`:
+    /// if `hint` is too far from `origin`, or nothing around it can be mapped, this falls back to [`ProximityAllocator::allocate`].
+    pub fn allocate_hint(
+        &mut self,
+        origin: usize,
+        size: usize,
+        hint: usize,
+    ) -> Result<Allocation, ProximityError> {
+        let memory_range =
+            (origin.saturating_sub(self.max_distance))..(origin.saturating_add(self.max_distance));
+        if !memory_range.contains(&hint) {
+            log::debug!("allocation hint {hint:#x} is out of range of {origin:#x}, ignoring it");
+            return self.allocate(origin, size);
+        }
+
+        let allocation = match self.allocate_near_hint(&memory_range, hint, size) {
+            Ok(allocation) => allocation,
+            Err(ProximityError::OutOfMemory) => return self.allocate(origin, size),
+            Err(e) => return Err(e),
+        };
+        self.make_writable(&allocation)?;
+        Ok(allocation)
+    }
+
+    /// Allocates a slice for each of `sizes` in eligible memory maps.
+    ///
+    /// Sizes that don't fit in an existing pool share a single new pool, so at most one memory map is created.
+    /// If any allocation fails, every allocation made so far is released.
+    pub fn allocate_batch(
+        &mut self,
+        origin: usize,
+        sizes: &[usize],
+    ) -> Result<Vec<Allocation>, ProximityError> {
         let memory_range =
             (origin.saturating_sub(self.max_distance))..(origin.saturating_add(self.max_distance));
 
+        let mut allocations = Vec::with_capacity(sizes.len());
+        let result = if self.guard_pages > 0 {
+            // Guarded allocations never share a pool, so there's nothing to batch
+            sizes.iter().try_for_each(|&size| {
+                allocations.push(self.allocate(origin, size)?);
+                Ok(())
+            })
+        } else {
+            self.allocate_batch_into(&memory_range, origin, sizes, &mut allocations)
+        };
+        if let Err(e) = result {
+            // Released one at a time, the same as dropping each allocation separately
+            for allocation in allocations {
+                self.release(&allocation);
+            }
+            return Err(e);
+        }
+        Ok(allocations)
+    }
+
+    /// Fills `allocations` with a slice for each of `sizes`, in order, making each one writable
+    fn allocate_batch_into(
+        &mut self,
+        range: &Range<usize>,
+        origin: usize,
+        sizes: &[usize],
+        allocations: &mut Vec<Allocation>,
+    ) -> Result<(), ProximityError> {
+        // Try the existing pools first, leaving gaps for allocations that need the new pool
+        let mut slots = Vec::with_capacity(sizes.len());
+        let mut remaining = 0;
+        for &size in sizes {
+            match self.allocate_memory(range, size) {
+                Ok(allocation) => slots.push(Some(allocation)),
+                Err(ProximityError::OutOfMemory) => {
+                    remaining += size;
+                    slots.push(None);
+                }
+                Err(e) => {
+                    allocations.extend(slots.into_iter().flatten());
+                    return Err(e);
+                }
+            }
+        }
+
+        if remaining > 0 {
+            match self.allocate_pool(range, origin, remaining) {
+                Ok(pool) => self.add_pool(pool),
+                Err(e) => {
+                    allocations.extend(slots.into_iter().flatten());
+                    return Err(e);
+                }
+            }
+        }
+        for (slot, &size) in slots.into_iter().zip(sizes) {
+            let allocation = match slot {
+                Some(allocation) => allocation,
+                // The new pool was sized for every remaining allocation, so this can't run out
+                None => match self.pools.last().and_then(|pool| pool.alloc(size)) {
+                    Some(allocation) => allocation,
+                    None => return Err(ProximityError::OutOfMemory),
+                },
+            };
+            allocations.push(allocation);
+        }
+
+        for allocation in allocations.iter() {
+            self.make_writable(allocation)?;
+        }
+        Ok(())
+    }
+
+    /// Tracks a new allocation as writable and updates the protection of its pages
+    fn make_writable(&mut self, allocation: &Allocation) -> Result<(), ProximityError> {
+        // The allocation may share pages with finalized allocations, which are no longer writable
+        let range = allocation_range(allocation);
+        self.writable.push(range.clone());
+        if let Err(e) = self.protect_pages(&range) {
+            self.writable.pop();
+            return Err(ProximityError::RegionError(e));
+        }
+        Ok(())
+    }
+
+    /// Makes the pages of an allocation read/execute-only, unless they're shared with an allocation that's still writable.
+    pub fn finalize(&mut self, value: &Allocation) -> Result<(), region::Error> {
+        let range = allocation_range(value);
+        self.writable.retain(|writable| *writable != range);
+        self.finalized.push(range.clone());
+
+        // Whichever allocation on the shared pages is finalized last will drop write access
+        self.protect_pages(&range)
+    }
+
+    /// Protects the pages containing `range` according to the allocations that share them
+    ///
+    /// Pages are writable while they contain an unfinalized allocation and executable while they contain a finalized one, so memory is only ever RWX while both kinds share a page.
+    fn protect_pages(&self, range: &Range<usize>) -> Result<(), region::Error> {
+        let pages = page_range(range);
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let overlaps = |other: &Range<usize>| {
+            let other = page_range(other);
+            other.start < pages.end && pages.start < other.end
+        };
+
+        let protection = match (
+            self.writable.iter().any(overlaps),
+            self.finalized.iter().any(overlaps),
+        ) {
+            (true, true) => region::Protection::READ_WRITE_EXECUTE,
+            (true, false) => region::Protection::READ_WRITE,
+            (false, _) => region::Protection::READ_EXECUTE,
+        };
+        unsafe { region::protect(pages.start as *const u8, pages.len(), protection) }
+    }
+
+    /// Allocates a slice in an eligible memory map without changing its protection.
+    fn allocate_unprotected(
+        &mut self,
+        origin: usize,
+        size: usize,
+        max_distance: usize,
+    ) -> Result<Allocation, ProximityError> {
+        let memory_range =
+            (origin.saturating_sub(max_distance))..(origin.saturating_add(max_distance));
+
+        // Guarded allocations always get a pool of their own
+        let existing = if self.guard_pages > 0 {
+            Err(ProximityError::OutOfMemory)
+        } else {
+            self.allocate_memory(&memory_range, size)
+        };
+
         // Check if an existing pool can handle the allocation request
-        self.allocate_memory(&memory_range, size).or_else(|e| {
+        existing.or_else(|e| {
             if !matches!(e, ProximityError::OutOfMemory) {
                 // make sure the error is that the pool is out of memory
                 return Err(e);
@@ -116,31 +405,82 @@ impl ProximityAllocator {
                 .and_then(|pool| {
                     // Use the newly allocated pool for the request
                     let allocation = pool.alloc(size).ok_or(ProximityError::OutOfMemory)?;
-                    self.pools.push(pool);
+                    self.add_pool(pool);
                     Ok(allocation)
                 })
         })
     }
 
     /// Releases the memory pool associated with an allocation.
+    ///
+    /// This runs while dropping executable memory, so it never panics. If the pool can't be found, a warning is logged and nothing is released.
     pub fn release(&mut self, value: &Allocation) {
+        if let Err(e) = self.try_release(value) {
+            log::warn!("{e}");
+        }
+    }
+
+    /// Releases the memory pool associated with an allocation, reporting an allocation without a pool as [`ProximityError::UnknownAllocation`].
+    pub fn try_release(&mut self, value: &Allocation) -> Result<(), ProximityError> {
+        let range = allocation_range(value);
+        self.writable.retain(|writable| *writable != range);
+        self.finalized.retain(|finalized| *finalized != range);
+
         // Find the associated memory pool
-        let index = self
-            .pools
-            .iter()
-            .position(|pool| {
-                let lower = pool.as_ptr() as usize;
-                let upper = lower + pool.len();
+        let Some(index) = self.pools.iter().position(|pool| {
+            let lower = pool.as_ptr() as usize;
+            let upper = lower + pool.len();
 
-                // Determine if this is the associated memory pool
-                (lower..upper).contains(&(value.as_ptr() as usize))
-            })
-            .expect("retrieving associated memory pool");
+            // Determine if this is the associated memory pool
+            (lower..upper).contains(&(value.as_ptr() as usize))
+        }) else {
+            return Err(ProximityError::UnknownAllocation(value.as_ptr() as _));
+        };
 
         // Release the pool if the associated allocation is unique
         if self.pools[index].len() == 1 {
-            self.pools.remove(index);
+            let pool = self.pools.remove(index);
+            self.mapped = self.mapped.saturating_sub(pool_pages(pool.len()));
+            if let Some(callback) = &self.on_pool_release {
+                callback(pool.as_ptr(), pool.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts using a newly mapped pool
+    fn add_pool(&mut self, pool: SlicePool<u8>) {
+        if let Some(callback) = &self.on_pool_alloc {
+            callback(pool.as_ptr(), pool.len());
         }
+        self.mapped += pool_pages(pool.len());
+        self.pools.push(pool);
+    }
+
+    /// Allocates a chunk from the pool containing `hint`, or from a new pool mapped as close to `hint` as possible.
+    fn allocate_near_hint(
+        &mut self,
+        range: &Range<usize>,
+        hint: usize,
+        size: usize,
+    ) -> Result<Allocation, ProximityError> {
+        // Guarded allocations always get a pool of their own
+        if self.guard_pages == 0 {
+            let hinted = self.pools.iter_mut().find(|pool| {
+                let lower = pool.as_ptr() as usize;
+                (lower..lower + pool.len()).contains(&hint) && range.contains(&lower)
+            });
+            if let Some(allocation) = hinted.and_then(|pool| pool.alloc(size)) {
+                return Ok(allocation);
+            }
+        }
+
+        // Start at the hint's page so the new pool contains the hint if it's free
+        let hint = hint - hint % region::page::size();
+        let pool = self.allocate_pool(range, hint, size)?;
+        let allocation = pool.alloc(size).ok_or(ProximityError::OutOfMemory)?;
+        self.add_pool(pool);
+        Ok(allocation)
     }
 
     /// Allocates a chunk using any of the existing pools.
@@ -177,58 +517,123 @@ impl ProximityAllocator {
         origin: usize,
         size: usize,
     ) -> Result<SlicePool<u8>, ProximityError> {
-        let before = region_search::before(origin, Some(range.clone()));
-        let after = region_search::after(origin, Some(range.clone()));
+        if self
+            .budget
+            .is_some_and(|budget| self.mapped + pool_pages(size) > budget)
+        {
+            log::debug!(
+                "mapping a pool of {size:#x} bytes would exceed the budget of {:#x} bytes",
+                self.budget.unwrap_or_default()
+            );
+            return Err(ProximityError::OutOfMemory);
+        }
 
         // TODO: Part of the pool can be out of range
-        // Try to allocate after the specified address first (mostly because
-        // macOS cannot allocate memory before the process's address).
-        after
-            .chain(before)
-            .find_map(|result| match result {
-                Ok(address) => Self::allocate_fixed_pool(address, size).ok().map(Ok),
-                Err(error) => Some(Err(ProximityError::RegionError(error))),
-            })
-            .unwrap_or(Err(ProximityError::OutOfMemory))
+        let guard_pages = self.guard_pages;
+        self.retry.find_map(
+            region_search::around(origin, Some(range.clone()), self.strategy),
+            |address| Self::allocate_fixed_pool(address, size, guard_pages).ok(),
+        )
     }
 
     /// Tries to allocate fixed memory at the specified address.
+    ///
+    /// With guard pages, the pool is exactly `size` bytes and ends at the upper guard page.
     fn allocate_fixed_pool(
         address: *const (),
         size: usize,
+        guard_pages: usize,
     ) -> Result<SlicePool<u8>, ProximityError> {
+        let page_size = region::page::size();
+        let guard_size = guard_pages * page_size;
+        let map_size = if guard_pages > 0 {
+            size.div_ceil(page_size) * page_size + guard_size * 2
+        } else {
+            size
+        };
+
         // Try to allocate memory at the specified address
-        mmap::MemoryMap::new(
-            size,
+        let map = mmap::MemoryMap::new(
+            map_size,
             &[
                 mmap::MapOption::MapReadable,
                 mmap::MapOption::MapWritable,
-                mmap::MapOption::MapExecutable,
                 mmap::MapOption::MapAddr(address as *const _),
             ],
         )
         .map_err(|e| match e {
             mmap::MapError::ErrNoMem => ProximityError::OutOfMemory,
             e => ProximityError::MmapError(e),
-        })
-        .map(SliceableMemoryMap)
-        .map(SlicePool::new)
+        })?;
+        if guard_pages == 0 {
+            return Ok(SlicePool::new(SliceableMemoryMap {
+                offset: 0,
+                len: map.len(),
+                map,
+            }));
+        }
+
+        // Anything that runs from the slack into the allocation hits an int3 first
+        let offset = map_size - guard_size - size;
+        unsafe {
+            map.data().write_bytes(0xcc, offset);
+            for guard in [map.data(), map.data().add(map_size - guard_size)] {
+                region::protect(guard, guard_size, region::Protection::NONE)
+                    .map_err(ProximityError::RegionError)?;
+            }
+        }
+        Ok(SlicePool::new(SliceableMemoryMap {
+            map,
+            offset,
+            len: size,
+        }))
     }
 }
 
+/// Gets the number of bytes a pool of `len` bytes counts against the budget
+fn pool_pages(len: usize) -> usize {
+    len.div_ceil(region::page::size()) * region::page::size()
+}
+
+/// Gets the address range of an allocation
+fn allocation_range(value: &Allocation) -> Range<usize> {
+    let start = value.as_ptr() as usize;
+    start..start + value.len()
+}
+
+/// Expands `range` to the pages that contain it
+fn page_range(range: &Range<usize>) -> Range<usize> {
+    if range.is_empty() {
+        return range.start..range.start;
+    }
+    let page_size = region::page::size();
+    let start = range.start - range.start % page_size;
+    let end = range.end.div_ceil(page_size) * page_size;
+    start..end
+}
+
 // TODO: Use memmap-rs instead
 /// A wrapper for making a memory map compatible with `SlicePool`.
-struct SliceableMemoryMap(mmap::MemoryMap);
+///
+/// Only part of the map is handed to the pool when it has guard pages; the guard pages are unmapped along with the rest of the map.
+struct SliceableMemoryMap {
+    /// Memory map backing the pool
+    map: mmap::MemoryMap,
+    /// Offset of the pool's memory in the map
+    offset: usize,
+    /// Length of the pool's memory
+    len: usize,
+}
 
 impl SliceableMemoryMap {
     /// Get a slice of the memory map
     pub fn as_slice(&self) -> &[u8] {
-        unsafe { slice::from_raw_parts(self.0.data(), self.0.len()) }
+        unsafe { slice::from_raw_parts(self.map.data().add(self.offset), self.len) }
     }
 
     /// Get a mutable slice of the memory map
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        unsafe { slice::from_raw_parts_mut(self.0.data(), self.0.len()) }
+        unsafe { slice::from_raw_parts_mut(self.map.data().add(self.offset), self.len) }
     }
 }
 
@@ -246,3 +651,80 @@ impl AsMut<[u8]> for SliceableMemoryMap {
 
 unsafe impl Send for SliceableMemoryMap {}
 unsafe impl Sync for SliceableMemoryMap {}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::time::Duration;
+
+    use crate::alloc::proximity::{ProximityError, RetryPolicy};
+
+    #[test]
+    /// Tests that failed candidates are skipped until one succeeds
+    fn test_retry() {
+        let candidates = || (1..=8).map(|address| Ok(address as *const ()));
+        let mut attempts = 0;
+        let result = RetryPolicy::default().find_map(candidates(), |address| {
+            attempts += 1;
+            (address as usize == 5).then_some(address)
+        });
+
+        assert_eq!(result.unwrap() as usize, 5);
+        assert_eq!(attempts, 5);
+    }
+
+    #[test]
+    /// Tests that the number of attempts is bounded
+    fn test_retry_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        let mut attempts = 0;
+        let result = policy.find_map((1..=8).map(|address| Ok(address as *const ())), |_| {
+            attempts += 1;
+            None::<()>
+        });
+
+        assert!(matches!(result, Err(ProximityError::OutOfMemory)));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    /// Tests that allocations are only retried after running out of memory, and only as many times as allowed
+    fn test_retry_run() {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_micros(1),
+            max_backoff: Duration::from_micros(2),
+            ..Default::default()
+        };
+
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            Err::<(), _>(ProximityError::OutOfMemory)
+        });
+        assert!(matches!(result, Err(ProximityError::OutOfMemory)));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            Err::<(), _>(ProximityError::UnknownAllocation(ptr::null()))
+        });
+        assert!(matches!(result, Err(ProximityError::UnknownAllocation(_))));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            if calls == 2 {
+                Ok(calls)
+            } else {
+                Err(ProximityError::OutOfMemory)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+}
@@ -56,8 +56,23 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use std::iter::Peekable;
 use std::ops::Range;
 
+/// Order in which free regions around an address are tried
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SearchStrategy {
+    /// Try every region after the address, then every region before it.
+    ///
+    /// This is the default, mostly because macOS cannot allocate memory before the process's address.
+    #[default]
+    AfterFirst,
+    /// Try every region before the address, then every region after it.
+    BeforeFirst,
+    /// Try regions in order of distance from the address, regardless of direction.
+    Closest,
+}
+
 /// Returns an iterator for free after the specified address.
 pub fn after(
     origin: usize,
@@ -74,6 +89,50 @@ pub fn before(
     FreeRegionIter::new(origin, range, SearchDirection::Before)
 }
 
+/// Returns an iterator for free regions around the specified address, in the order given by `strategy`.
+pub fn around(
+    origin: usize,
+    range: Option<Range<usize>>,
+    strategy: SearchStrategy,
+) -> Box<dyn Iterator<Item = Result<*const (), region::Error>>> {
+    let before = FreeRegionIter::new(origin, range.clone(), SearchDirection::Before);
+    let after = FreeRegionIter::new(origin, range, SearchDirection::After);
+
+    match strategy {
+        SearchStrategy::AfterFirst => Box::new(after.chain(before)),
+        SearchStrategy::BeforeFirst => Box::new(before.chain(after)),
+        SearchStrategy::Closest => Box::new(ClosestIter::new(origin, before, after)),
+    }
+}
+
+/// Returns the free regions within `range`, in increasing address order.
+///
+/// Unlike the other searches, the whole range is queried at once and each free region is returned in full rather than one page at a time.
+/// Partial pages at either end of `range` are left out.
+pub fn free_regions(range: Range<usize>) -> Result<Vec<Range<usize>>, region::Error> {
+    let page_size = region::page::size();
+    // The first page is never considered free, matching the other searches
+    let start = range.start.max(page_size).next_multiple_of(page_size);
+    let end = range.end - range.end % page_size;
+    if start >= end {
+        return Ok(Vec::new());
+    }
+
+    let mut free = Vec::new();
+    let mut next = start;
+    for region in region::query_range(start as *const (), end - start)? {
+        let mapped = region?.as_range();
+        if mapped.start > next {
+            free.push(next..mapped.start.min(end));
+        }
+        next = next.max(mapped.end);
+    }
+    if next < end {
+        free.push(next..end);
+    }
+    Ok(free)
+}
+
 #[allow(clippy::missing_docs_in_private_items)]
 /// Direction for the region search.
 enum SearchDirection {
@@ -140,3 +199,108 @@ impl Iterator for FreeRegionIter {
         None
     }
 }
+
+/// An iterator interleaving searches in both directions by distance from the origin.
+///
+/// Both searches must yield regions in order of increasing distance from the origin.
+struct ClosestIter<B: Iterator, A: Iterator> {
+    /// Address the distance is measured from
+    origin: usize,
+    /// Search for regions before the origin
+    before: Peekable<B>,
+    /// Search for regions after the origin
+    after: Peekable<A>,
+}
+
+impl<B, A> ClosestIter<B, A>
+where
+    B: Iterator<Item = Result<*const (), region::Error>>,
+    A: Iterator<Item = Result<*const (), region::Error>>,
+{
+    /// Creates a new iterator merging `before` and `after`.
+    fn new(origin: usize, before: B, after: A) -> Self {
+        ClosestIter {
+            origin,
+            before: before.peekable(),
+            after: after.peekable(),
+        }
+    }
+}
+
+impl<B, A> Iterator for ClosestIter<B, A>
+where
+    B: Iterator<Item = Result<*const (), region::Error>>,
+    A: Iterator<Item = Result<*const (), region::Error>>,
+{
+    type Item = Result<*const (), region::Error>;
+
+    /// Returns whichever search's next region is closer, preferring the region after the origin on ties.
+    fn next(&mut self) -> Option<Self::Item> {
+        let origin = self.origin;
+        // Errors are reported as soon as they're seen
+        let distance = |item: &Self::Item| match item {
+            Ok(address) => (*address as usize).abs_diff(origin),
+            Err(_) => 0,
+        };
+
+        let before_first = match (self.before.peek(), self.after.peek()) {
+            (Some(before), Some(after)) => distance(before) < distance(after),
+            (before, _) => before.is_some(),
+        };
+
+        if before_first {
+            self.before.next()
+        } else {
+            self.after.next()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::alloc::search::{free_regions, ClosestIter};
+
+    #[test]
+    /// Tests that the closest strategy interleaves both directions by distance
+    fn test_closest() {
+        let origin = 0x10_0000usize;
+        let before = [origin - 0x1000, origin - 0x5000, origin - 0x6000];
+        let after = [origin + 0x2000, origin + 0x3000, origin + 0x6000];
+
+        let addresses: Vec<_> = ClosestIter::new(
+            origin,
+            before.iter().map(|&address| Ok(address as *const ())),
+            after.iter().map(|&address| Ok(address as *const ())),
+        )
+        .map(|result| result.unwrap() as isize - origin as isize)
+        .collect();
+
+        assert_eq!(
+            addresses,
+            [-0x1000, 0x2000, 0x3000, -0x5000, 0x6000, -0x6000]
+        );
+    }
+
+    #[test]
+    /// Tests that free regions skip mapped memory and the first page
+    fn test_free_regions() {
+        let page_size = region::page::size();
+        let memory = region::alloc(page_size * 4, region::Protection::READ_WRITE).unwrap();
+        let mapped = memory.as_ptr::<u8>() as usize..memory.as_ptr::<u8>() as usize + memory.len();
+
+        assert!(free_regions(mapped.clone()).unwrap().is_empty());
+        for free in
+            free_regions(mapped.start - page_size * 16..mapped.end + page_size * 16).unwrap()
+        {
+            assert!(free.end <= mapped.start || free.start >= mapped.end);
+        }
+
+        // nothing is ever mapped this low, so only the first page is left out
+        assert_eq!(
+            free_regions(0..page_size * 16).unwrap(),
+            slice::from_ref(&(page_size..page_size * 16))
+        );
+    }
+}
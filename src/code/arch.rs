@@ -0,0 +1,150 @@
+//! # Architecture
+//!
+//! Describes the instruction sets that code is decoded and generated for, either at compile time ([`Architecture`]) or at runtime ([`ArchRuntime`])
+
+use iced_x86::{Code, Instruction};
+
+use super::x64::{self, needs_endbr64};
+use super::x86;
+
+/// Helper functions for an architecture
+pub trait Architecture {
+    /// Gets the maximum instruction length for this architecture
+    fn max_instr_len() -> usize;
+    /// Gets the bitness of this architecture
+    fn bitness() -> u32;
+    /// Gets the length of the jump this architecture uses to reach any address, which is the largest patch a jump hook needs
+    ///
+    /// Defaults to the length of x86_64's [`jmp_abs`](x64::jmp_abs), the longest jump this crate generates.
+    fn abs_jmp_len() -> usize {
+        x64::JMP_ABS_LEN
+    }
+    /// Gets the instruction that generated code must start with to be a valid indirect branch target, if any
+    fn entry_marker() -> Option<Instruction> {
+        None
+    }
+    /// Generates code that immediately returns `value` in this architecture's return register(s)
+    fn ret_const(value: u64) -> Vec<u8>;
+}
+
+/// x86_64 architecture
+pub struct X86_64;
+impl Architecture for X86_64 {
+    fn max_instr_len() -> usize {
+        16
+    }
+    fn bitness() -> u32 {
+        64
+    }
+    fn abs_jmp_len() -> usize {
+        x64::JMP_ABS_LEN
+    }
+    fn entry_marker() -> Option<Instruction> {
+        needs_endbr64().then(|| Instruction::with(Code::Endbr64))
+    }
+    fn ret_const(value: u64) -> Vec<u8> {
+        x64::ret_const(value)
+    }
+}
+
+/// 32-bit x86 architecture
+pub struct X86;
+impl Architecture for X86 {
+    fn max_instr_len() -> usize {
+        15
+    }
+    fn bitness() -> u32 {
+        32
+    }
+    fn abs_jmp_len() -> usize {
+        x86::JMP_ABS_LEN
+    }
+    fn entry_marker() -> Option<Instruction> {
+        needs_endbr64().then(|| Instruction::with(Code::Endbr32))
+    }
+    fn ret_const(value: u64) -> Vec<u8> {
+        x86::ret_const(value)
+    }
+}
+
+/// Runtime counterpart of [`Architecture`], for when the architecture is data rather than a type
+///
+/// See [`CodePatcher::new_with_arch`](crate::patcher::code::CodePatcher::new_with_arch).
+pub trait ArchRuntime {
+    /// Gets the maximum instruction length for this architecture
+    fn max_instr_len(&self) -> usize;
+    /// Gets the bitness of this architecture
+    fn bitness(&self) -> u32;
+    /// Gets the length of the jump this architecture uses to reach any address. See [`Architecture::abs_jmp_len`]
+    fn abs_jmp_len(&self) -> usize {
+        x64::JMP_ABS_LEN
+    }
+    /// Gets the instruction that generated code must start with to be a valid indirect branch target, if any
+    fn entry_marker(&self) -> Option<Instruction> {
+        None
+    }
+}
+
+/// x86 architecture chosen at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DynArch {
+    /// Bitness of the code (16, 32, or 64)
+    pub bitness: u32,
+    /// Maximum instruction length
+    pub max_instr_len: usize,
+}
+impl DynArch {
+    /// 32-bit x86
+    pub const X86: Self = Self {
+        bitness: 32,
+        max_instr_len: 15,
+    };
+    /// x86_64, equivalent to [`X86_64`]
+    pub const X86_64: Self = Self {
+        bitness: 64,
+        max_instr_len: 16,
+    };
+}
+impl ArchRuntime for DynArch {
+    fn max_instr_len(&self) -> usize {
+        self.max_instr_len
+    }
+    fn bitness(&self) -> u32 {
+        self.bitness
+    }
+    fn abs_jmp_len(&self) -> usize {
+        match self.bitness {
+            64 => x64::JMP_ABS_LEN,
+            _ => x86::JMP_ABS_LEN,
+        }
+    }
+    fn entry_marker(&self) -> Option<Instruction> {
+        // CET is a property of the CPU, so the same check covers 32-bit code
+        match self.bitness {
+            64 => needs_endbr64().then(|| Instruction::with(Code::Endbr64)),
+            32 => needs_endbr64().then(|| Instruction::with(Code::Endbr32)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::arch::{ArchRuntime, Architecture, DynArch, X86, X86_64};
+
+    #[test]
+    /// Tests that the runtime architectures report the same jump length as their compile-time counterparts
+    fn test_abs_jmp_len() {
+        assert_eq!(DynArch::X86_64.abs_jmp_len(), X86_64::abs_jmp_len());
+        assert_eq!(DynArch::X86.abs_jmp_len(), X86::abs_jmp_len());
+    }
+
+    #[test]
+    /// Tests that the runtime architectures match their static counterparts
+    fn test_dyn_arch() {
+        assert_eq!(DynArch::X86_64.max_instr_len(), X86_64::max_instr_len());
+        assert_eq!(DynArch::X86.max_instr_len(), X86::max_instr_len());
+        assert_eq!(DynArch::X86_64.bitness(), X86_64::bitness());
+        assert_eq!(DynArch::X86.bitness(), X86::bitness());
+    }
+}
@@ -1 +1,412 @@
+//! # Code
+//!
+//! This module contains helpers for generating and reasoning about machine code
+
+use std::{ptr, slice};
+
+use iced_x86::{Decoder, DecoderOptions, Encoder, FlowControl, IcedError, Instruction};
+use thiserror::Error;
+
+use crate::patcher::Patcher;
+
+use self::arch::Architecture;
+
+pub mod arch;
 pub mod x64;
+pub mod x86;
+
+#[derive(Debug, Error)]
+/// Error types for [`patch_instruction`]
+pub enum InstructionPatchError<E> {
+    /// The code at the location isn't a valid instruction
+    #[error("Invalid instruction at {0:?}")]
+    InvalidInstruction(*const ()),
+    /// The new instruction couldn't be encoded at the location
+    #[error("Unable to encode instruction: {0}")]
+    EncodeError(#[from] IcedError),
+    /// The new instruction is longer than the one it would replace
+    #[error("Instruction needs {needed} bytes but only {available} are available")]
+    TooLong {
+        /// Length of the new instruction
+        needed: usize,
+        /// Length of the instruction being replaced
+        available: usize,
+    },
+    /// Error from the underlying patcher
+    #[error("patcher error")]
+    PatcherError(E),
+}
+
+/// Length of a `jmp rel8` instruction
+pub const JMP_REL8_LEN: usize = 2;
+/// Length of a `jmp rel32` instruction
+pub const JMP_REL32_LEN: usize = 5;
+
+/// Calculates the displacement from `next_ip` (the address of the instruction after the one being encoded) to `target`
+///
+/// Returns `None` if the displacement doesn't fit in `T`
+pub fn displacement<T: TryFrom<isize>>(next_ip: usize, target: usize) -> Option<T> {
+    // Addresses wrap around, so the wrapping difference is the actual displacement
+    (target as isize)
+        .wrapping_sub(next_ip as isize)
+        .try_into()
+        .ok()
+}
+
+/// Checks whether a `jmp rel32` located at `source` can reach `target`
+///
+/// The displacement is calculated from the end of the 5-byte instruction, so this is the check to use before emitting a rel32 jump at `source`.
+pub fn within_rel32(source: usize, target: usize) -> bool {
+    displacement::<i32>(source.wrapping_add(JMP_REL32_LEN), target).is_some()
+}
+
+/// Checks whether a `jmp rel8` located at `source` can reach `target`
+///
+/// The displacement is calculated from the end of the 2-byte instruction, so this is the check to use before emitting a rel8 jump at `source`.
+pub fn within_rel8(source: usize, target: usize) -> bool {
+    displacement::<i8>(source.wrapping_add(JMP_REL8_LEN), target).is_some()
+}
+
+/// Guesses the length of the function starting at `start` by disassembling forward from it
+///
+/// Decoding stops at the first `ret` or unconditional jump that isn't skipped over by an earlier forward branch, and the length up to and including that instruction is returned.
+/// Returns `None` if no end is found within `max_len` bytes or an invalid instruction is decoded first.
+///
+/// This is a heuristic, so check the result before relying on it. Known limitations:
+/// - Code after a call to a function that never returns (e.g. `abort`) is treated as part of the function
+/// - Jump tables (`jmp [reg*8 + table]`) are treated as the end of the function, since their targets aren't known
+/// - A forward `jmp` within `max_len` is assumed to be internal, so a tail call to a nearby function extends the result
+/// - Code shared between functions, or functions split into separate hot and cold sections, aren't handled
+///
+/// # Safety
+///
+/// `start` must be valid for reads of `max_len` bytes
+pub unsafe fn find_function_end<A: Architecture>(
+    start: *const u8,
+    max_len: usize,
+) -> Option<usize> {
+    // Safety: the caller must ensure that `start` is valid for `max_len` bytes
+    let code = slice::from_raw_parts(start, max_len);
+    let start = start as u64;
+    let end = start + max_len as u64;
+    let mut decoder = Decoder::with_ip(A::bitness(), code, start, DecoderOptions::NONE);
+
+    // Furthest address reached by a forward branch. Code before it is still part of the function
+    let mut furthest = start;
+    for instruction in decoder.iter() {
+        if instruction.is_invalid() {
+            return None;
+        }
+
+        let next = instruction.next_ip();
+        let forward_target = match instruction.flow_control() {
+            FlowControl::ConditionalBranch | FlowControl::UnconditionalBranch => {
+                Some(instruction.near_branch_target())
+                    .filter(|&target| target >= next && target < end)
+            }
+            _ => None,
+        };
+        if let Some(target) = forward_target {
+            furthest = furthest.max(target);
+        }
+
+        let terminates = match instruction.flow_control() {
+            FlowControl::Return | FlowControl::IndirectBranch => true,
+            FlowControl::UnconditionalBranch => forward_target.is_none(),
+            _ => false,
+        };
+        if terminates && next > furthest {
+            return Some((next - start) as usize);
+        }
+    }
+    None
+}
+
+/// Recommended multi-byte NOPs, indexed by length - 1
+///
+/// These are valid in both 32 and 64-bit code.
+const MULTI_BYTE_NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0f, 0x1f, 0x00],
+    &[0x0f, 0x1f, 0x40, 0x00],
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// Generates `len` bytes of NOPs using as few multi-byte NOPs as possible
+///
+/// Runs longer than the largest NOP (9 bytes) are made of several NOPs.
+pub fn multi_byte_nops(len: usize) -> Vec<u8> {
+    let mut nops = Vec::with_capacity(len);
+    while nops.len() < len {
+        let remaining = len - nops.len();
+        nops.extend_from_slice(MULTI_BYTE_NOPS[remaining.min(MULTI_BYTE_NOPS.len()) - 1]);
+    }
+    nops
+}
+
+/// Replaces the instruction at `location` with `instruction`, returning a guard for the patch
+///
+/// `instruction` is encoded for `location`, so relative branches and RIP-relative operands are fixed up for where it's written.
+/// A shorter instruction is padded with [`multi_byte_nops`] to the length of the one it replaces.
+/// A longer one would overwrite the next instruction, so it's rejected with [`InstructionPatchError::TooLong`]; use a [`CodePatcher`](crate::patcher::code::CodePatcher) to patch across instructions.
+///
+/// # Safety
+///
+/// `location` must point to valid code for `A`, readable for the max instruction length and valid for `patcher`. See [`Patcher::patch`]
+pub unsafe fn patch_instruction<'a, P: Patcher, A: Architecture>(
+    patcher: &'a P,
+    location: *const u8,
+    instruction: &Instruction,
+) -> Result<P::Guard<'a>, InstructionPatchError<P::Error>> {
+    // Safety: the caller must ensure that `location` is readable for the max instruction length
+    let code = slice::from_raw_parts(location, A::max_instr_len());
+    let original =
+        Decoder::with_ip(A::bitness(), code, location as u64, DecoderOptions::NONE).decode();
+    if original.is_invalid() {
+        return Err(InstructionPatchError::InvalidInstruction(location as _));
+    }
+
+    let mut encoder = Encoder::new(A::bitness());
+    let needed = encoder.encode(instruction, location as u64)?;
+    if needed > original.len() {
+        return Err(InstructionPatchError::TooLong {
+            needed,
+            available: original.len(),
+        });
+    }
+
+    let mut patch = encoder.take_buffer();
+    patch.extend(multi_byte_nops(original.len() - needed));
+    patcher
+        .patch(location as _, &patch)
+        .map_err(InstructionPatchError::PatcherError)
+}
+
+/// Replaces runs of single-byte `nop`s within `len` bytes at `location` with as few multi-byte NOPs as possible
+///
+/// The code is disassembled first, so `0x90` bytes inside other instructions are left alone.
+/// A run is split wherever a branch within the range targets it, so that every branch still lands on an instruction boundary.
+/// Branches from outside the range can't be seen, so only use this on code where nothing else jumps into the NOPs.
+///
+/// # Safety
+///
+/// `location` must be valid and writable for `len` bytes, and nothing may be executing the code while it's rewritten
+pub unsafe fn coalesce_nops<A: Architecture>(location: *mut u8, len: usize) {
+    // Safety: the caller must ensure that `location` is valid for `len` bytes
+    let code = slice::from_raw_parts(location, len);
+    let start = location as u64;
+    let mut decoder = Decoder::with_ip(A::bitness(), code, start, DecoderOptions::NONE);
+    let instructions: Vec<_> = decoder.iter().take_while(|i| !i.is_invalid()).collect();
+
+    let targets: Vec<_> = instructions
+        .iter()
+        .map(|instruction| instruction.near_branch_target())
+        .filter(|&target| target != 0)
+        .collect();
+
+    // Runs of single-byte NOPs, as (offset, length)
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for instruction in &instructions {
+        let offset = (instruction.ip() - start) as usize;
+        if instruction.len() != 1 || code[offset] != 0x90 {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((run, run_len))
+                if *run + *run_len == offset && !targets.contains(&instruction.ip()) =>
+            {
+                *run_len += 1
+            }
+            _ => runs.push((offset, 1)),
+        }
+    }
+
+    for (offset, run_len) in runs {
+        let nops = multi_byte_nops(run_len);
+        // Safety: the caller must ensure that `location` is writable for `len` bytes
+        ptr::copy_nonoverlapping(nops.as_ptr(), location.add(offset), run_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use iced_x86::{Code, Instruction, Register};
+
+    use crate::code::arch::X86_64;
+    use crate::code::{
+        coalesce_nops, displacement, find_function_end, multi_byte_nops, patch_instruction,
+        within_rel32, within_rel8, InstructionPatchError, MULTI_BYTE_NOPS,
+    };
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::PatchGuard;
+
+    #[test]
+    /// Tests displacement calculation in both directions
+    fn test_displacement() {
+        assert_eq!(displacement::<i32>(0x1000, 0x1100), Some(0x100));
+        assert_eq!(displacement::<i32>(0x1100, 0x1000), Some(-0x100));
+        assert_eq!(displacement::<i8>(0x1000, 0x1100), None);
+        assert_eq!(displacement::<i32>(0, 0x1_0000_0000), None);
+    }
+
+    #[test]
+    /// Tests the boundaries of rel32 jumps, accounting for the instruction length
+    fn test_within_rel32() {
+        let source = 0x1_0000_0000usize;
+        let next_ip = source + 5;
+
+        assert!(within_rel32(source, next_ip + i32::MAX as usize));
+        assert!(!within_rel32(source, next_ip + i32::MAX as usize + 1));
+        assert!(within_rel32(source, next_ip - 0x8000_0000));
+        assert!(!within_rel32(source, next_ip - 0x8000_0001));
+
+        // the displacement is relative to the end of the instruction, not the start
+        assert!(within_rel32(source, source + 0x8000_0000));
+        assert!(!within_rel32(source, source - 0x7fff_fffc));
+    }
+
+    #[test]
+    /// Tests the boundaries of rel8 jumps, accounting for the instruction length
+    fn test_within_rel8() {
+        let source = 0x1000usize;
+
+        assert!(within_rel8(source, source + 2 + 127));
+        assert!(!within_rel8(source, source + 2 + 128));
+        assert!(within_rel8(source, source + 2 - 128));
+        assert!(!within_rel8(source, source + 2 - 129));
+    }
+
+    #[test]
+    /// Tests that addresses near the ends of the address space wrap around
+    fn test_wrapping() {
+        assert!(within_rel32(usize::MAX - 1, 0x10));
+        assert!(within_rel8(0, usize::MAX - 0x10));
+    }
+
+    #[test]
+    /// Tests that a `ret` skipped over by a branch doesn't end the function
+    fn test_find_function_end() {
+        let code = [
+            0x55, // push rbp
+            0x85, 0xff, // test edi, edi
+            0x74, 0x03, // je +3
+            0x31, 0xc0, // xor eax, eax
+            0xc3, // ret
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0x5d, // pop rbp
+            0xc3, // ret
+            0xcc, 0xcc, 0xcc, 0xcc,
+        ];
+
+        let len = unsafe { find_function_end::<X86_64>(code.as_ptr(), code.len()) };
+        assert_eq!(len, Some(15));
+
+        // the end isn't within the bound
+        let len = unsafe { find_function_end::<X86_64>(code.as_ptr(), 10) };
+        assert_eq!(len, None);
+    }
+
+    #[test]
+    /// Tests that backward jumps and tail calls end the function
+    fn test_find_function_end_jmp() {
+        let code = [
+            0x31, 0xc0, // xor eax, eax
+            0xff, 0xc0, // inc eax
+            0xeb, 0xfc, // jmp -4
+            0xcc, 0xcc,
+        ];
+        let len = unsafe { find_function_end::<X86_64>(code.as_ptr(), code.len()) };
+        assert_eq!(len, Some(6));
+
+        let code = [
+            0x31, 0xc0, // xor eax, eax
+            0xe9, 0x00, 0x10, 0x00, 0x00, // jmp +0x1000
+            0xcc,
+        ];
+        let len = unsafe { find_function_end::<X86_64>(code.as_ptr(), code.len()) };
+        assert_eq!(len, Some(7));
+    }
+
+    #[test]
+    /// Tests that NOP padding uses the longest NOPs that fit
+    fn test_multi_byte_nops() {
+        assert_eq!(multi_byte_nops(0), []);
+        assert_eq!(multi_byte_nops(1), [0x90]);
+        assert_eq!(multi_byte_nops(3), [0x0f, 0x1f, 0x00]);
+
+        let mut expected = MULTI_BYTE_NOPS[8].to_vec();
+        expected.extend([0x66, 0x90]);
+        assert_eq!(multi_byte_nops(11), expected);
+    }
+
+    #[test]
+    /// Tests that NOP runs are merged without touching other instructions or branch targets
+    fn test_coalesce_nops() {
+        let mut code = vec![0x90; 5];
+        code.push(0xc3); // ret
+        code.extend([0x90; 2]);
+        code.extend([0xb8, 0x90, 0x90, 0x90, 0x90]); // mov eax, 0x90909090
+        code.extend([0x90; 12]);
+        code.extend([0xeb, 0x02]); // jmp +2, into the middle of the next run
+        code.extend([0x90; 4]);
+        let (ptr, size, capacity) = code.into_raw_parts();
+
+        unsafe { coalesce_nops::<X86_64>(ptr, size) };
+
+        let mut expected = vec![0x0f, 0x1f, 0x44, 0x00, 0x00];
+        expected.push(0xc3);
+        expected.extend([0x66, 0x90]);
+        expected.extend([0xb8, 0x90, 0x90, 0x90, 0x90]);
+        expected.extend([0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        expected.extend([0x0f, 0x1f, 0x00]);
+        expected.extend([0xeb, 0x02]);
+        expected.extend([0x66, 0x90, 0x66, 0x90]);
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, size) }, expected);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests replacing an instruction with a shorter one, and rejecting a longer one
+    fn test_patch_instruction() {
+        // mov eax, 1; ret
+        let mut vec = vec![0xb8u8, 0x01, 0x00, 0x00, 0x00, 0xc3];
+        vec.resize(32, 0xcc);
+        let (ptr, size, capacity) = vec.into_raw_parts();
+        let patcher = BytePatcher::new();
+
+        let xor = Instruction::with2(Code::Xor_rm32_r32, Register::EAX, Register::EAX).unwrap();
+        let guard = unsafe { patch_instruction::<_, X86_64>(&patcher, ptr, &xor) }.unwrap();
+        // xor eax, eax; nop dword ptr [rax]
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, 6) },
+            [0x31, 0xc0, 0x0f, 0x1f, 0x00, 0xc3]
+        );
+        guard.restore();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, 6) },
+            [0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3]
+        );
+
+        // `ret` is too short to hold `xor eax, eax`
+        let result = unsafe { patch_instruction::<_, X86_64>(&patcher, ptr.add(5), &xor) };
+        assert!(matches!(
+            result,
+            Err(InstructionPatchError::TooLong {
+                needed: 2,
+                available: 1
+            })
+        ));
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
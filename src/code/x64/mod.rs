@@ -1,4 +1,10 @@
-use std::mem;
+use std::{mem, ptr, slice};
+
+use iced_x86::{Decoder, DecoderOptions};
+use lazy_static::lazy_static;
+
+use super::arch::{Architecture, X86_64};
+use super::{displacement, within_rel32, within_rel8, JMP_REL32_LEN, JMP_REL8_LEN};
 
 #[repr(packed)]
 #[allow(dead_code)]
@@ -10,12 +16,252 @@ struct JmpAbs {
     target: usize,
 }
 
+/// Opcode bytes for `jmp [rip + 0]`, used as the prefix of [`jmp_abs`]
+const JMP_ABS_OPCODE: [u8; 6] = [0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+
+/// Length of the bytecode generated by [`jmp_abs`]
+pub const JMP_ABS_LEN: usize = mem::size_of::<JmpAbs>();
+
 /// Generates an absolute jump to a specified address and returns bytecode
-pub fn jmp_abs(target: usize) -> [u8; mem::size_of::<JmpAbs>()] {
+pub fn jmp_abs(target: usize) -> [u8; JMP_ABS_LEN] {
     unsafe {
         mem::transmute(JmpAbs {
-            jmp: [0xff, 0x25, 0x00, 0x00, 0x00, 0x00],
+            jmp: JMP_ABS_OPCODE,
             target,
         })
     }
 }
+
+/// Generates a `jmp rel32` located at `source` that jumps to `target`
+///
+/// Returns `None` if `target` is out of range, i.e. the displacement from the end of the 5-byte instruction doesn't fit in an `i32`.
+pub fn jmp_rel(source: usize, target: usize) -> Option<[u8; JMP_REL32_LEN]> {
+    let displacement = displacement::<i32>(source.wrapping_add(JMP_REL32_LEN), target)?;
+    let mut code = [0xe9, 0, 0, 0, 0];
+    code[1..].copy_from_slice(&displacement.to_le_bytes());
+    Some(code)
+}
+
+/// Generates the shorter of [`jmp_rel`] and [`jmp_abs`] for a jump located at `source` to `target`
+///
+/// `jmp rel8` is never chosen: it only reaches 127 bytes, so it's almost never usable for a hook, and a patch that's sometimes 2 bytes would make the relocated length depend on the destination even more.
+pub fn jmp_near_or_abs(source: usize, target: usize) -> Vec<u8> {
+    match jmp_rel(source, target) {
+        Some(code) => code.to_vec(),
+        None => jmp_abs(target).to_vec(),
+    }
+}
+
+/// Reads the target of an absolute jump generated by [`jmp_abs`]
+///
+/// Returns `None` if `code` doesn't start with an absolute jump
+pub fn read_jmp_abs(code: &[u8]) -> Option<usize> {
+    let code = code.get(..JMP_ABS_LEN)?;
+    if code[..JMP_ABS_OPCODE.len()] != JMP_ABS_OPCODE {
+        return None;
+    }
+    let target = code[JMP_ABS_OPCODE.len()..].try_into().ok()?;
+    Some(usize::from_le_bytes(target))
+}
+
+/// Max number of jumps followed by [`follow_thunks`], in case the thunks form a loop
+const MAX_THUNK_DEPTH: usize = 16;
+
+/// Follows a chain of thunks starting at `location`, returning the first location that isn't a jump
+///
+/// `jmp rel8`, `jmp rel32` and `jmp [rip + disp]` are followed.
+/// Jumps generated by [`jmp_abs`] are *not* followed, since they're hooks rather than thunks.
+///
+/// # Safety
+///
+/// `location` and every thunk it leads to must be valid for reads of 16 bytes, and every `jmp [rip + disp]` must point to a readable address
+pub unsafe fn follow_thunks(location: *const u8) -> *const u8 {
+    let mut location = location;
+    for _ in 0..MAX_THUNK_DEPTH {
+        // Safety: the caller must ensure that `location` is valid for the longest instruction
+        let code = slice::from_raw_parts(location, 16);
+        if read_jmp_abs(code).is_some() {
+            break;
+        }
+
+        let instruction =
+            Decoder::with_ip(64, code, location as u64, DecoderOptions::NONE).decode();
+        location = if instruction.is_jmp_short_or_near() {
+            instruction.near_branch_target() as _
+        } else if instruction.is_jmp_near_indirect() && instruction.is_ip_rel_memory_operand() {
+            // Safety: the caller must ensure that the jump's pointer is readable
+            ptr::read_unaligned(instruction.ip_rel_memory_address() as *const usize) as _
+        } else {
+            break;
+        };
+    }
+    location
+}
+
+/// `endbr64`, which marks a valid target for indirect branches when CET indirect branch tracking is enforced
+pub const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+
+lazy_static! {
+    /// Whether the CPU supports indirect branch tracking
+    static ref IBT_SUPPORTED: bool = ibt_supported();
+}
+
+/// Returns `true` if generated code that can be reached by an indirect branch should start with [`ENDBR64`]
+///
+/// This is the case when the `cet` feature is enabled, or when the CPU supports indirect branch tracking.
+/// `endbr64` executes as a NOP when CET isn't enforced, so emitting it on a supported CPU is harmless even if the process doesn't enable CET.
+pub fn needs_endbr64() -> bool {
+    cfg!(feature = "cet") || *IBT_SUPPORTED
+}
+
+/// Checks CPUID for indirect branch tracking support (leaf 7, EDX bit 20)
+fn ibt_supported() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{__cpuid, __cpuid_count};
+
+        #[allow(unused_unsafe)]
+        let (max_leaf, features) = unsafe { (__cpuid(0).eax, __cpuid_count(7, 0).edx) };
+        max_leaf >= 7 && features & (1 << 20) != 0
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+/// Generates code that immediately returns `value` in `rax`
+///
+/// Uses the shortest encoding for the value: `xor eax, eax` for zero, `mov eax, imm32` (which zero-extends) for values that fit in 32 bits, and `mov rax, imm64` otherwise.
+pub fn ret_const(value: u64) -> Vec<u8> {
+    let mut code = if value == 0 {
+        // xor eax, eax
+        vec![0x31, 0xc0]
+    } else if let Ok(value) = u32::try_from(value) {
+        // mov eax, imm32
+        let mut code = vec![0xb8];
+        code.extend(value.to_le_bytes());
+        code
+    } else {
+        // mov rax, imm64
+        let mut code = vec![0x48, 0xb8];
+        code.extend(value.to_le_bytes());
+        code
+    };
+    // ret
+    code.push(0xc3);
+    code
+}
+
+/// Gets the length of the smallest jump at `source` that can reach `destination`
+///
+/// Returns the length of a `jmp rel8` (2), `jmp rel32` (5), or [`jmp_abs`] ([`X86_64::abs_jmp_len`]), depending on which is in range
+pub fn min_jmp_len(source: usize, destination: usize) -> usize {
+    if within_rel8(source, destination) {
+        JMP_REL8_LEN
+    } else if within_rel32(source, destination) {
+        JMP_REL32_LEN
+    } else {
+        X86_64::abs_jmp_len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::x64::{follow_thunks, jmp_abs, jmp_near_or_abs, jmp_rel, ret_const};
+    #[cfg(feature = "trampolines")]
+    use crate::test_util::TestFn;
+
+    #[test]
+    /// Tests near jump encodings, including the largest displacements in each direction
+    fn test_jmp_rel() {
+        let source = 0x1_0000_0000usize;
+        let next_ip = source + 5;
+
+        assert_eq!(
+            jmp_rel(source, source),
+            Some([0xe9, 0xfb, 0xff, 0xff, 0xff])
+        );
+        assert_eq!(
+            jmp_rel(source, next_ip + 0x1234),
+            Some([0xe9, 0x34, 0x12, 0x00, 0x00])
+        );
+        assert_eq!(
+            jmp_rel(source, next_ip + i32::MAX as usize),
+            Some([0xe9, 0xff, 0xff, 0xff, 0x7f])
+        );
+        assert_eq!(jmp_rel(source, next_ip + i32::MAX as usize + 1), None);
+        assert_eq!(
+            jmp_rel(source, next_ip - 0x8000_0000),
+            Some([0xe9, 0x00, 0x00, 0x00, 0x80])
+        );
+        assert_eq!(jmp_rel(source, next_ip - 0x8000_0001), None);
+    }
+
+    #[test]
+    /// Tests that the near jump is used whenever it's in range
+    fn test_jmp_near_or_abs() {
+        let source = 0x1_0000_0000usize;
+        assert_eq!(
+            jmp_near_or_abs(source, source + 0x1005),
+            [0xe9, 0x00, 0x10, 0x00, 0x00]
+        );
+        assert_eq!(
+            jmp_near_or_abs(source, source + 0x1_0000_0000),
+            jmp_abs(source + 0x1_0000_0000)
+        );
+    }
+
+    #[test]
+    /// Tests following a chain of short, near, and indirect jumps
+    fn test_follow_thunks() {
+        let mut code = [0xccu8; 0x50];
+        let base = code.as_ptr() as usize;
+
+        // jmp short 0x10
+        code[0x00..0x02].copy_from_slice(&[0xeb, 0x0e]);
+        // jmp near 0x20
+        code[0x10..0x15].copy_from_slice(&[0xe9, 0x0b, 0x00, 0x00, 0x00]);
+        // jmp [rip + 2], with the target stored after 2 bytes of padding
+        code[0x20..0x26].copy_from_slice(&[0xff, 0x25, 0x02, 0x00, 0x00, 0x00]);
+        code[0x28..0x30].copy_from_slice(&(base + 0x30).to_le_bytes());
+        // push rbp
+        code[0x30] = 0x55;
+
+        assert_eq!(
+            unsafe { follow_thunks(code.as_ptr()) } as usize,
+            base + 0x30
+        );
+        assert_eq!(unsafe { follow_thunks(&code[0x30]) } as usize, base + 0x30);
+    }
+
+    #[test]
+    /// Tests that existing hooks aren't followed
+    fn test_follow_thunks_hooked() {
+        let mut code = jmp_abs(0x1234).to_vec();
+        code.resize(16, 0xcc);
+
+        assert_eq!(unsafe { follow_thunks(code.as_ptr()) }, code.as_ptr());
+    }
+
+    #[test]
+    /// Tests the encoding chosen for each size of value
+    fn test_ret_const() {
+        assert_eq!(ret_const(0), [0x31, 0xc0, 0xc3]);
+        assert_eq!(ret_const(0x1234), [0xb8, 0x34, 0x12, 0x00, 0x00, 0xc3]);
+        assert_eq!(
+            ret_const(u64::MAX),
+            [0x48, 0xb8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc3]
+        );
+    }
+
+    #[cfg(feature = "trampolines")]
+    #[test]
+    /// Tests that the generated code returns the value when called
+    fn test_ret_const_call() {
+        for value in [0, 1, 0xffff_ffff, 0x1_0000_0000, u64::MAX] {
+            let function = TestFn::<extern "C" fn() -> u64>::new(&ret_const(value));
+            assert_eq!(unsafe { function.get() }(), value);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! # x86
+//!
+//! Helpers for generating 32-bit x86 code. `jmp [rip + 0]` doesn't exist outside of 64-bit mode, so absolute jumps are `push imm32; ret` instead
+
+/// Length of the bytecode generated by [`jmp_abs`]
+pub const JMP_ABS_LEN: usize = 6;
+
+/// Generates an absolute jump to a specified address and returns bytecode
+///
+/// The jump is `push target; ret`. The `ret` pops what the `push` pushed, so the stack is unchanged by the time `target` runs.
+pub fn jmp_abs(target: u32) -> [u8; JMP_ABS_LEN] {
+    let mut code = [0x68, 0, 0, 0, 0, 0xc3];
+    code[1..5].copy_from_slice(&target.to_le_bytes());
+    code
+}
+
+/// Reads the target of an absolute jump generated by [`jmp_abs`]
+///
+/// Returns `None` if `code` doesn't start with an absolute jump
+pub fn read_jmp_abs(code: &[u8]) -> Option<u32> {
+    match code.get(..JMP_ABS_LEN)? {
+        [0x68, target @ .., 0xc3] => Some(u32::from_le_bytes(target.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Generates code that immediately returns `value` in `edx:eax`
+///
+/// Both halves are always set, so the code works for functions returning 32- or 64-bit values. Each half is zeroed with `xor` if it's zero and loaded with `mov r32, imm32` otherwise.
+pub fn ret_const(value: u64) -> Vec<u8> {
+    let mut code = Vec::with_capacity(11);
+    for (half, xor, mov) in [
+        (value as u32, [0x31, 0xc0], 0xb8),         // eax
+        ((value >> 32) as u32, [0x31, 0xd2], 0xba), // edx
+    ] {
+        if half == 0 {
+            code.extend(xor);
+        } else {
+            code.push(mov);
+            code.extend(half.to_le_bytes());
+        }
+    }
+    // ret
+    code.push(0xc3);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::x86::{jmp_abs, read_jmp_abs, ret_const};
+
+    #[test]
+    /// Tests that absolute jumps are generated and read back
+    fn test_jmp_abs() {
+        assert_eq!(jmp_abs(0x1234_5678), [0x68, 0x78, 0x56, 0x34, 0x12, 0xc3]);
+        assert_eq!(read_jmp_abs(&jmp_abs(0x1234_5678)), Some(0x1234_5678));
+        assert_eq!(read_jmp_abs(&[0x68, 0, 0, 0, 0, 0x90]), None);
+        assert_eq!(read_jmp_abs(&[0x68, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    /// Tests the encoding chosen for each half of the value
+    fn test_ret_const() {
+        assert_eq!(ret_const(0), [0x31, 0xc0, 0x31, 0xd2, 0xc3]);
+        assert_eq!(
+            ret_const(0x1234),
+            [0xb8, 0x34, 0x12, 0x00, 0x00, 0x31, 0xd2, 0xc3]
+        );
+        assert_eq!(
+            ret_const(0x1_0000_0000),
+            [0x31, 0xc0, 0xba, 0x01, 0x00, 0x00, 0x00, 0xc3]
+        );
+    }
+}
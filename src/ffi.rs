@@ -0,0 +1,84 @@
+//! # FFI
+//!
+//! This module covers handing guards to C code.
+//!
+//! Guards restore their patch or hook when dropped, but C code can't run Rust destructors.
+//! [`FfiGuard::into_raw`] erases a guard behind a thin opaque pointer that C can hold onto, and [`libhook_guard_restore`] takes ownership back and drops it.
+//! Every pointer returned by [`FfiGuard::into_raw`] must be passed to [`libhook_guard_restore`] exactly once, otherwise the guard is leaked and never restored.
+
+use std::any::Any;
+
+/// Type-erased guard that can be handed to C as an opaque pointer
+pub struct FfiGuard {
+    /// Guard being kept alive. Dropping it restores the patch or hook
+    _guard: Box<dyn Any>,
+}
+impl FfiGuard {
+    /// Moves `guard` to the heap and returns an opaque pointer to it for use from C
+    ///
+    /// The guard is only restored once the pointer is passed to [`libhook_guard_restore`].
+    /// Guards must be `'static` since C can hold onto the pointer for as long as it wants.
+    pub fn into_raw<G: 'static>(guard: G) -> *mut FfiGuard {
+        Box::into_raw(Box::new(FfiGuard {
+            _guard: Box::new(guard),
+        }))
+    }
+}
+
+/// Restores the guard behind `guard` and frees it
+///
+/// Does nothing if `guard` is null.
+///
+/// # Safety
+///
+/// `guard` must be null or a pointer returned by [`FfiGuard::into_raw`] that hasn't already been restored
+#[no_mangle]
+pub unsafe extern "C" fn libhook_guard_restore(guard: *mut FfiGuard) {
+    if !guard.is_null() {
+        // Safety: the caller guarantees that we have ownership of the guard
+        drop(Box::from_raw(guard));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::slice;
+
+    use crate::ffi::{libhook_guard_restore, FfiGuard};
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::Patcher;
+
+    /// Stands in for C code that was handed a guard and later frees it
+    extern "C" fn c_code(guard: *mut FfiGuard, restore: unsafe extern "C" fn(*mut FfiGuard)) {
+        unsafe { restore(guard) }
+    }
+
+    #[test]
+    /// Tests that a guard handed across FFI is restored when C frees it
+    fn test_restore() {
+        let vec = vec![1u8, 2, 3, 4];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = BytePatcher::new();
+        let guard = unsafe { patcher.patch(ptr, &[5, 6]) }.unwrap();
+        let guard = FfiGuard::into_raw(guard);
+
+        // the patch stays applied while C holds the guard
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [5, 6, 3, 4]);
+
+        c_code(guard, libhook_guard_restore);
+
+        // make sure the patch was restored
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [1, 2, 3, 4]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that restoring a null guard is a no-op
+    fn test_restore_null() {
+        unsafe { libhook_guard_restore(ptr::null_mut()) };
+    }
+}
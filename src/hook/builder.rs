@@ -0,0 +1,284 @@
+//! # Hook Builder
+//!
+//! Hooks are usually made by combining a [`CodePatcher`] constructor, a jump encoding and a handful of options, each of which has its own constructor or method.
+//! [`HookBuilder`] collects those options in one place and installs a [`DetourHook`](super::detour::DetourHook)-style hook with them:
+//!
+//! ```no_run
+//! use libhook::hook::builder::{HookBuilder, JumpStrategy};
+//! use libhook::patcher::code::Fill;
+//!
+//! # let (source, destination) = (0x1000 as *const u8, 0x2000 as *const u8);
+//! let guard = unsafe {
+//!     HookBuilder::new()
+//!         .source(source)
+//!         .destination(destination)
+//!         .strategy(JumpStrategy::Auto)
+//!         .fill(Fill::Byte(0xcc))
+//!         .check_signature(&[0x55, 0x48, 0x89, 0xe5])
+//!         .build()
+//! }
+//! .unwrap();
+//! let original = guard.original();
+//! ```
+
+use std::slice;
+
+use thiserror::Error;
+
+use crate::code::x64::{follow_thunks, jmp_abs, jmp_near_or_abs, jmp_rel};
+use crate::patcher::byte::BytePatcher;
+use crate::patcher::code::{CodeError, CodePatcher, Fill, X86_64};
+use crate::patcher::mem::PermissionError;
+use crate::patcher::Patcher;
+
+use super::detour::DetourHookGuard;
+
+/// Jump written over the source by [`HookBuilder`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JumpStrategy {
+    /// Always write a 14-byte absolute jump. See [`jmp_abs`]
+    Abs,
+    /// Always write a 5-byte `jmp rel32`, failing with [`HookBuilderError::OutOfRange`] if the destination is further than ±2GiB. See [`jmp_rel`]
+    Near,
+    /// Write a `jmp rel32` if the destination is in range, and an absolute jump otherwise. See [`jmp_near_or_abs`]
+    #[default]
+    Auto,
+}
+
+#[derive(Debug, Error)]
+/// Error types for [`HookBuilder`]
+pub enum HookBuilderError<E> {
+    /// [`HookBuilder::source`] wasn't called
+    #[error("No source to hook")]
+    MissingSource,
+    /// [`HookBuilder::destination`] wasn't called
+    #[error("No destination to redirect to")]
+    MissingDestination,
+    /// The bytes at the source don't match the signature passed to [`HookBuilder::check_signature`]. Holds the source address
+    #[error("Source {0:?} doesn't match the expected signature")]
+    SignatureMismatch(*const ()),
+    /// [`JumpStrategy::Near`] was chosen but the destination is out of range of a `jmp rel32`. Holds the destination address
+    #[error("Destination {0:?} is out of range of a near jump")]
+    OutOfRange(*const ()),
+    /// Error while preparing or writing the patch
+    #[error("{0}")]
+    CodeError(#[from] CodeError<E>),
+}
+
+/// Builder for trampoline-backed jump hooks
+///
+/// Only the source and destination are required; everything else defaults to what [`CodePatcher::new`] does.
+pub struct HookBuilder<P = BytePatcher> {
+    /// Patcher that the code patcher writes with
+    patcher: P,
+    /// Location to hook
+    source: Option<*const u8>,
+    /// Location that execution is redirected to
+    destination: Option<*const u8>,
+    /// Jump written over the source
+    strategy: JumpStrategy,
+    /// Padding after the jump
+    fill: Fill,
+    /// Bytes the source must start with
+    signature: Option<Vec<u8>>,
+    /// Whether to hook the function a thunk at the source jumps to
+    follow_thunks: bool,
+}
+impl HookBuilder {
+    /// Creates a builder that writes with a [`BytePatcher`]
+    pub fn new() -> Self {
+        Self::with_patcher(BytePatcher::new())
+    }
+}
+impl Default for HookBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<P> HookBuilder<P>
+where
+    P: Patcher + 'static,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Creates a builder that writes with `patcher`
+    ///
+    /// The patcher will be wrapped in a [`PermissionWrapper`](crate::patcher::mem::PermissionWrapper), so there is no need to wrap it yourself.
+    pub fn with_patcher(patcher: P) -> Self {
+        Self {
+            patcher,
+            source: None,
+            destination: None,
+            strategy: JumpStrategy::default(),
+            fill: Fill::default(),
+            signature: None,
+            follow_thunks: false,
+        }
+    }
+    /// Sets the location to hook
+    pub fn source(self, source: *const u8) -> Self {
+        Self {
+            source: Some(source),
+            ..self
+        }
+    }
+    /// Sets the location that execution is redirected to
+    pub fn destination(self, destination: *const u8) -> Self {
+        Self {
+            destination: Some(destination),
+            ..self
+        }
+    }
+    /// Sets the jump written over the source. Defaults to [`JumpStrategy::Auto`]
+    pub fn strategy(self, strategy: JumpStrategy) -> Self {
+        Self { strategy, ..self }
+    }
+    /// Sets how relocated bytes after the jump are padded. See [`CodePatcher::with_fill`]
+    pub fn fill(self, fill: Fill) -> Self {
+        Self { fill, ..self }
+    }
+    /// Refuses to hook unless the source starts with `signature`
+    ///
+    /// Use this to make sure the code is the version the hook was written for (e.g. after the target updates).
+    /// When following thunks, the signature is checked against the function the thunks lead to.
+    pub fn check_signature(self, signature: &[u8]) -> Self {
+        Self {
+            signature: Some(signature.to_vec()),
+            ..self
+        }
+    }
+    /// Hooks the function a thunk at the source ultimately jumps to rather than the thunk itself. See [`follow_thunks`]
+    pub fn following_thunks(self) -> Self {
+        Self {
+            follow_thunks: true,
+            ..self
+        }
+    }
+    /// Installs the hook
+    ///
+    /// # Safety
+    ///
+    /// See [`Hook::hook`](super::Hook::hook). The source must also be valid for reads of the signature, if one was given.
+    pub unsafe fn build(self) -> Result<DetourHookGuard<P>, HookBuilderError<P::Error>> {
+        let source = self.source.ok_or(HookBuilderError::MissingSource)?;
+        let destination = self
+            .destination
+            .ok_or(HookBuilderError::MissingDestination)?;
+        let source = if self.follow_thunks {
+            follow_thunks(source)
+        } else {
+            source
+        };
+
+        if let Some(signature) = &self.signature {
+            if slice::from_raw_parts(source, signature.len()) != signature.as_slice() {
+                return Err(HookBuilderError::SignatureMismatch(source as _));
+            }
+        }
+
+        let jump = match self.strategy {
+            JumpStrategy::Abs => jmp_abs(destination as _).to_vec(),
+            JumpStrategy::Near => jmp_rel(source as _, destination as _)
+                .ok_or(HookBuilderError::OutOfRange(destination as _))?
+                .to_vec(),
+            JumpStrategy::Auto => jmp_near_or_abs(source as _, destination as _),
+        };
+
+        let patcher =
+            CodePatcher::<P, X86_64>::new(self.patcher, source, jump)?.with_fill(self.fill);
+        Ok(DetourHookGuard::install(patcher).map_err(CodeError::from)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::builder::{HookBuilder, HookBuilderError, JumpStrategy};
+    use crate::patcher::code::Fill;
+
+    /// `push rbp; mov rbp, rsp; sub rsp, 0x20` followed by NOPs
+    fn prologue() -> Vec<u8> {
+        let mut code = vec![0x55, 0x48, 0x89, 0xe5, 0x48, 0x83, 0xec, 0x20];
+        code.resize(48, 0x90);
+        code
+    }
+
+    #[test]
+    /// Tests that the builder's options decide the jump and padding that are written
+    fn test_build() {
+        let (ptr, size, capacity) = prologue().into_raw_parts();
+
+        // a near jump only covers the first 5 bytes, so the rest of `sub rsp, 0x20` is padding
+        let destination = unsafe { ptr.add(0x1005) };
+        let guard = unsafe {
+            HookBuilder::new()
+                .source(ptr)
+                .destination(destination)
+                .fill(Fill::Byte(0xcc))
+                .check_signature(&[0x55, 0x48, 0x89, 0xe5])
+                .build()
+        }
+        .unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, 8) },
+            [0xe9, 0x00, 0x10, 0x00, 0x00, 0xcc, 0xcc, 0xcc]
+        );
+        assert!(!guard.original().is_null());
+        drop(guard);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, prologue());
+
+        // the absolute strategy is used even when a near jump would reach
+        let guard = unsafe {
+            HookBuilder::new()
+                .source(ptr)
+                .destination(destination)
+                .strategy(JumpStrategy::Abs)
+                .build()
+        }
+        .unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, 14) },
+            jmp_abs(destination as _)
+        );
+        drop(guard);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that invalid configurations are rejected before anything is written
+    fn test_build_errors() {
+        let (ptr, size, capacity) = prologue().into_raw_parts();
+
+        let result = unsafe { HookBuilder::new().source(ptr).build() };
+        assert!(matches!(result, Err(HookBuilderError::MissingDestination)));
+
+        let result = unsafe {
+            HookBuilder::new()
+                .source(ptr)
+                .destination(0x1234 as _)
+                .check_signature(&[0xcc])
+                .build()
+        };
+        assert!(
+            matches!(result, Err(HookBuilderError::SignatureMismatch(source)) if std::ptr::eq(source, ptr.cast()))
+        );
+
+        // the heap is never within 2GiB of the first pages of the address space
+        let result = unsafe {
+            HookBuilder::new()
+                .source(ptr)
+                .destination(0x1234 as _)
+                .strategy(JumpStrategy::Near)
+                .build()
+        };
+        assert!(matches!(result, Err(HookBuilderError::OutOfRange(_))));
+
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, prologue());
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
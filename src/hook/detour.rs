@@ -0,0 +1,145 @@
+//! # Detour Hook
+//!
+//! [`JmpHook`](super::jmphook::JmpHook) writes its jump straight over the start of the function, so the overwritten instructions are gone and the original function can't be called while it's hooked.
+//! [`DetourHook`] writes the same jump through a [`CodePatcher`], which relocates the overwritten instructions to a trampoline first.
+//! Calling the trampoline ([`DetourHookGuard::original`]) runs the relocated instructions and jumps back into the rest of the function, which is what a detour needs to call through to the real function.
+//!
+//! Like [`JmpHook`](super::jmphook::JmpHook), the jump is generated by a [`JumpEncoder`], so [`NearJumpEncoder`](super::jmphook::NearJumpEncoder) can be used to relocate fewer instructions.
+
+use std::mem::ManuallyDrop;
+use std::ptr::NonNull;
+
+use crate::patcher::code::{CodeError, CodePatcher, X86_64};
+use crate::patcher::mem::{PermissionError, PermissionWrapperGuard};
+use crate::patcher::{PatchGuard, Patcher};
+
+use super::jmphook::{AbsJumpEncoder, JumpEncoder};
+use super::{Hook, HookGuard};
+
+/// Jump hook that keeps a callable original function
+///
+/// Every hook gets its own [`CodePatcher`] (and therefore its own trampoline), built from a clone of `patcher`.
+/// The patcher is wrapped in a [`PermissionWrapper`](crate::patcher::mem::PermissionWrapper) by the code patcher, so there is no need to wrap it yourself.
+pub struct DetourHook<P, E = AbsJumpEncoder> {
+    /// Underlying patcher to be used to hook
+    patcher: P,
+    /// Generates the jump that's written to `source`
+    encoder: E,
+}
+impl<P: Patcher + Clone> DetourHook<P> {
+    /// Creates a new detour hook
+    pub fn new(patcher: P) -> Self {
+        Self::with_encoder(patcher, AbsJumpEncoder)
+    }
+}
+impl<P: Patcher + Clone, E: JumpEncoder> DetourHook<P, E> {
+    /// Creates a new detour hook that writes jumps generated by `encoder`
+    pub fn with_encoder(patcher: P, encoder: E) -> Self {
+        Self { patcher, encoder }
+    }
+}
+unsafe impl<P, E> Hook for DetourHook<P, E>
+where
+    P: Patcher + Clone + 'static,
+    E: JumpEncoder,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    type Error = CodeError<P::Error>;
+    type Guard<'a>
+        = DetourHookGuard<P>
+    where
+        Self: 'a;
+
+    unsafe fn hook(
+        &self,
+        source: *const u8,
+        destination: *const u8,
+    ) -> Result<Self::Guard<'_>, Self::Error> {
+        let jump = self.encoder.encode(source, destination);
+        let patcher = CodePatcher::new(self.patcher.clone(), source, jump)?;
+        Ok(DetourHookGuard::install(patcher)?)
+    }
+
+    fn preview(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        self.encoder.encode(source, destination)
+    }
+}
+
+/// Guard for [`DetourHook`]
+///
+/// Unhooks when dropped, then frees the trampoline.
+/// Make sure nothing is still running the trampoline (e.g. a detour that's partway through calling the original) when it's dropped.
+pub struct DetourHookGuard<P>
+where
+    P: Patcher + 'static,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Guard for the written jump. Borrows `patcher`, so it must be dropped first
+    guard: ManuallyDrop<PermissionWrapperGuard<P::Guard<'static>>>,
+    /// Patcher that owns the trampoline. Allocated with [`Box::into_raw`] and freed after `guard` is dropped
+    patcher: NonNull<CodePatcher<P, X86_64>>,
+}
+impl<P> DetourHookGuard<P>
+where
+    P: Patcher + 'static,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Writes the patch prepared by `patcher`, keeping the patcher (and its trampoline) alive for as long as the guard
+    pub(crate) fn install(
+        patcher: CodePatcher<P, X86_64>,
+    ) -> Result<Self, PermissionError<P::Error>> {
+        // Safety: `Box::into_raw` never returns null
+        let patcher = unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(patcher))) };
+
+        // Safety: the patcher stays at this address until it's freed, which only happens after the guard is dropped
+        let borrowed: &'static CodePatcher<P, X86_64> = unsafe { patcher.as_ref() };
+        match borrowed.patch() {
+            Ok(guard) => Ok(Self {
+                guard: ManuallyDrop::new(guard),
+                patcher,
+            }),
+            Err(e) => {
+                // Safety: the patcher came from `Box::into_raw`, and nothing borrows it anymore
+                drop(unsafe { Box::from_raw(patcher.as_ptr()) });
+                Err(e)
+            }
+        }
+    }
+    /// Returns a pointer to the original function
+    ///
+    /// Calling it runs the instructions the jump overwrote and then continues in the hooked function, as if it weren't hooked.
+    /// The pointer is only valid until the guard is dropped.
+    pub fn original(&self) -> *const u8 {
+        // Patchers created with [`CodePatcher::new`] always have a trampoline
+        self.patcher().original().unwrap()
+    }
+    /// Gets the code patcher that installed the hook, e.g. to inspect the trampoline
+    pub fn patcher(&self) -> &CodePatcher<P, X86_64> {
+        // Safety: the patcher is only freed when the guard is dropped
+        unsafe { self.patcher.as_ref() }
+    }
+}
+unsafe impl<P> HookGuard for DetourHookGuard<P>
+where
+    P: Patcher + 'static,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+}
+impl<P> Drop for DetourHookGuard<P>
+where
+    P: Patcher + 'static,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    fn drop(&mut self) {
+        // Safety: the guard is never used again, and is dropped while the patcher it borrows is still alive
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        // Safety: the patcher came from `Box::into_raw`, and the guard that borrowed it is gone
+        drop(unsafe { Box::from_raw(self.patcher.as_ptr()) });
+    }
+}
@@ -1,28 +1,113 @@
 //! # Jump Hook
 //!
 //! This hook type uses a basic `jmp` instruction to redirect execution
+//!
+//! The jump is an absolute `jmp` by default, or a `jmp rel32` when it's in range with [`NearJumpEncoder`]. Other encodings can be used by implementing [`JumpEncoder`].
+//!
+//! The jump overwrites the start of the function, so the original can't be called while it's hooked. Use [`DetourHook`](super::detour::DetourHook) to keep a callable original.
+
+use region::Protection;
+use thiserror::Error;
 
 use crate::{
-    code::x64::jmp_abs,
+    code::x64::{follow_thunks, jmp_abs, jmp_near_or_abs},
     patcher::{PatchGuard, Patcher},
 };
 
 use super::{Hook, HookGuard};
 
+#[derive(Debug, Error)]
+/// Error types for [`JmpHook`]
+pub enum JmpHookError<E> {
+    /// Error from the underlying patcher
+    #[error("patcher error")]
+    PatcherError(E),
+    /// The destination is inside the jump that would be written, so the hook would jump to itself
+    #[error("Destination {0:?} is inside the patched jump")]
+    DegenerateJump(*const ()),
+}
+
+/// Generates the code [`JmpHook`] writes to redirect execution
+///
+/// The destination must be reached with `rsp` unchanged, or functions that rely on stack alignment (e.g. for `movaps`) will fault.
+/// See [the code patcher's notes on stack alignment](crate::patcher::code#stack-alignment).
+pub trait JumpEncoder {
+    /// Encodes a jump located at `source` that redirects execution to `destination`
+    fn encode(&self, source: *const u8, destination: *const u8) -> Vec<u8>;
+}
+
+/// Encodes a 14-byte absolute jump, which can reach any destination. See [`jmp_abs`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AbsJumpEncoder;
+impl JumpEncoder for AbsJumpEncoder {
+    fn encode(&self, _source: *const u8, destination: *const u8) -> Vec<u8> {
+        jmp_abs(destination as _).to_vec()
+    }
+}
+
+/// Encodes a 5-byte `jmp rel32` when the destination is within ±2GiB of the source, and falls back to a 14-byte absolute jump otherwise. See [`jmp_near_or_abs`].
+///
+/// The smaller jump overwrites fewer instructions, but the size of the patch depends on where the destination is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NearJumpEncoder;
+impl JumpEncoder for NearJumpEncoder {
+    fn encode(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        jmp_near_or_abs(source as _, destination as _)
+    }
+}
+
 /// Simple jmp hook
-pub struct JmpHook<P> {
+pub struct JmpHook<P, E = AbsJumpEncoder> {
     /// Underlying patcher to be used to hook
     patcher: P,
+    /// Generates the jump that's written to `source`
+    encoder: E,
+    /// Whether to hook the function that a thunk at `source` jumps to rather than the thunk itself
+    follow_thunks: bool,
 }
 impl<P: Patcher> JmpHook<P> {
     /// Creates a new jmp hook
     pub fn new(patcher: P) -> Self {
-        Self { patcher }
+        Self::with_encoder(patcher, AbsJumpEncoder)
+    }
+    /// Creates a new jmp hook that hooks the function a thunk at `source` ultimately jumps to
+    ///
+    /// This is opt-in since sometimes the thunk itself is the intended hook point.
+    /// See [`follow_thunks`] for which jumps are followed.
+    pub fn new_following_thunks(patcher: P) -> Self {
+        Self::new(patcher).following_thunks()
     }
 }
-unsafe impl<P: Patcher> Hook for JmpHook<P> {
-    type Error = P::Error;
-    type Guard<'a> = JmpHookGuard<P::Guard<'a>>
+impl<P: Patcher, E: JumpEncoder> JmpHook<P, E> {
+    /// Creates a new jmp hook that writes jumps generated by `encoder`
+    pub fn with_encoder(patcher: P, encoder: E) -> Self {
+        Self {
+            patcher,
+            encoder,
+            follow_thunks: false,
+        }
+    }
+    /// Hooks the function a thunk at `source` ultimately jumps to rather than the thunk itself
+    ///
+    /// See [`JmpHook::new_following_thunks`].
+    pub fn following_thunks(self) -> Self {
+        Self {
+            follow_thunks: true,
+            ..self
+        }
+    }
+    /// Gets the number of bytes the hook writes to redirect `source` to `destination`
+    ///
+    /// This is the length of the jump the encoder generates, e.g. always 14 with [`AbsJumpEncoder`], and 5 or 14 with [`NearJumpEncoder`] depending on whether a `jmp rel32` is in range.
+    /// Use this to check that the function at `source` is large enough before hooking it.
+    pub fn min_patch_size(&self, source: *const u8, destination: *const u8) -> usize {
+        self.encoder.encode(source, destination).len()
+    }
+}
+unsafe impl<P: Patcher, E: JumpEncoder> Hook for JmpHook<P, E> {
+    type Error = JmpHookError<P::Error>;
+    type Guard<'a>
+        = JmpHookGuard<P::Guard<'a>>
     where
         Self: 'a;
 
@@ -31,12 +116,34 @@ unsafe impl<P: Patcher> Hook for JmpHook<P> {
         source: *const u8,
         destination: *const u8,
     ) -> Result<Self::Guard<'_>, Self::Error> {
-        // patch with an absolute jmp to the destination
+        let source = self.resolve(source);
+
+        let jump = self.encoder.encode(source, destination);
+
+        // Jumping into the patch would loop forever (or run half of the jump) on the first call
+        if (source as usize..source as usize + jump.len()).contains(&(destination as usize)) {
+            return Err(JmpHookError::DegenerateJump(destination as _));
+        }
+
+        // patch with a jmp to the destination
         let patch = self
             .patcher
-            .patch(source as _, &jmp_abs(destination as _))?;
+            .patch(source as _, &jump)
+            .map_err(JmpHookError::PatcherError)?;
+
+        Ok(JmpHookGuard::new(patch, destination))
+    }
+
+    fn preview(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        self.encoder.encode(source, destination)
+    }
 
-        Ok(JmpHookGuard::new(patch))
+    unsafe fn resolve(&self, source: *const u8) -> *const u8 {
+        if self.follow_thunks {
+            follow_thunks(source)
+        } else {
+            source
+        }
     }
 }
 
@@ -45,15 +152,232 @@ unsafe impl<P: Patcher> Hook for JmpHook<P> {
 pub struct JmpHookGuard<G: PatchGuard> {
     /// Underlying patch guard that we're wrapping
     guard: G,
+    /// Location that execution is redirected to. Stored as an address so the guard stays `Send` and `Sync` if `G` is
+    destination: usize,
 }
 impl<G: PatchGuard> JmpHookGuard<G> {
     /// Creates a new jmp hook guard that wraps `guard`
-    fn new(guard: G) -> Self {
-        Self { guard }
+    fn new(guard: G, destination: *const u8) -> Self {
+        Self {
+            guard,
+            destination: destination as usize,
+        }
     }
     /// Get the underlying patch guard in case info is needed
     pub fn patch(&self) -> &G {
         &self.guard
     }
+    /// Gets the location that execution is redirected to
+    pub fn destination(&self) -> *const u8 {
+        self.destination as _
+    }
+    /// Checks whether the destination is still mapped as executable memory
+    ///
+    /// The module containing the destination can be unloaded while the hook is installed, after which calling the hooked function crashes (or runs whatever was loaded in its place).
+    /// This only checks the memory at the destination, so a different module loaded at the same address still counts as valid.
+    pub fn is_target_valid(&self) -> bool {
+        region::query(self.destination())
+            .map(|region| region.protection().contains(Protection::EXECUTE))
+            .unwrap_or(false)
+    }
+}
+unsafe impl<G: PatchGuard> HookGuard for JmpHookGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::code::arch::{Architecture, X86_64};
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::{JmpHook, JmpHookError, JumpEncoder, NearJumpEncoder};
+    use crate::hook::{Hook, HookGuard};
+    use crate::patcher::byte::BytePatcher;
+
+    #[test]
+    /// Tests that the patch size is the length of the jump each encoder writes
+    fn test_min_patch_size() {
+        let source = 0x1_0000_0000usize;
+
+        // absolute jumps are the same size however close the destination is
+        let hook = JmpHook::new(BytePatcher::new());
+        for destination in [source + 2, source + 5 + i32::MAX as usize + 1] {
+            assert_eq!(
+                hook.min_patch_size(source as _, destination as _),
+                X86_64::abs_jmp_len()
+            );
+        }
+
+        let hook = JmpHook::with_encoder(BytePatcher::new(), NearJumpEncoder);
+        let size = |destination: usize| hook.min_patch_size(source as _, destination as _);
+        // there's no rel8 encoder, so even the closest destinations take a rel32
+        assert_eq!(size(source + 2), 5);
+        assert_eq!(size(source + 5 + i32::MAX as usize), 5);
+        assert_eq!(size(source + 5 - 0x8000_0000), 5);
+        assert_eq!(
+            size(source + 5 + i32::MAX as usize + 1),
+            X86_64::abs_jmp_len()
+        );
+        assert_eq!(size(source + 5 - 0x8000_0001), X86_64::abs_jmp_len());
+    }
+
+    #[test]
+    /// Tests that the preview matches the bytes written by the hook
+    fn test_preview() {
+        let hook = JmpHook::new(BytePatcher::new());
+        let destination = 0x1234usize;
+
+        let vec = vec![0u8; 14];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let preview = hook.preview(ptr, destination as _);
+        assert_eq!(preview, jmp_abs(destination));
+
+        // previewing must not touch the source
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0; 14]);
+
+        // the hook should write exactly the previewed bytes
+        let guard = unsafe { hook.hook(ptr, destination as _) }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, preview);
+        assert_eq!(guard.location(), ptr as *const u8);
+        assert_eq!(guard.len(), preview.len());
+        guard.unhook();
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that destinations are only valid while they're mapped as executable
+    fn test_is_target_valid() {
+        let vec = vec![0x90u8; 48];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+        let hook = JmpHook::new(BytePatcher::new());
+
+        let guard = unsafe { hook.hook(ptr, test_is_target_valid as *const u8) }.unwrap();
+        assert_eq!(guard.destination(), test_is_target_valid as *const u8);
+        assert!(guard.is_target_valid());
+
+        // the heap isn't executable
+        let data = unsafe { hook.hook(ptr.add(16), ptr) }.unwrap();
+        assert!(!data.is_target_valid());
+
+        // nothing is mapped at the first page
+        let unmapped = unsafe { hook.hook(ptr.add(32), 0x10 as _) }.unwrap();
+        assert!(!unmapped.is_target_valid());
+
+        drop((guard, data, unmapped));
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that following thunks hooks the thunk's target rather than the thunk
+    fn test_follow_thunks() {
+        let mut vec = vec![0xccu8; 0x20];
+        // jmp short 0x10
+        vec[..2].copy_from_slice(&[0xeb, 0x0e]);
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = JmpHook::new_following_thunks(BytePatcher::new());
+        let guard = unsafe { hook.hook(ptr, 0x1234 as _) }.unwrap();
+
+        // the thunk is untouched and its target is hooked
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 2) }, [0xeb, 0x0e]);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr.add(0x10), 14) },
+            jmp_abs(0x1234)
+        );
+        guard.unhook();
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that hooking a location to itself is rejected
+    fn test_degenerate_jump() {
+        let vec = vec![0x90u8; 16];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = JmpHook::new(BytePatcher::new());
+        for destination in [ptr, unsafe { ptr.add(13) }] {
+            let result = unsafe { hook.hook(ptr, destination) };
+            assert!(
+                matches!(result, Err(JmpHookError::DegenerateJump(target)) if target == destination as _)
+            );
+        }
+
+        // nothing should have been written
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 16]);
+
+        // jumping just past the patch is fine
+        let guard = unsafe { hook.hook(ptr, ptr.add(14)) }.unwrap();
+        guard.unhook();
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    /// Encodes a `jmp rel32`, which is shorter but can only reach nearby destinations
+    struct Rel32Encoder;
+    impl JumpEncoder for Rel32Encoder {
+        fn encode(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+            let displacement = (destination as isize - (source as isize + 5)) as i32;
+            let mut jump = vec![0xe9];
+            jump.extend(displacement.to_le_bytes());
+            jump
+        }
+    }
+
+    #[test]
+    /// Tests that a custom encoder decides the bytes written and the degenerate range
+    fn test_encoder() {
+        let vec = vec![0x90u8; 16];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = JmpHook::with_encoder(BytePatcher::new(), Rel32Encoder);
+
+        // the degenerate range is only as long as the encoded jump
+        let result = unsafe { hook.hook(ptr, ptr.add(4)) };
+        assert!(matches!(result, Err(JmpHookError::DegenerateJump(_))));
+
+        let guard = unsafe { hook.hook(ptr, ptr.add(0x105)) }.unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, 6) },
+            [0xe9, 0x00, 0x01, 0x00, 0x00, 0x90]
+        );
+        assert_eq!(guard.len(), 5);
+        guard.unhook();
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that the near encoder only falls back to an absolute jump when the destination is out of range
+    fn test_near_encoder() {
+        let vec = vec![0x90u8; 16];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = JmpHook::with_encoder(BytePatcher::new(), NearJumpEncoder);
+        let guard = unsafe { hook.hook(ptr, ptr.add(0x105)) }.unwrap();
+        assert_eq!(guard.len(), 5);
+        guard.unhook();
+
+        // the heap is never within 2GiB of the first pages of the address space
+        let guard = unsafe { hook.hook(ptr, 0x1234 as _) }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, jmp_abs(0x1234));
+        guard.unhook();
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
 }
-unsafe impl<G: PatchGuard> HookGuard for JmpHookGuard<G> {}
@@ -2,7 +2,19 @@
 //!
 //! This module covers hooks, which redirect execution from one location to another
 
+#[cfg(feature = "trampolines")]
+pub mod builder;
+#[cfg(feature = "trampolines")]
+pub mod detour;
 pub mod jmphook;
+pub mod owned;
+pub mod persistent;
+pub mod registry;
+pub mod replace;
+pub mod tagged;
+pub mod timed;
+#[cfg(feature = "trampolines")]
+pub mod wrapped;
 
 /// Trait for hooks
 ///
@@ -29,6 +41,25 @@ pub unsafe trait Hook {
         source: *const u8,
         destination: *const u8,
     ) -> Result<Self::Guard<'_>, Self::Error>;
+
+    /// Gets the bytes that [`Hook::hook`] would write to `source` to redirect it to `destination`, without hooking anything
+    ///
+    /// Hooks that can't know their patch ahead of time (e.g. ones that jump to memory allocated while hooking) return an empty patch.
+    fn preview(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        let _ = (source, destination);
+        Vec::new()
+    }
+
+    /// Gets the location that [`Hook::hook`] would patch to hook `source`
+    ///
+    /// This is `source` itself unless the hook patches somewhere else, e.g. [`JmpHook::following_thunks`](jmphook::JmpHook::following_thunks) patches the function a thunk jumps to.
+    ///
+    /// # Safety
+    ///
+    /// `source` must be a valid pointer, since it may be read to resolve the location
+    unsafe fn resolve(&self, source: *const u8) -> *const u8 {
+        source
+    }
 }
 
 /// Guard for a currently active hook
@@ -37,6 +68,14 @@ pub unsafe trait Hook {
 ///
 /// Must ensure that the guard fully unhooks whether dropped or unhooked via `unhook`
 pub unsafe trait HookGuard: Sized {
+    /// Gets the hooked location
+    fn location(&self) -> *const u8;
+    /// Gets the number of bytes overwritten at the hooked location
+    fn len(&self) -> usize;
+    /// Returns `true` if the hook didn't overwrite anything
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     /// Manually unhooks the hook rather than letting the guard go out of scope
     fn unhook(self) {
         // most guards will implement all functionality in [`Drop::drop`]
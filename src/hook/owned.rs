@@ -0,0 +1,142 @@
+//! # Owned Hook
+//!
+//! [`Hook::hook`] returns a guard that borrows the hook, which makes guards awkward to store for a long time (e.g. in a registry keyed by address).
+//! [`hook_owned`] instead shares ownership of the hook with the guard, so the guard carries no borrow and can be stored anywhere.
+
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::{Hook, HookGuard};
+
+/// Source of unique [`HookHandle`]s
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Stable identifier for a hook installed by [`hook_owned`]
+///
+/// Handles are unique for the life of the process, even if the same location is hooked again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HookHandle(u64);
+
+/// Hooks `source` with a shared `hook`, redirecting it to `destination`
+///
+/// The returned guard keeps the hook alive, so it can outlive any borrow of `hook`.
+///
+/// # Safety
+///
+/// See [`Hook::hook`]
+pub unsafe fn hook_owned<H: Hook + 'static>(
+    hook: Arc<H>,
+    source: *const u8,
+    destination: *const u8,
+) -> Result<OwnedHookGuard<H>, H::Error> {
+    // Safety: the hook lives in the `Arc` at a stable address, and the guard is always dropped before the `Arc`
+    let borrowed: &'static H = &*Arc::as_ptr(&hook);
+    let guard = borrowed.hook(source, destination)?;
+
+    Ok(OwnedHookGuard {
+        guard: ManuallyDrop::new(guard),
+        hook,
+        handle: HookHandle(NEXT_HANDLE.fetch_add(1, Ordering::Relaxed)),
+        source,
+        destination,
+    })
+}
+
+/// Guard for a hook installed by [`hook_owned`]
+///
+/// Unhooks when dropped, then releases its share of the hook.
+pub struct OwnedHookGuard<H: Hook + 'static> {
+    /// Guard for the installed hook. Borrows `hook`, so it must be dropped first
+    guard: ManuallyDrop<H::Guard<'static>>,
+    /// Hook that `guard` borrows from
+    hook: Arc<H>,
+    /// Stable identifier for the hook
+    handle: HookHandle,
+    /// Location that was hooked
+    source: *const u8,
+    /// Location that execution is redirected to
+    destination: *const u8,
+}
+impl<H: Hook + 'static> OwnedHookGuard<H> {
+    /// Gets the stable identifier for this hook
+    pub fn handle(&self) -> HookHandle {
+        self.handle
+    }
+    /// Gets the hooked location
+    pub fn source(&self) -> *const u8 {
+        self.source
+    }
+    /// Gets the location that execution is redirected to
+    pub fn destination(&self) -> *const u8 {
+        self.destination
+    }
+    /// Gets the hook that installed this guard
+    pub fn hook(&self) -> &Arc<H> {
+        &self.hook
+    }
+}
+unsafe impl<H: Hook + 'static> HookGuard for OwnedHookGuard<H> {
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+}
+impl<H: Hook + 'static> Drop for OwnedHookGuard<H> {
+    fn drop(&mut self) {
+        // Safety: the guard is never used again, and is dropped while `hook` is still alive
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::slice;
+    use std::sync::Arc;
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::owned::hook_owned;
+    use crate::patcher::byte::BytePatcher;
+
+    #[test]
+    /// Tests storing owned guards in a registry after the hook has been dropped
+    fn test_registry() {
+        let vec = vec![0x90u8; 32];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let mut registry = HashMap::new();
+        {
+            let hook = Arc::new(JmpHook::new(BytePatcher::new()));
+            for offset in [0, 16] {
+                let source = unsafe { ptr.add(offset) };
+                let guard = unsafe { hook_owned(hook.clone(), source, 0x1234 as _) }.unwrap();
+                registry.insert(source as usize, guard);
+            }
+        }
+
+        // handles are unique
+        assert_ne!(
+            registry[&(ptr as usize)].handle(),
+            registry[&(ptr as usize + 16)].handle()
+        );
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, jmp_abs(0x1234));
+
+        // removing a guard from the registry unhooks it
+        registry.remove(&(ptr as usize));
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 16) }, [0x90; 16]);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr.add(16), 14) },
+            jmp_abs(0x1234)
+        );
+
+        registry.clear();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 32]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
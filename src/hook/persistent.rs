@@ -0,0 +1,204 @@
+//! # Persistent Hook
+//!
+//! Some targets rewrite their own code after it's been hooked (e.g. self-patching or integrity-restoring code), silently removing the hook.
+//! [`PersistentHook`] watches the hooked bytes from a background thread and writes them again whenever they've been overwritten.
+//!
+//! ## Cost
+//!
+//! Every hook gets its own watchdog thread, which wakes up once per poll interval to compare the hooked bytes against the patch (usually 14 bytes).
+//! The comparison itself is negligible, so the cost is dominated by the thread wake-ups: at the default interval of 100ms that's 10 wake-ups per second per hook.
+//! Shorter intervals reinstall the hook sooner, at the cost of more wake-ups.
+
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use region::Protection;
+
+use super::{Hook, HookGuard};
+
+/// Default time between checks of the hooked bytes
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hook that reinstalls itself if the hooked bytes are overwritten
+///
+/// Wraps another hook, which performs the initial hook and the final unhook.
+pub struct PersistentHook<H> {
+    /// Hook used to install and remove the hook
+    hook: H,
+    /// Time between checks of the hooked bytes
+    interval: Duration,
+}
+impl<H: Hook> PersistentHook<H> {
+    /// Creates a new persistent hook that checks the hooked bytes every [`DEFAULT_POLL_INTERVAL`]
+    pub fn new(hook: H) -> Self {
+        Self::with_interval(hook, DEFAULT_POLL_INTERVAL)
+    }
+    /// Creates a new persistent hook that checks the hooked bytes every `interval`
+    pub fn with_interval(hook: H, interval: Duration) -> Self {
+        Self { hook, interval }
+    }
+}
+unsafe impl<H: Hook> Hook for PersistentHook<H> {
+    type Error = H::Error;
+    type Guard<'a>
+        = PersistentHookGuard<H::Guard<'a>>
+    where
+        Self: 'a;
+
+    unsafe fn hook(
+        &self,
+        source: *const u8,
+        destination: *const u8,
+    ) -> Result<Self::Guard<'_>, Self::Error> {
+        let guard = self.hook.hook(source, destination)?;
+
+        // Remember exactly what the hook wrote so it can be written again
+        let location = guard.location() as usize;
+        let patch = slice::from_raw_parts(guard.location(), guard.len()).to_vec();
+
+        let reinstalls = Arc::new(AtomicUsize::new(0));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let interval = self.interval;
+        let thread = {
+            let reinstalls = reinstalls.clone();
+            thread::spawn(move || {
+                // Runs until the guard drops its sender
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // Safety: the guard keeps the hook (and therefore `location`) valid until this thread is joined
+                    if unsafe { reinstall(location as *mut u8, &patch) } {
+                        reinstalls.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        };
+
+        Ok(PersistentHookGuard {
+            guard: Some(guard),
+            stop: Some(stop),
+            thread: Some(thread),
+            reinstalls,
+        })
+    }
+
+    fn preview(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        self.hook.preview(source, destination)
+    }
+
+    unsafe fn resolve(&self, source: *const u8) -> *const u8 {
+        self.hook.resolve(source)
+    }
+}
+
+/// Writes `patch` to `location` again if it's been overwritten, returning whether it was rewritten
+///
+/// # Safety
+///
+/// `location` must be valid for the length of `patch`
+unsafe fn reinstall(location: *mut u8, patch: &[u8]) -> bool {
+    // The target can write to this memory at any time, so don't let the compiler assume anything about it
+    let intact = (0..patch.len()).all(|i| ptr::read_volatile(location.add(i)) == patch[i]);
+    if intact {
+        return false;
+    }
+
+    match region::protect_with_handle(location, patch.len(), Protection::READ_WRITE_EXECUTE) {
+        Ok(_handle) => {
+            ptr::copy_nonoverlapping(patch.as_ptr(), location, patch.len());
+            true
+        }
+        Err(e) => {
+            log::warn!("unable to reinstall hook at {location:?}: {e}");
+            false
+        }
+    }
+}
+
+/// Guard for [`PersistentHook`]
+///
+/// Stops the watchdog thread, then unhooks.
+pub struct PersistentHookGuard<G: HookGuard> {
+    /// Guard for the wrapped hook. Only `None` while dropping
+    guard: Option<G>,
+    /// Sender for stopping the watchdog. Dropping it stops the thread
+    stop: Option<Sender<()>>,
+    /// Watchdog thread
+    thread: Option<JoinHandle<()>>,
+    /// Number of times the hook has been reinstalled
+    reinstalls: Arc<AtomicUsize>,
+}
+impl<G: HookGuard> PersistentHookGuard<G> {
+    /// Gets the number of times the hook has been reinstalled after being overwritten
+    pub fn reinstalls(&self) -> usize {
+        self.reinstalls.load(Ordering::Relaxed)
+    }
+}
+unsafe impl<G: HookGuard> HookGuard for PersistentHookGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.guard.as_ref().unwrap().location()
+    }
+    fn len(&self) -> usize {
+        self.guard.as_ref().unwrap().len()
+    }
+}
+impl<G: HookGuard> Drop for PersistentHookGuard<G> {
+    fn drop(&mut self) {
+        // The watchdog must be stopped first, otherwise it would reinstall the hook right after it's removed
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Some(guard) = self.guard.take() {
+            guard.unhook();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::persistent::PersistentHook;
+    use crate::hook::{Hook, HookGuard};
+    use crate::patcher::byte::BytePatcher;
+
+    #[test]
+    /// Tests that an overwritten hook is reinstalled, and that unhooking stops the watchdog
+    fn test_reinstall() {
+        let vec = vec![0x90u8; 16];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = PersistentHook::with_interval(
+            JmpHook::new(BytePatcher::new()),
+            Duration::from_millis(1),
+        );
+        let guard = unsafe { hook.hook(ptr, 0x1234 as _) }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, jmp_abs(0x1234));
+
+        // the target restores its own code
+        unsafe { ptr.write_bytes(0x90, 14) };
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while guard.reinstalls() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(guard.reinstalls(), 1);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, jmp_abs(0x1234));
+
+        // unhooking must not be undone by the watchdog
+        guard.unhook();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 16]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
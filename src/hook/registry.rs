@@ -0,0 +1,242 @@
+//! # Hook Registry
+//!
+//! Guards restore the bytes that were there when they hooked, so two hooks on the same location undo each other: whichever guard is dropped first also wipes out the other hook, and the other guard then writes the first hook back.
+//! This happens easily when independent components (e.g. plugins) hook the same function without knowing about each other.
+//!
+//! [`RegisteredHook`] records every hooked range in the process-wide [`HookRegistry`] and refuses to hook a range that overlaps one that's already hooked, so conflicting hooks fail up front instead of fighting on drop.
+//!
+//! The registry is a `static` in this crate, so it's only shared by components that use the same copy of the crate.
+//! Components that each statically link their own copy (e.g. plugins built as separate `cdylib`s) each get their own registry; they need to link the crate dynamically to be coordinated.
+
+use std::collections::BTreeMap;
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+use super::{Hook, HookGuard};
+
+lazy_static! {
+    static ref REGISTRY: HookRegistry = HookRegistry {
+        hooks: Mutex::new(BTreeMap::new()),
+    };
+}
+
+#[derive(Debug, Error)]
+/// Error types for [`RegisteredHook`]
+pub enum RegistryError<E> {
+    /// The range that would be hooked overlaps a hook that's already installed (start of that hook included)
+    #[error("Location is already hooked (hook at {0:?})")]
+    AlreadyHooked(*const ()),
+    /// Error from the underlying hook
+    #[error("hook error")]
+    HookError(E),
+}
+
+/// Process-wide record of the ranges hooked through [`RegisteredHook`]
+pub struct HookRegistry {
+    /// Hooked ranges, keyed by their start
+    hooks: Mutex<BTreeMap<usize, usize>>,
+}
+impl HookRegistry {
+    /// Gets the registry for this process
+    pub fn global() -> &'static Self {
+        &REGISTRY
+    }
+    /// Gets the range of the registered hook that covers `location`, if any
+    pub fn hooked_range(&self, location: *const u8) -> Option<Range<usize>> {
+        let location = location as usize;
+        let hooks = self.hooks.lock().unwrap();
+        Self::overlapping(&hooks, &(location..location + 1))
+    }
+    /// Gets the first registered range that overlaps `range`
+    fn overlapping(hooks: &BTreeMap<usize, usize>, range: &Range<usize>) -> Option<Range<usize>> {
+        // Only the last hook starting before the end of `range` can reach into it, since hooks never overlap
+        hooks
+            .range(..range.end)
+            .next_back()
+            .map(|(&start, &end)| start..end)
+            .filter(|hook| hook.end > range.start)
+    }
+}
+
+/// Hook that registers what it hooks in the [`HookRegistry`], and fails rather than hooking over another registered hook
+///
+/// Only hooks installed through a [`RegisteredHook`] are known to the registry.
+pub struct RegisteredHook<H> {
+    /// Hook used to install the hook
+    hook: H,
+}
+impl<H: Hook> RegisteredHook<H> {
+    /// Creates a new registered hook wrapping `hook`
+    pub fn new(hook: H) -> Self {
+        Self { hook }
+    }
+}
+unsafe impl<H: Hook> Hook for RegisteredHook<H> {
+    type Error = RegistryError<H::Error>;
+    type Guard<'a>
+        = RegisteredHookGuard<H::Guard<'a>>
+    where
+        Self: 'a;
+
+    unsafe fn hook(
+        &self,
+        source: *const u8,
+        destination: *const u8,
+    ) -> Result<Self::Guard<'_>, Self::Error> {
+        // Held while hooking so nothing else can claim the range in between
+        let mut hooks = REGISTRY.hooks.lock().unwrap();
+
+        // Checked where the hook will actually patch, which isn't always `source` (e.g. when following thunks).
+        // Hooks that can't preview still overwrite at least the first byte
+        let location = self.hook.resolve(source);
+        let start = location as usize;
+        let len = self.hook.preview(location, destination).len().max(1);
+        if let Some(existing) = HookRegistry::overlapping(&hooks, &(start..start + len)) {
+            return Err(RegistryError::AlreadyHooked(existing.start as _));
+        }
+
+        let guard = self
+            .hook
+            .hook(source, destination)
+            .map_err(RegistryError::HookError)?;
+        // Registered as what was actually patched
+        let start = guard.location() as usize;
+        hooks.insert(start, start + guard.len().max(1));
+
+        Ok(RegisteredHookGuard {
+            guard: ManuallyDrop::new(guard),
+            start,
+        })
+    }
+
+    fn preview(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        self.hook.preview(source, destination)
+    }
+
+    unsafe fn resolve(&self, source: *const u8) -> *const u8 {
+        self.hook.resolve(source)
+    }
+}
+
+/// Guard for registered hooks
+///
+/// The hook is removed before its range is unregistered, so another hook can't be installed over it while it's still being removed.
+pub struct RegisteredHookGuard<G: HookGuard> {
+    /// Guard for the installed hook. Dropped manually, while the registry is locked
+    guard: ManuallyDrop<G>,
+    /// Start of the registered range
+    start: usize,
+}
+impl<G: HookGuard> RegisteredHookGuard<G> {
+    /// Get the underlying hook guard in case info is needed
+    pub fn hook(&self) -> &G {
+        &self.guard
+    }
+}
+unsafe impl<G: HookGuard> HookGuard for RegisteredHookGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+}
+impl<G: HookGuard> Drop for RegisteredHookGuard<G> {
+    fn drop(&mut self) {
+        // Hold the lock while unhooking, for the same reason as while hooking
+        let mut hooks = REGISTRY
+            .hooks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Safety: the guard is never used again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        hooks.remove(&self.start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::registry::{HookRegistry, RegisteredHook, RegistryError};
+    use crate::hook::{Hook, HookGuard};
+    use crate::patcher::byte::BytePatcher;
+
+    #[test]
+    /// Tests that overlapping hooks are refused until the first one is removed
+    fn test_conflict() {
+        let vec = vec![0x90u8; 32];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+        let registry = HookRegistry::global();
+
+        // two independent hooks, as if installed by two plugins
+        let first = RegisteredHook::new(JmpHook::new(BytePatcher::new()));
+        let second = RegisteredHook::new(JmpHook::new(BytePatcher::new()));
+
+        let guard = unsafe { first.hook(ptr, 0x1234 as _) }.unwrap();
+        assert_eq!(
+            registry.hooked_range(unsafe { ptr.add(13) }),
+            Some(ptr as usize..ptr as usize + 14)
+        );
+        assert_eq!(registry.hooked_range(unsafe { ptr.add(14) }), None);
+
+        for source in [ptr, unsafe { ptr.add(13) }, ptr.wrapping_sub(13)] {
+            let result = unsafe { second.hook(source, 0x5678 as _) };
+            assert!(matches!(result, Err(RegistryError::AlreadyHooked(hook)) if hook == ptr as _));
+        }
+
+        // a range right after the first hook doesn't overlap it
+        let adjacent = unsafe { second.hook(ptr.add(14), 0x5678 as _) }.unwrap();
+        drop(adjacent);
+
+        drop(guard);
+        assert_eq!(registry.hooked_range(ptr), None);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 32]);
+        let guard = unsafe { second.hook(ptr, 0x5678 as _) }.unwrap();
+        drop(guard);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that hooks are registered where they patch rather than where they were asked to hook
+    fn test_followed_thunk() {
+        let mut vec = vec![0x90u8; 64];
+        // jmp rel8 to offset 32
+        vec[..2].copy_from_slice(&[0xeb, 0x1e]);
+        let (ptr, size, capacity) = vec.into_raw_parts();
+        let registry = HookRegistry::global();
+        let target = unsafe { ptr.add(32) };
+
+        let following = RegisteredHook::new(JmpHook::new_following_thunks(BytePatcher::new()));
+        let guard = unsafe { following.hook(ptr, 0x1234 as _) }.unwrap();
+        assert_eq!(guard.location(), target as *const u8);
+        assert_eq!(registry.hooked_range(ptr), None);
+        assert_eq!(
+            registry.hooked_range(target),
+            Some(target as usize..target as usize + 14)
+        );
+
+        // hooking the thunk's target directly collides with the followed hook
+        let direct = RegisteredHook::new(JmpHook::new(BytePatcher::new()));
+        let result = unsafe { direct.hook(target, 0x5678 as _) };
+        assert!(matches!(result, Err(RegistryError::AlreadyHooked(hook)) if hook == target as _));
+        drop(guard);
+
+        // and the other way around, the followed hook is checked where it'll patch
+        let guard = unsafe { direct.hook(target, 0x5678 as _) }.unwrap();
+        let result = unsafe { following.hook(ptr, 0x1234 as _) };
+        assert!(matches!(result, Err(RegistryError::AlreadyHooked(hook)) if hook == target as _));
+        drop(guard);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
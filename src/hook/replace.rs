@@ -0,0 +1,109 @@
+//! # Replace Hook
+//!
+//! This hook type is for detours that fully replace the hooked function and never call the original.
+//!
+//! It writes the same jump as [`JmpHook`] and keeps the bytes it overwrote so the function can be restored, but doesn't relocate them into a trampoline.
+//! Nothing is allocated, which makes it cheaper than a trampoline-based hook. In exchange, calling the original function while hooked is unsupported: the overwritten instructions only exist as data.
+
+use std::slice;
+
+use crate::patcher::{PatchGuard, Patcher};
+
+use super::jmphook::{JmpHook, JmpHookError, JmpHookGuard};
+use super::{Hook, HookGuard};
+
+/// Hook that redirects a function without keeping a callable original
+pub struct ReplaceHook<P> {
+    /// Hook that writes the jump
+    hook: JmpHook<P>,
+}
+impl<P: Patcher> ReplaceHook<P> {
+    /// Creates a new replace hook
+    pub fn new(patcher: P) -> Self {
+        Self {
+            hook: JmpHook::new(patcher),
+        }
+    }
+}
+unsafe impl<P: Patcher> Hook for ReplaceHook<P> {
+    type Error = JmpHookError<P::Error>;
+    type Guard<'a>
+        = ReplaceHookGuard<P::Guard<'a>>
+    where
+        Self: 'a;
+
+    unsafe fn hook(
+        &self,
+        source: *const u8,
+        destination: *const u8,
+    ) -> Result<Self::Guard<'_>, Self::Error> {
+        let len = self.hook.preview(source, destination).len();
+        // Safety: the caller must ensure that `source` is valid for the length of the jump
+        let overwritten = slice::from_raw_parts(source, len).to_vec();
+
+        let guard = self.hook.hook(source, destination)?;
+        Ok(ReplaceHookGuard { guard, overwritten })
+    }
+
+    fn preview(&self, source: *const u8, destination: *const u8) -> Vec<u8> {
+        self.hook.preview(source, destination)
+    }
+}
+
+/// Guard for replace hooks
+///
+/// Dropping the guard writes the overwritten bytes back. There's deliberately no way to get a callable original from it.
+pub struct ReplaceHookGuard<G: PatchGuard> {
+    /// Guard for the jump
+    guard: JmpHookGuard<G>,
+    /// Bytes at the hooked location before the jump was written
+    overwritten: Vec<u8>,
+}
+impl<G: PatchGuard> ReplaceHookGuard<G> {
+    /// Gets the bytes that were overwritten by the jump, and will be restored when unhooking
+    ///
+    /// These are a copy of the original instructions for inspection; they aren't executable.
+    pub fn overwritten(&self) -> &[u8] {
+        &self.overwritten
+    }
+}
+unsafe impl<G: PatchGuard> HookGuard for ReplaceHookGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::replace::ReplaceHook;
+    use crate::hook::{Hook, HookGuard};
+    use crate::patcher::byte::BytePatcher;
+
+    #[test]
+    /// Tests that the hook keeps the overwritten bytes and restores them when dropped
+    fn test_replace() {
+        let vec = (0..16u8).collect::<Vec<_>>();
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = ReplaceHook::new(BytePatcher::new());
+        let guard = unsafe { hook.hook(ptr, 0x1234 as _) }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, jmp_abs(0x1234));
+        assert_eq!(guard.overwritten(), (0..14).collect::<Vec<_>>());
+        assert_eq!(guard.len(), 14);
+
+        drop(guard);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            (0..16).collect::<Vec<_>>()
+        );
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
@@ -0,0 +1,223 @@
+//! # Tagged Hooks
+//!
+//! Components that install many hooks (e.g. plugins) usually manage them in groups: everything belonging to one feature is turned off together, or everything is removed when the component unloads.
+//! [`TaggedHooks`] is a tagging layer over the [`HookRegistry`]: it stores [owned guards](super::owned::OwnedHookGuard) of [`RegisteredHook`]s under a user-chosen tag (a string, an enum, ...)
+//! so whole groups can be disabled, re-enabled or removed at once.
+//!
+//! Since every tagged hook is registered, re-enabling a group fails with [`RegistryError::AlreadyHooked`] rather than hooking over something that claimed the location while the group was disabled,
+//! and [`TaggedHooks::tag_of`] can look up which group hooked an address.
+
+use std::sync::Arc;
+
+use super::owned::{hook_owned, OwnedHookGuard};
+use super::registry::{HookRegistry, RegisteredHook, RegistryError};
+use super::{Hook, HookGuard};
+
+/// State of a single tagged hook
+enum TaggedState<H: Hook + 'static> {
+    /// Hook is installed
+    Enabled(OwnedHookGuard<RegisteredHook<H>>),
+    /// Hook was removed, but can be installed again
+    Disabled {
+        /// Hook that installed the guard
+        hook: Arc<RegisteredHook<H>>,
+        /// Location that was hooked
+        source: *const u8,
+        /// Location that execution was redirected to
+        destination: *const u8,
+    },
+}
+
+/// Hook stored under a tag
+struct TaggedHook<T, H: Hook + 'static> {
+    /// Tag the hook was registered with
+    tag: T,
+    /// Whether the hook is currently installed
+    state: TaggedState<H>,
+}
+
+/// Collection of hooks grouped by tag
+///
+/// Hooks are kept in registration order; dropping the collection unhooks everything that's still enabled.
+pub struct TaggedHooks<T, H: Hook + 'static> {
+    /// Registered hooks
+    hooks: Vec<TaggedHook<T, H>>,
+}
+impl<T: PartialEq, H: Hook + 'static> TaggedHooks<T, H> {
+    /// Creates an empty collection
+    pub fn new() -> Self {
+        Self { hooks: Vec::new() }
+    }
+    /// Stores `guard` under `tag`
+    pub fn register_tagged(&mut self, tag: T, guard: OwnedHookGuard<RegisteredHook<H>>) {
+        self.hooks.push(TaggedHook {
+            tag,
+            state: TaggedState::Enabled(guard),
+        });
+    }
+    /// Unhooks and forgets every hook registered under `tag`, returning how many were removed
+    ///
+    /// Disabled hooks are forgotten as well.
+    pub fn remove_by_tag(&mut self, tag: &T) -> usize {
+        let before = self.hooks.len();
+        self.hooks.retain(|hook| hook.tag != *tag);
+        before - self.hooks.len()
+    }
+    /// Installs (`enabled`) or removes every hook registered under `tag`, returning how many changed state
+    ///
+    /// Disabled hooks stay in the collection and are hooked again with the same source and destination when re-enabled.
+    /// If re-hooking fails (e.g. with [`RegistryError::AlreadyHooked`] if another hook was registered over a disabled one), the error is returned and the remaining hooks under `tag` are left disabled.
+    ///
+    /// # Safety
+    ///
+    /// Re-enabling hooks has the same requirements as [`Hook::hook`]: the source and destination of every hook under `tag` must still be valid
+    pub unsafe fn set_enabled_by_tag(
+        &mut self,
+        tag: &T,
+        enabled: bool,
+    ) -> Result<usize, RegistryError<H::Error>> {
+        let mut changed = 0;
+        for tagged in self.hooks.iter_mut().filter(|hook| hook.tag == *tag) {
+            let state = match (&tagged.state, enabled) {
+                (TaggedState::Enabled(guard), false) => TaggedState::Disabled {
+                    hook: guard.hook().clone(),
+                    source: guard.source(),
+                    destination: guard.destination(),
+                },
+                (
+                    TaggedState::Disabled {
+                        hook,
+                        source,
+                        destination,
+                    },
+                    true,
+                ) => TaggedState::Enabled(hook_owned(hook.clone(), *source, *destination)?),
+                _ => continue,
+            };
+            // Replacing an enabled state drops (and therefore unhooks) its guard
+            tagged.state = state;
+            changed += 1;
+        }
+        Ok(changed)
+    }
+    /// Gets the number of hooks registered under `tag`, enabled or not
+    pub fn count_by_tag(&self, tag: &T) -> usize {
+        self.hooks.iter().filter(|hook| hook.tag == *tag).count()
+    }
+    /// Gets whether any hook registered under `tag` is currently installed
+    pub fn is_enabled_by_tag(&self, tag: &T) -> bool {
+        self.hooks
+            .iter()
+            .any(|hook| hook.tag == *tag && matches!(hook.state, TaggedState::Enabled(_)))
+    }
+    /// Gets the tag of the enabled hook in this collection that covers `location`, if any
+    ///
+    /// The covering hook is looked up in the [`HookRegistry`], so `location` can be anywhere in the hooked range rather than only its start.
+    pub fn tag_of(&self, location: *const u8) -> Option<&T> {
+        let range = HookRegistry::global().hooked_range(location)?;
+        self.hooks
+            .iter()
+            .find(|hook| match &hook.state {
+                TaggedState::Enabled(guard) => guard.location() as usize == range.start,
+                TaggedState::Disabled { .. } => false,
+            })
+            .map(|hook| &hook.tag)
+    }
+}
+impl<T: PartialEq, H: Hook + 'static> Default for TaggedHooks<T, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+    use std::sync::Arc;
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::owned::hook_owned;
+    use crate::hook::registry::{HookRegistry, RegisteredHook, RegistryError};
+    use crate::hook::tagged::TaggedHooks;
+    use crate::hook::Hook;
+    use crate::patcher::byte::BytePatcher;
+
+    #[derive(Debug, PartialEq)]
+    enum Feature {
+        Render,
+        Input,
+    }
+
+    #[test]
+    /// Tests disabling, re-enabling and removing hooks by tag
+    fn test_tags() {
+        let vec = vec![0x90u8; 48];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = Arc::new(RegisteredHook::new(JmpHook::new(BytePatcher::new())));
+        let mut hooks = TaggedHooks::new();
+        for (offset, tag) in [
+            (0, Feature::Render),
+            (16, Feature::Render),
+            (32, Feature::Input),
+        ] {
+            let guard = unsafe { hook_owned(hook.clone(), ptr.add(offset), 0x1234 as _) }.unwrap();
+            hooks.register_tagged(tag, guard);
+        }
+        assert_eq!(hooks.count_by_tag(&Feature::Render), 2);
+        assert_eq!(hooks.tag_of(unsafe { ptr.add(20) }), Some(&Feature::Render));
+        assert_eq!(hooks.tag_of(unsafe { ptr.add(40) }), Some(&Feature::Input));
+
+        // disabling a group only unhooks that group
+        assert_eq!(
+            unsafe { hooks.set_enabled_by_tag(&Feature::Render, false) }.unwrap(),
+            2
+        );
+        assert!(!hooks.is_enabled_by_tag(&Feature::Render));
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 32) }, [0x90; 32]);
+        assert_eq!(HookRegistry::global().hooked_range(ptr), None);
+        assert_eq!(hooks.tag_of(ptr), None);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr.add(32), 14) },
+            jmp_abs(0x1234)
+        );
+
+        // re-enabling fails while something else is registered over a disabled hook
+        let other = RegisteredHook::new(JmpHook::new(BytePatcher::new()));
+        let conflict = unsafe { other.hook(ptr.add(16), 0x5678 as _) }.unwrap();
+        assert!(matches!(
+            unsafe { hooks.set_enabled_by_tag(&Feature::Render, true) },
+            Err(RegistryError::AlreadyHooked(_))
+        ));
+        drop(conflict);
+
+        // re-enabling hooks the same locations again (the first one was re-enabled before the conflict)
+        assert_eq!(
+            unsafe { hooks.set_enabled_by_tag(&Feature::Render, true) }.unwrap(),
+            1
+        );
+        assert_eq!(
+            unsafe { hooks.set_enabled_by_tag(&Feature::Render, true) }.unwrap(),
+            0
+        );
+        for offset in [0, 16] {
+            assert_eq!(
+                unsafe { slice::from_raw_parts(ptr.add(offset), 14) },
+                jmp_abs(0x1234)
+            );
+        }
+
+        // removing a group unhooks it for good
+        assert_eq!(hooks.remove_by_tag(&Feature::Render), 2);
+        assert_eq!(hooks.count_by_tag(&Feature::Render), 0);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 32) }, [0x90; 32]);
+        assert!(hooks.is_enabled_by_tag(&Feature::Input));
+
+        drop(hooks);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 48]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
@@ -0,0 +1,167 @@
+//! # Timed Hook
+//!
+//! Temporary instrumentation (e.g. "profile this function for 30 seconds") needs the hook removed once the window is over, even if the host forgets about it.
+//! [`TimedHook`] installs a hook and unhooks it from a timer thread after a fixed duration.
+//!
+//! ## Threads
+//!
+//! Every timed hook gets its own timer thread, which sleeps until the hook expires or is cancelled, drops the hook's guard and exits.
+//! The guard is dropped *on the timer thread*, so the hook must be [`Send`] and [`Sync`], and its guards must be safe to drop on a thread other than the one that hooked.
+//! Hosts that already have a scheduler can do the same without the extra thread by storing the guard from [`hook_owned`] and dropping it from a scheduled task.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::owned::{hook_owned, OwnedHookGuard};
+use super::Hook;
+
+/// Hook that removes itself after a fixed duration
+pub struct TimedHook<H> {
+    /// Hook used to install and remove the hook
+    hook: Arc<H>,
+    /// Time until each hook is removed
+    duration: Duration,
+}
+impl<H: Hook + Send + Sync + 'static> TimedHook<H> {
+    /// Creates a new timed hook whose hooks are removed `duration` after they're installed
+    pub fn new(hook: H, duration: Duration) -> Self {
+        Self::from_arc(Arc::new(hook), duration)
+    }
+    /// Creates a new timed hook from a hook that's shared with other code
+    pub fn from_arc(hook: Arc<H>, duration: Duration) -> Self {
+        Self { hook, duration }
+    }
+    /// Hooks `source`, redirecting it to `destination` until the duration expires or the returned guard cancels it
+    ///
+    /// # Safety
+    ///
+    /// See [`Hook::hook`]. In addition, the hook's guards must be safe to drop on the timer thread.
+    pub unsafe fn hook(
+        &self,
+        source: *const u8,
+        destination: *const u8,
+    ) -> Result<TimedHookGuard, H::Error> {
+        let guard = Expiring(hook_owned(self.hook.clone(), source, destination)?);
+
+        let unhooked = Arc::new(AtomicBool::new(false));
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let duration = self.duration;
+        let deadline = Instant::now() + duration;
+        let thread = {
+            let unhooked = unhooked.clone();
+            thread::spawn(move || {
+                let guard = guard.into_inner();
+                // A disconnect means the guard was detached, so the hook stays until the deadline
+                if let Err(RecvTimeoutError::Disconnected) = cancelled.recv_timeout(duration) {
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                }
+                drop(guard);
+                unhooked.store(true, Ordering::Release);
+            })
+        };
+
+        Ok(TimedHookGuard {
+            cancel: Some(cancel),
+            thread: Some(thread),
+            unhooked,
+        })
+    }
+}
+
+/// Owned guard moved to the timer thread
+struct Expiring<H: Hook + 'static>(OwnedHookGuard<H>);
+impl<H: Hook + 'static> Expiring<H> {
+    /// Unwraps the guard. Called inside the timer thread so the closure captures the whole wrapper rather than just the guard
+    fn into_inner(self) -> OwnedHookGuard<H> {
+        self.0
+    }
+}
+// Safety: the hook is `Send` and `Sync`, and the caller of [`TimedHook::hook`] guarantees its guard can be dropped on another thread
+unsafe impl<H: Hook + Send + Sync + 'static> Send for Expiring<H> {}
+
+/// Guard for [`TimedHook`]
+///
+/// Dropping the guard (or [cancelling](TimedHookGuard::cancel)) unhooks early; [`TimedHookGuard::detach`] leaves the hook in place until it expires.
+pub struct TimedHookGuard {
+    /// Sender for cancelling the timer. Only `None` after detaching or while dropping
+    cancel: Option<Sender<()>>,
+    /// Timer thread, which owns the hook's guard
+    thread: Option<JoinHandle<()>>,
+    /// Set once the timer thread has unhooked
+    unhooked: Arc<AtomicBool>,
+}
+impl TimedHookGuard {
+    /// Gets whether the hook is still installed
+    pub fn is_hooked(&self) -> bool {
+        !self.unhooked.load(Ordering::Acquire)
+    }
+    /// Unhooks now instead of waiting for the duration to expire
+    pub fn cancel(self) {
+        drop(self)
+    }
+    /// Lets the hook run until its duration expires without keeping the guard around
+    ///
+    /// The timer thread removes the hook on its own, and there's no way to cancel it early afterwards.
+    pub fn detach(mut self) {
+        // Dropping the sender without sending tells the timer to wait out the duration, and dropping the handle detaches the thread
+        self.cancel.take();
+        self.thread.take();
+    }
+}
+impl Drop for TimedHookGuard {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            // The timer may have already expired and exited, in which case there's nothing to cancel
+            let _ = cancel.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::timed::TimedHook;
+    use crate::patcher::byte::BytePatcher;
+
+    #[test]
+    /// Tests that hooks are removed when they expire, and can be cancelled before that
+    fn test_expiry() {
+        let vec = vec![0x90u8; 32];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        // detached hooks are removed by the timer alone
+        let hook = TimedHook::new(JmpHook::new(BytePatcher::new()), Duration::from_millis(10));
+        let guard = unsafe { hook.hook(ptr, 0x1234 as _) }.unwrap();
+        assert!(guard.is_hooked());
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, jmp_abs(0x1234));
+        guard.detach();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unsafe { slice::from_raw_parts(ptr, 14) } != [0x90; 14] && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 14) }, [0x90; 14]);
+
+        // cancelling doesn't wait for the duration
+        let hook = TimedHook::new(JmpHook::new(BytePatcher::new()), Duration::from_secs(60));
+        let guard = unsafe { hook.hook(ptr.add(16), 0x1234 as _) }.unwrap();
+        let start = Instant::now();
+        guard.cancel();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 32]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
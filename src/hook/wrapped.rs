@@ -0,0 +1,183 @@
+//! # Wrapped Hook
+//!
+//! This hook type standardizes the calling convention of the hooked function with a [`CallWrapper`] before redirecting execution
+//!
+//! Wrappers and hooks can also be combined by hand, in which case [`CompositeGuard`] keeps their guards together and removes them in the right order.
+
+use thiserror::Error;
+
+use crate::wrapper::{CallWrapper, CallWrapperGuard};
+
+use super::{Hook, HookGuard};
+
+/// Errors that can occur while installing a wrapped hook
+#[derive(Debug, Error)]
+pub enum WrappedHookError<W, H> {
+    /// Error while activating the call wrapper
+    #[error("{0}")]
+    WrapperError(W),
+    /// Error while installing the hook
+    #[error("{0}")]
+    HookError(H),
+}
+
+/// Hook that redirects execution through a call wrapper
+///
+/// The wrapper is activated first to produce a standardized entry point for `destination`, then `source` is hooked to that entry point.
+pub struct WrappedHook<W, H> {
+    /// Wrapper that standardizes the calling convention
+    wrapper: W,
+    /// Hook that redirects execution to the wrapper
+    hook: H,
+}
+impl<W: CallWrapper, H: Hook> WrappedHook<W, H> {
+    /// Creates a new wrapped hook
+    pub fn new(wrapper: W, hook: H) -> Self {
+        Self { wrapper, hook }
+    }
+}
+unsafe impl<W: CallWrapper, H: Hook> Hook for WrappedHook<W, H> {
+    type Error = WrappedHookError<W::Error, H::Error>;
+    type Guard<'a>
+        = WrappedHookGuard<W::Guard<'a>, H::Guard<'a>>
+    where
+        Self: 'a;
+
+    unsafe fn hook(
+        &self,
+        source: *const u8,
+        destination: *const u8,
+    ) -> Result<Self::Guard<'_>, Self::Error> {
+        let wrapper = self
+            .wrapper
+            .activate(source, destination)
+            .map_err(WrappedHookError::WrapperError)?;
+        let hook = self
+            .hook
+            .hook(source, wrapper.entry())
+            .map_err(WrappedHookError::HookError)?;
+
+        Ok(CompositeGuard::new(wrapper, hook))
+    }
+
+    unsafe fn resolve(&self, source: *const u8) -> *const u8 {
+        self.hook.resolve(source)
+    }
+}
+
+/// Guard for wrapped hooks
+pub type WrappedHookGuard<WG, HG> = CompositeGuard<WG, HG>;
+
+/// Guard for a hook that sends execution to a call wrapper
+///
+/// The hook is removed before the wrapper is cleaned up, so execution is never sent to a freed wrapper.
+pub struct CompositeGuard<WG: CallWrapperGuard, HG: HookGuard> {
+    /// Guard for the hook. Declared first so it's dropped before the wrapper
+    hook: HG,
+    /// Guard for the call wrapper
+    wrapper: WG,
+}
+impl<WG: CallWrapperGuard, HG: HookGuard> CompositeGuard<WG, HG> {
+    /// Combines the guard of a wrapper with the guard of a hook that redirects to the wrapper's [`entry`](CallWrapperGuard::entry)
+    pub fn new(wrapper: WG, hook: HG) -> Self {
+        Self { hook, wrapper }
+    }
+    /// Get the underlying hook guard in case info is needed
+    pub fn hook(&self) -> &HG {
+        &self.hook
+    }
+    /// Get the underlying call wrapper guard in case info is needed
+    pub fn wrapper(&self) -> &WG {
+        &self.wrapper
+    }
+}
+unsafe impl<WG: CallWrapperGuard, HG: HookGuard> HookGuard for CompositeGuard<WG, HG> {
+    fn location(&self) -> *const u8 {
+        self.hook.location()
+    }
+    fn len(&self) -> usize {
+        self.hook.len()
+    }
+    fn unhook(self) {
+        let Self { hook, wrapper } = self;
+        hook.unhook();
+        CallWrapperGuard::drop(wrapper);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::code::x64::{jmp_abs, needs_endbr64, ENDBR64, JMP_ABS_LEN};
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::wrapped::{CompositeGuard, WrappedHook};
+    use crate::hook::{Hook, HookGuard};
+    use crate::patcher::byte::BytePatcher;
+    use crate::wrapper::cdecl::CDeclWrapper;
+    use crate::wrapper::{CallWrapper, CallWrapperGuard};
+
+    #[test]
+    /// Tests that the source is hooked to the wrapper, and the wrapper jumps to the destination
+    fn test_hook() {
+        let vec = vec![0x90u8; JMP_ABS_LEN];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+        let destination = 0x1234 as *const u8;
+
+        let hook = WrappedHook::new(CDeclWrapper::new(), JmpHook::new(BytePatcher::new()));
+        let guard = unsafe { hook.hook(ptr, destination) }.unwrap();
+
+        // the source jumps to the wrapper
+        let entry = guard.wrapper().entry();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            jmp_abs(entry as _)
+        );
+
+        // the cdecl wrapper jumps straight to the destination
+        let mut stub = if needs_endbr64() {
+            ENDBR64.to_vec()
+        } else {
+            Vec::new()
+        };
+        stub.extend(jmp_abs(destination as _));
+        assert_eq!(unsafe { slice::from_raw_parts(entry, stub.len()) }, stub);
+
+        guard.unhook();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0x90; JMP_ABS_LEN]
+        );
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests combining a wrapper and a hook that were set up separately
+    fn test_composite_guard() {
+        let vec = vec![0x90u8; JMP_ABS_LEN];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let wrapper = CDeclWrapper::new();
+        let hook = JmpHook::new(BytePatcher::new());
+        let wrapper_guard = unsafe { wrapper.activate(ptr, 0x1234 as _) }.unwrap();
+        let hook_guard = unsafe { hook.hook(ptr, wrapper_guard.entry()) }.unwrap();
+
+        let guard = CompositeGuard::new(wrapper_guard, hook_guard);
+        assert_eq!(guard.location(), ptr as *const u8);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            jmp_abs(guard.wrapper().entry() as _)
+        );
+
+        guard.unhook();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0x90; JMP_ABS_LEN]
+        );
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
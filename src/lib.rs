@@ -5,8 +5,17 @@
 #![feature(generic_associated_types)]
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "trampolines")]
 pub mod alloc;
 pub mod code;
+pub mod ffi;
 pub mod hook;
 pub mod patcher;
+pub mod scan;
+#[cfg(all(test, feature = "trampolines"))]
+mod test_util;
+pub mod trampoline;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "trap"))]
+pub mod trap;
+#[cfg(feature = "trampolines")]
 pub mod wrapper;
@@ -0,0 +1,314 @@
+//! This module contains a patcher which applies several patches at once
+//!
+//! Wrapping each patch in its own [`PermissionWrapper`](super::mem::PermissionWrapper) changes memory permissions twice per patch.
+//! [`BatchPatcher`] instead changes permissions once for each contiguous group of pages covered by the patches, applies every patch, and then restores the permissions once.
+//!
+//! [`BatchPatcher::plan`] reports those permission changes without making them, so a host can audit or approve every page a batch will touch before applying it.
+
+use std::mem;
+use std::ops::Range;
+use std::ptr;
+
+use region::Protection;
+
+use super::mem::PermissionError;
+use super::{PatchGuard, PatchStage, Patcher};
+
+/// Permission change that [`BatchPatcher::patch`] would make, as reported by [`BatchPatcher::plan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedChange {
+    /// Pages whose protection would change
+    pub pages: Range<usize>,
+    /// Current protection of the pages, which is restored after patching
+    pub current: Protection,
+    /// Protection of the pages while the patches are written
+    pub during: Protection,
+}
+
+/// Patcher for applying several patches with a single permission change per contiguous page group
+///
+/// # Safety
+///
+/// The same rules as [`PermissionWrapper`](super::mem::PermissionWrapper) apply to every patch in the batch.
+pub struct BatchPatcher<P: Patcher> {
+    /// Underlying patcher used for every patch in the batch
+    patcher: P,
+}
+impl<P> BatchPatcher<P>
+where
+    P: Patcher,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Creates a new BatchPatcher
+    ///
+    /// Note: `patcher` must *not* be wrapped in a [`PermissionWrapper`](super::mem::PermissionWrapper), since the batch patcher changes permissions itself. This is checked at compile time
+    pub fn new(patcher: P) -> Self {
+        const {
+            assert!(
+                matches!(P::STAGE, PatchStage::Write),
+                "BatchPatcher can't wrap a patcher that already changes memory permissions"
+            )
+        };
+        Self { patcher }
+    }
+    /// Gets every permission change that [`BatchPatcher::patch`] would make for `patches`, without changing or writing anything
+    ///
+    /// Pages are reported in address order, split wherever their current protection differs.
+    /// The plan is only accurate until something else changes the pages' protection.
+    pub fn plan(&self, patches: &[(*mut u8, &[u8])]) -> Result<Vec<PlannedChange>, region::Error> {
+        let groups = page_groups(patches.iter().map(|(location, patch)| {
+            let start = *location as usize;
+            start..start + patch.len()
+        }));
+
+        let mut changes = Vec::new();
+        for group in groups {
+            for region in region::query_range(group.start as *const u8, group.len())? {
+                let region = region?;
+                // Regions can extend past the group, but only the group's pages are changed
+                let range = region.as_range();
+                changes.push(PlannedChange {
+                    pages: range.start.max(group.start)..range.end.min(group.end),
+                    current: region.protection(),
+                    during: Protection::all(),
+                });
+            }
+        }
+        Ok(changes)
+    }
+    /// Applies every `(location, patch)` pair, returning a guard that restores all of them
+    ///
+    /// If any patch fails, the patches that were already applied are restored before returning the error.
+    ///
+    /// # Safety
+    ///
+    /// Every `location` must be valid for the length of its patch, see [`Patcher::patch`]
+    pub unsafe fn patch<'a>(
+        &'a self,
+        patches: &[(*mut u8, &[u8])],
+    ) -> Result<BatchPatchGuard<P::Guard<'a>>, PermissionError<P::Error>> {
+        let groups = page_groups(patches.iter().map(|(location, patch)| {
+            let start = *location as usize;
+            start..start + patch.len()
+        }));
+
+        // Handles restore the original permissions when dropped, so they must outlive the patches being applied
+        let _handles = groups
+            .iter()
+            .map(|group| {
+                region::protect_with_handle(
+                    group.start as *const u8,
+                    group.len(),
+                    Protection::all(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Note: if a patch fails, `guards` is dropped before `_handles`, so the applied patches are restored while still writable
+        let mut guards = Vec::with_capacity(patches.len());
+        for (location, patch) in patches {
+            guards.push(self.patcher.patch(*location, patch)?);
+        }
+
+        Ok(BatchPatchGuard { guards, groups })
+    }
+}
+
+/// Merges the page-aligned versions of `ranges` into contiguous groups
+fn page_groups(ranges: impl Iterator<Item = Range<usize>>) -> Vec<Range<usize>> {
+    let page_size = region::page::size();
+
+    let mut pages: Vec<_> = ranges
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let start = range.start - range.start % page_size;
+            let end = range.end + (page_size - range.end % page_size) % page_size;
+            start..end
+        })
+        .collect();
+    pages.sort_by_key(|range| range.start);
+
+    let mut groups: Vec<Range<usize>> = Vec::with_capacity(pages.len());
+    for range in pages {
+        match groups.last_mut() {
+            // overlapping or adjacent pages join the previous group
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => groups.push(range),
+        }
+    }
+    groups
+}
+
+/// Guard for a batch of patches
+///
+/// See [`BatchPatcher`].
+pub struct BatchPatchGuard<G: PatchGuard> {
+    /// Guards for each patch, in the order they were applied
+    guards: Vec<G>,
+    /// Contiguous page groups that need to be writable to restore the patches
+    groups: Vec<Range<usize>>,
+}
+unsafe impl<G: PatchGuard> PatchGuard for BatchPatchGuard<G> {
+    // The location and length cover every patch in the batch, including any unpatched bytes between them
+    fn location(&self) -> *const u8 {
+        self.guards
+            .iter()
+            .map(|guard| guard.location())
+            .min()
+            .unwrap_or(ptr::null())
+    }
+    fn len(&self) -> usize {
+        self.guards
+            .iter()
+            .map(|guard| guard.location() as usize + guard.len())
+            .max()
+            .map_or(0, |end| end - self.location() as usize)
+    }
+}
+impl<G: PatchGuard> Drop for BatchPatchGuard<G> {
+    fn drop(&mut self) {
+        // SAFETY: We already changed memory permissions to construct the guard, so we shouldn't run into errors here
+        let handles = self
+            .groups
+            .iter()
+            .map(|group| unsafe {
+                region::protect_with_handle(
+                    group.start as *const u8,
+                    group.len(),
+                    Protection::all(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>();
+        let _handles = match handles {
+            Ok(handles) => handles,
+            Err(e) => {
+                log::warn!(
+                    "Not restoring {} patches, their pages couldn't be made writable: {e}",
+                    self.guards.len()
+                );
+                // Restoring would write to memory that isn't writable
+                for guard in self.guards.drain(..) {
+                    mem::forget(guard);
+                }
+                return;
+            }
+        };
+
+        // Restore in reverse order so that overlapping patches end up with the original data
+        while let Some(guard) = self.guards.pop() {
+            guard.restore();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use region::Protection;
+
+    use crate::patcher::batch::{page_groups, BatchPatcher, PlannedChange};
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::PatchGuard;
+
+    #[test]
+    /// Tests that ranges are merged into contiguous page groups
+    fn test_page_groups() {
+        let page = region::page::size();
+
+        let groups = page_groups(
+            [
+                page * 4 + 1..page * 4 + 2,
+                1..2,
+                page - 1..page + 1,
+                page * 2..page * 2 + 1,
+                page * 8..page * 8,
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(groups, [0..page * 3, page * 4..page * 5]);
+    }
+
+    #[test]
+    /// Tests patching several locations at once
+    fn test_patch() {
+        let vec = b"batching".to_vec();
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = BatchPatcher::new(BytePatcher::new());
+        let patch = unsafe {
+            patcher
+                .patch(&[
+                    (ptr, b"B"),
+                    (ptr.add(5), b"ING"),
+                    // overlaps the previous patch
+                    (ptr.add(7), b"!"),
+                ])
+                .unwrap()
+        };
+
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"BatchIN!");
+
+        // the guard covers every patch in the batch
+        assert_eq!(patch.location(), ptr as *const u8);
+        assert_eq!(patch.len(), 8);
+
+        // permissions should be reverted after the patch
+        for region in region::query_range(ptr, size).unwrap() {
+            assert_eq!(region.unwrap().protection(), Protection::READ_WRITE);
+        }
+
+        patch.restore();
+
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"batching");
+        for region in region::query_range(ptr, size).unwrap() {
+            assert_eq!(region.unwrap().protection(), Protection::READ_WRITE);
+        }
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that planning reports each page group, split by current protection, without changing anything
+    fn test_plan() {
+        let page = region::page::size();
+        let map = mmap::MemoryMap::new(
+            page * 2,
+            &[mmap::MapOption::MapReadable, mmap::MapOption::MapWritable],
+        )
+        .unwrap();
+        let ptr = map.data();
+        unsafe { region::protect(ptr.add(page), page, Protection::READ) }.unwrap();
+
+        let patcher = BatchPatcher::new(BytePatcher::new());
+        // the patch straddles both pages
+        let plan = patcher
+            .plan(&[(ptr, b"a"), (unsafe { ptr.add(page - 1) }, b"bc")])
+            .unwrap();
+
+        let start = ptr as usize;
+        assert_eq!(
+            plan,
+            [
+                PlannedChange {
+                    pages: start..start + page,
+                    current: Protection::READ_WRITE,
+                    during: Protection::all(),
+                },
+                PlannedChange {
+                    pages: start + page..start + page * 2,
+                    current: Protection::READ,
+                    during: Protection::all(),
+                },
+            ]
+        );
+
+        // nothing was written or protected
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 1) }, [0]);
+        for region in region::query_range(unsafe { ptr.add(page) }, page).unwrap() {
+            assert_eq!(region.unwrap().protection(), Protection::READ);
+        }
+    }
+}
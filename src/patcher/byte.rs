@@ -1,22 +1,70 @@
 //! This module contains a byte patcher
 
-use std::ptr;
+use std::ops::Range;
+use std::sync::atomic::{fence, Ordering};
+use std::{mem, ptr, slice};
 
-use super::{PatchGuard, Patcher};
+use crate::code::arch::Architecture;
+
+use super::undo::UndoToken;
+use super::{PatchGuard, PatchStage, Patcher};
 
 /// Patcher for patching memory locations with byte arrays.
 /// This patcher never fails.
-#[derive(Default)]
+///
+/// Writes are surrounded by memory fences so that they aren't reordered with surrounding memory operations.
+/// This narrows the window where another thread can observe a partially written patch, but does **not** make the write atomic.
+/// Patching code that may be executing concurrently still requires suspending the other threads or an atomic write.
+#[derive(Default, Clone, Copy)]
 pub struct BytePatcher;
 impl BytePatcher {
     /// Creates a new [`BytePatcher`]
     pub fn new() -> Self {
         Self::default()
     }
+    /// Patches the function at `location` to immediately return `value`
+    ///
+    /// See [`Architecture::ret_const`] for the generated code.
+    ///
+    /// # Safety
+    ///
+    /// `location` must be valid and writable for the length of the generated code
+    pub unsafe fn patch_ret_const<A: Architecture>(
+        &self,
+        location: *mut u8,
+        value: u64,
+    ) -> BytePatchGuard {
+        BytePatchGuard::patch(location, &A::ret_const(value))
+    }
+    /// Patches `location` with `patch`, writing `restore` instead of the original bytes when the guard is restored
+    ///
+    /// Use this when the state after unpatching should differ from the state before patching, e.g. to leave a permanent fix behind.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `restore` isn't the same length as `patch`
+    ///
+    /// # Safety
+    ///
+    /// `location` must be valid and writable for the length of `patch`
+    pub unsafe fn patch_with_restore(
+        &self,
+        location: *mut u8,
+        patch: &[u8],
+        restore: &[u8],
+    ) -> BytePatchGuard {
+        assert_eq!(
+            restore.len(),
+            patch.len(),
+            "restore bytes must be the same length as the patch"
+        );
+        BytePatchGuard::patch_with_restore(location, patch, restore.to_vec())
+    }
 }
 unsafe impl Patcher for BytePatcher {
     type Error = ();
     type Guard<'a> = BytePatchGuard;
+    const STAGE: PatchStage = PatchStage::Write;
 
     unsafe fn patch<'a>(
         &'a self,
@@ -30,10 +78,12 @@ unsafe impl Patcher for BytePatcher {
 ///
 /// See [`BytePatcher`].
 pub struct BytePatchGuard {
-    /// Original data from `location`
+    /// Data written back to `location` when restoring. This is the original data unless [`BytePatcher::patch_with_restore`] was used
     original: Vec<u8>,
     /// Location of the patch
     location: *mut u8,
+    /// Whether each byte of the patch is still applied. Bytes restored by [`BytePatchGuard::restore_range`] aren't written again on drop
+    patched: Vec<bool>,
 }
 impl BytePatchGuard {
     /// Patches a location, returning a guard for unpatching
@@ -50,28 +100,117 @@ impl BytePatchGuard {
         // Safety: We initialized the vec to patch.len(), so fix the length
         original.set_len(patch.len());
 
-        let guard = Self { original, location };
+        Self::patch_with_restore(location, patch, original)
+    }
+    /// Patches a location, returning a guard that writes `restore` when unpatching
+    ///
+    /// # Safety
+    ///
+    /// `location` must be a valid pointer, and `restore` must be the same length as `patch`
+    unsafe fn patch_with_restore(location: *mut u8, patch: &[u8], restore: Vec<u8>) -> Self {
+        let guard = Self {
+            patched: vec![true; restore.len()],
+            original: restore,
+            location,
+        };
 
         // Safety: caller must ensure that `location` is writable
-        ptr::copy(patch.as_ptr(), location, patch.len());
+        write_fenced(patch, location);
 
         guard
     }
+    /// Restores the original bytes for `len` bytes starting at `offset` into the patch, leaving the rest of the patch applied
+    ///
+    /// Restored bytes aren't written again when the guard is dropped, so they can safely be patched by something else afterwards.
+    /// Restoring bytes that were already restored does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range extends past the end of the patch
+    pub fn restore_range(&mut self, offset: usize, len: usize) {
+        let end = offset
+            .checked_add(len)
+            .filter(|&end| end <= self.original.len())
+            .expect("restore range is outside of the patch");
+        self.restore_runs(offset..end);
+    }
+    /// Converts the guard into an [`UndoToken`] without restoring the patch
+    ///
+    /// The token owns a copy of the location and the bytes to write back, and restores through its own [`PermissionWrapper`](super::mem::PermissionWrapper),
+    /// so restore data can be kept in long-lived collections without borrowing anything.
+    /// Bytes already restored by [`BytePatchGuard::restore_range`] are skipped when undoing, since something else may have patched them since.
+    pub fn into_undo(mut self) -> UndoToken {
+        // Nothing is left for the guard to restore when it's dropped
+        let original = mem::take(&mut self.original);
+        let patched = mem::take(&mut self.patched);
+        UndoToken::new(self.location, original, patched)
+    }
+    /// Lists the bytes that differ between the original and the patched memory as `(offset, old, new)`, for logging patches that only change a few bytes
+    ///
+    /// `old` is the byte written back when restoring (see [`BytePatcher::patch_with_restore`]) and `new` is the byte currently in memory.
+    /// Bytes restored by [`BytePatchGuard::restore_range`] are left out.
+    pub fn diff(&self) -> Vec<(usize, u8, u8)> {
+        // Safety: creator must pass in a `location` pointer that is valid for the full length of the patch
+        let current = unsafe { slice::from_raw_parts(self.location, self.original.len()) };
+        self.original
+            .iter()
+            .zip(current)
+            .enumerate()
+            .filter(|&(offset, (old, new))| self.patched[offset] && old != new)
+            .map(|(offset, (&old, &new))| (offset, old, new))
+            .collect()
+    }
+    /// Writes back the original bytes for every run of still-patched bytes within `range`
+    fn restore_runs(&mut self, range: Range<usize>) {
+        let mut offset = range.start;
+        while offset < range.end {
+            if !self.patched[offset] {
+                offset += 1;
+                continue;
+            }
+            let start = offset;
+            while offset < range.end && self.patched[offset] {
+                self.patched[offset] = false;
+                offset += 1;
+            }
+            // Safety: creator must pass in a `location` pointer that is valid and writable for the full length of the patch
+            unsafe {
+                write_fenced(&self.original[start..offset], self.location.add(start));
+            }
+        }
+    }
+}
+unsafe impl PatchGuard for BytePatchGuard {
+    fn location(&self) -> *const u8 {
+        self.location
+    }
+    fn len(&self) -> usize {
+        self.original.len()
+    }
 }
-unsafe impl PatchGuard for BytePatchGuard {}
 impl Drop for BytePatchGuard {
     fn drop(&mut self) {
-        // Safety: creator must pass in a `location` pointer that is valid and writable for the full length of the patch
-        unsafe {
-            ptr::copy(self.original.as_ptr(), self.location, self.original.len());
-        }
+        self.restore_runs(0..self.original.len());
     }
 }
 
+/// Copies `data` to `location`, with fences on both sides of the write
+///
+/// # Safety
+///
+/// `location` must be valid and writable for the length of `data`
+pub(crate) unsafe fn write_fenced(data: &[u8], location: *mut u8) {
+    fence(Ordering::SeqCst);
+    ptr::copy(data.as_ptr(), location, data.len());
+    fence(Ordering::SeqCst);
+}
+
 #[cfg(test)]
 mod tests {
     use std::slice;
 
+    use crate::code::arch::X86_64;
+    use crate::code::x64::ret_const;
     use crate::patcher::byte::BytePatcher;
     use crate::patcher::{PatchGuard, Patcher};
 
@@ -121,6 +260,10 @@ mod tests {
         // make sure the data was actually changed
         assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [1, 5, 5, 4]);
 
+        // the guard only covers the patched bytes
+        assert_eq!(patch.location() as usize, ptr as usize + 1);
+        assert_eq!(patch.len(), 2);
+
         // restore the patch
         patch.restore();
 
@@ -130,4 +273,138 @@ mod tests {
         // clean up
         let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
     }
+
+    #[test]
+    /// Tests restoring part of a patch while the rest stays applied
+    fn test_restore_range() {
+        let vec = vec![1u8, 2, 3, 4, 5, 6];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = BytePatcher::new();
+        let mut patch = unsafe { patcher.patch(ptr, &[9, 9, 9, 9, 9]).unwrap() };
+
+        // restore the middle of the patch
+        patch.restore_range(1, 2);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [9, 2, 3, 9, 9, 6]
+        );
+
+        // restoring an overlapping range only touches bytes that are still patched
+        patch.restore_range(2, 2);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [9, 2, 3, 4, 9, 6]
+        );
+
+        // something else patches a restored byte
+        unsafe { *ptr.add(1) = 7 };
+
+        // restoring the full patch must leave it alone
+        patch.restore();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [1, 7, 3, 4, 5, 6]
+        );
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    #[should_panic]
+    /// Tests that restoring past the end of the patch panics
+    fn test_restore_range_out_of_bounds() {
+        let mut data = [1u8, 2, 3, 4];
+
+        let patcher = BytePatcher::new();
+        let mut patch = unsafe { patcher.patch(data.as_mut_ptr(), &[5, 5]).unwrap() };
+
+        patch.restore_range(1, 2);
+    }
+
+    #[test]
+    /// Tests patching a function to return a constant
+    fn test_patch_ret_const() {
+        let vec = vec![0x90u8; 16];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patch = unsafe { BytePatcher::new().patch_ret_const::<X86_64>(ptr, 1) };
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 6) }, ret_const(1));
+
+        patch.restore();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 16]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that the diff only lists bytes the patch actually changed and that are still patched
+    fn test_diff() {
+        let vec = vec![1u8, 2, 3, 4];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let mut patch = unsafe { BytePatcher::new().patch(ptr, &[1, 9, 3, 8]) }.unwrap();
+        assert_eq!(patch.diff(), [(1, 2, 9), (3, 4, 8)]);
+
+        patch.restore_range(1, 1);
+        assert_eq!(patch.diff(), [(3, 4, 8)]);
+
+        drop(patch);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that converting a guard into an undo token leaves the patch in place until the token is used
+    fn test_into_undo() {
+        let vec = vec![1u8, 2, 3, 4];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patch = unsafe { BytePatcher::new().patch(ptr, &[4, 3, 2, 1]) }.unwrap();
+        let token = patch.into_undo();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [4, 3, 2, 1]);
+        assert_eq!(token.location(), ptr as *const u8);
+        assert_eq!(token.original(), [1, 2, 3, 4]);
+
+        unsafe { token.undo() }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [1, 2, 3, 4]);
+
+        // bytes restored before the conversion belong to whoever patches them next, so undoing leaves them alone
+        let mut patch = unsafe { BytePatcher::new().patch(ptr, &[4, 3, 2, 1]) }.unwrap();
+        patch.restore_range(1, 2);
+        unsafe { ptr.add(1).write_bytes(9, 2) };
+        unsafe { patch.into_undo().undo() }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [1, 9, 9, 4]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that restoring writes the caller's bytes instead of the original ones
+    fn test_patch_with_restore() {
+        let vec = vec![1u8, 2, 3, 4];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = BytePatcher::new();
+        let patch = unsafe { patcher.patch_with_restore(ptr.add(1), &[5, 5], &[7, 7]) };
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [1, 5, 5, 4]);
+
+        patch.restore();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [1, 7, 7, 4]);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    #[should_panic(expected = "restore bytes must be the same length as the patch")]
+    /// Tests that restore bytes of the wrong length are rejected before anything is written
+    fn test_patch_with_restore_length() {
+        let mut data = [1u8, 2, 3, 4];
+        let _ = unsafe { BytePatcher::new().patch_with_restore(data.as_mut_ptr(), &[5, 5], &[7]) };
+    }
 }
@@ -4,22 +4,62 @@
 //! This is because future pipelines may need to get the pointer to the original function *without* patching the location
 //!
 //! When you finally want to patch, use [`CodePatcher::patch`].
+//!
+//! # Absolute addresses
+//!
+//! Relative branches and RIP-relative operands are fixed up when code is relocated, but immediates are treated as data.
+//! If a relocated `mov reg, imm` loads an address inside the patched bytes (e.g. a pointer into the function's own prologue), it would still point at the patch after relocating.
+//! This is detected on a best-effort basis and reported as [`CodeError::AbsoluteReference`]; addresses built any other way aren't detected.
+//!
+//! # Stack alignment
+//!
+//! Optimized prologues often spill SIMD registers with aligned moves (e.g. `movaps [rsp + N], xmm6`), which fault unless `rsp` has the alignment the function was entered with.
+//! Trampolines never touch the stack: they run the relocated instructions and `jmp` back, and every jump this library writes (`jmp rel8`, `jmp rel32` and [`jmp_abs`](crate::code::x64::jmp_abs)'s `jmp [rip]`) leaves `rsp` alone.
+//! Custom [`JumpEncoder`](crate::hook::jmphook::JumpEncoder)s must do the same by the time they reach the destination; `push addr; ret` is fine since the `ret` pops what was pushed, but anything that leaves data on the stack (e.g. a `call`) breaks the alignment.
+//!
+//! # Self-modifying targets
+//!
+//! The original code is captured (and relocated) when the patcher is created.
+//! Functions that decrypt or rewrite their own prologue at runtime will have their *encrypted* bytes captured if they're patched before they first run, so the trampoline runs garbage and restoring writes the encrypted bytes back.
+//!
+//! Patch such functions only after they've run once. If it isn't known when that happens, the int3-assisted capture mode in `trap::capture` (with the `step` feature) records the bytes on first execution;
+//! create the patcher once they're captured, or pass them to [`BytePatcher::patch_with_restore`] to choose the bytes that are written back.
+//! A full `Int3Patcher`, which would hook through the trap instead of a jump, isn't part of this crate.
 
+use std::ffi::c_void;
+use std::fmt::{self, Write as _};
 use std::marker::PhantomData;
-use std::{iter, ptr, slice};
+use std::ops::Range;
+use std::{mem, ptr, slice};
 
 use iced_x86::{
-    BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, IcedError, Instruction,
-    InstructionBlock,
+    BlockEncoder, BlockEncoderOptions, BlockEncoderResult, Code, Decoder, DecoderOptions,
+    FlowControl, Formatter, IcedError, Instruction, InstructionBlock, IntelFormatter,
 };
+use region::Protection;
 use thiserror::Error;
 
-use crate::alloc::{allocate_executable, proximity::ProximityError, ExecutableMemory};
+use crate::alloc::{
+    allocate_executable_within, proximity::ProximityError, ExecutableMemory, Finalized,
+    DETOUR_RANGE,
+};
+use crate::code::x64::{follow_thunks, read_jmp_abs, JMP_ABS_LEN};
+use crate::code::x86;
+use crate::code::{multi_byte_nops, within_rel32, JMP_REL32_LEN};
 
 use super::byte::BytePatcher;
 use super::mem::{to_mut, PermissionError, PermissionWrapper};
 use super::Patcher;
 
+pub use crate::code::arch::{ArchRuntime, Architecture, DynArch, X86, X86_64};
+
+/// Number of trampoline allocations to try before giving up on relocating
+const RELOCATION_ATTEMPTS: usize = 4;
+/// How much closer each retried trampoline allocation has to be
+const RELOCATION_DISTANCE_DIVISOR: usize = 16;
+/// Distance from the original code at which trampolines are encoded to find their size
+const SCRATCH_OFFSET: u64 = 0x10_0000;
+
 #[derive(Debug, Error)]
 /// Error types for `CodePatcher`
 pub enum CodeError<E> {
@@ -36,9 +76,102 @@ pub enum CodeError<E> {
     #[error("{0}")]
     ProximityError(#[from] ProximityError),
     /// Buffer size that was allocated was too small.
-    /// If you encounter this error, open an issue and include the full patch bytes, [allocated] bytes from the original function, and the location of the target and location value from this error.
-    #[error("Buffer size was too small (allocated: {0}, needed: {1}, location: {2:?})")]
-    BufferTooSmall(usize, usize, *const ()),
+    /// If you encounter this error, open an issue and include this error's message, which contains the full [`RelocationReport`].
+    #[error("Buffer size was too small ({0})")]
+    BufferTooSmall(RelocationReport),
+    /// The location already starts with a jump generated by this library (jump target included).
+    /// Relocating the jump would chain to the existing hook rather than the original code.
+    #[error("Location is already hooked (jumps to {0:?})")]
+    AlreadyHooked(*const ()),
+    /// The patch is longer than the instructions that were decoded to make room for it.
+    /// Writing it would clobber code that hasn't been relocated.
+    #[error("Patch is larger than the relocated code (patch: {0}, relocated: {1})")]
+    PatchTooLarge(usize, usize),
+    /// The patch starts with a jump into the patched bytes, so it would jump to itself
+    #[error("Patch jumps into itself (target: {0:?})")]
+    DegenerateJump(*const ()),
+    /// The code to relocate contains an invalid instruction, or the relocation length doesn't end on an instruction boundary
+    #[error("Invalid instruction in relocated code (location: {0:?})")]
+    InvalidRelocation(*const ()),
+    /// A relocated instruction loads an absolute address inside the patched bytes (instruction location included).
+    /// Immediates are data to the relocator, so the address can't be fixed up to point at the trampoline.
+    #[error("Relocated instruction references the patched bytes (location: {0:?})")]
+    AbsoluteReference(*const ()),
+    /// The trampoline was allocated too far from the end of the patched block (trampoline, end of block) for its `jmp rel32` to reach it
+    #[error("Trampoline at {0:?} is out of range of its back-jump to {1:?}")]
+    BackJumpOutOfRange(*const (), *const ()),
+    /// The code to relocate contains an `int3` (instruction location included), which is usually a debugger's software breakpoint rather than part of the function.
+    /// Saving it as the original code would run the breakpoint from the trampoline and write it back when restoring, long after the debugger removed it.
+    /// Only checked by [`CodePatcher::new_rejecting_breakpoints`], since compilers also pad between functions with `int3`.
+    #[error("Relocated code contains a breakpoint (location: {0:?})")]
+    Breakpoint(*const ()),
+    /// The patch ends partway through an instruction (offset of that instruction in the patch), so the padding after it would be decoded as part of it
+    #[error("Patch doesn't end on an instruction boundary (incomplete instruction at offset {0})")]
+    IncompletePatch(usize),
+}
+
+/// How [`CodePatcher`] fills relocated bytes that aren't covered by the patch
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// One single-byte `nop` per byte
+    #[default]
+    Nop,
+    /// As few multi-byte NOPs as possible, usually a single one. See [`multi_byte_nops`]
+    MultiByteNop,
+    /// Repeats of a byte, e.g. `0xcc` so that reaching the padding traps
+    Byte(u8),
+}
+impl Fill {
+    /// Generates `len` bytes of padding
+    pub fn bytes(self, len: usize) -> Vec<u8> {
+        match self {
+            Self::Nop => vec![0x90; len],
+            Self::MultiByteNop => multi_byte_nops(len),
+            Self::Byte(byte) => vec![byte; len],
+        }
+    }
+}
+
+/// Details of a relocation that didn't fit in its trampoline, for diagnosing [`CodeError::BufferTooSmall`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelocationReport {
+    /// Location of the target
+    pub location: *const (),
+    /// Location of the trampoline
+    pub trampoline: *const (),
+    /// Size of the trampoline that was allocated
+    pub allocated: usize,
+    /// Size of the relocated code
+    pub needed: usize,
+    /// Bytes that were relocated from the target
+    pub original: Vec<u8>,
+    /// Number of instructions that were relocated
+    pub instructions: usize,
+    /// Patch that would have been written over the relocated bytes
+    pub patch: Vec<u8>,
+}
+impl fmt::Display for RelocationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Formats bytes as space separated hex
+        fn hex(bytes: &[u8]) -> String {
+            bytes
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        write!(
+            f,
+            "allocated: {}, needed: {}, location: {:?}, trampoline: {:?}, instructions: {}, original: [{}], patch: [{}]",
+            self.allocated,
+            self.needed,
+            self.location,
+            self.trampoline,
+            self.instructions,
+            hex(&self.original),
+            hex(&self.patch),
+        )
+    }
 }
 
 /// Wrapper for patching code sections that may need to patch more bytes than what's provided
@@ -51,15 +184,29 @@ pub enum CodeError<E> {
 /// The caller must therefore ensure that `location` is valid for the patch size + 14
 /// (enough space to ensure that if `location` ends on the first byte of the largest instruction size (15),
 /// we can still disassemble the full instruction)
-pub struct CodePatcher<P: Patcher, A: Architecture> {
+pub struct CodePatcher<P: Patcher, A> {
     /// Internal patcher that will actually write the data we create
     patcher: PermissionWrapper<P>,
     /// Original data that was patched. Created such that `original` contains safely moved code that can be executed as if you were executing the original code.
-    original: ExecutableMemory,
+    ///
+    /// `None` if the patcher was created with [`CodePatcher::new_replace`]
+    original: Option<ExecutableMemory<Finalized>>,
     /// Data to patch to the location
     patch: Vec<u8>,
+    /// Length of the caller's patch. The rest of `patch` is padding
+    patch_len: usize,
+    /// Offset of each relocated instruction in the original code, paired with its offset in the trampoline
+    offset_map: Vec<(u64, u64)>,
     /// location to patch
     location: *const u8,
+    /// Address the code at `location` runs at
+    ip: u64,
+    /// Code that was relocated to the trampoline. Empty if the patcher was created with [`CodePatcher::new_replace`]
+    relocated: Vec<u8>,
+    /// Length of the code in the trampoline
+    trampoline_len: usize,
+    /// Bitness of the patched code
+    bitness: u32,
     /// Placeholder for architecture
     _arch: PhantomData<A>,
 }
@@ -81,90 +228,529 @@ where
         patcher: P,
         location: *const u8,
         patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let arch = StaticArch::<A>::default();
+        let patch = patch.as_ref();
+        validate(
+            readable(location, patch.len(), &arch),
+            location as u64,
+            patch,
+            0,
+            &arch,
+        )?;
+        Self::new_unchecked(patcher, location, patch)
+    }
+    /// Creates a new CodePatcher without checking whether the location can safely be patched
+    ///
+    /// **This skips every safety check.** The location isn't checked for an existing hook ([`CodeError::AlreadyHooked`]), the patch isn't checked for jumping into itself ([`CodeError::DegenerateJump`]),
+    /// and the relocated code isn't checked for absolute references to the patched bytes ([`CodeError::AbsoluteReference`]).
+    /// [`CodePatcher::new`] runs those checks and then this; only use this for unusual code that the checks reject even though you know patching it is fine.
+    ///
+    /// Relocation itself can still fail, e.g. if the trampoline can't be allocated or encoded.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// See [`CodePatcher::new`]. The caller is also responsible for everything the skipped checks would have caught.
+    pub unsafe fn new_unchecked<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        Self::decode(
+            patcher,
+            location,
+            location as u64,
+            patch.as_ref(),
+            0,
+            &StaticArch::<A>::default(),
+        )
+    }
+    /// Creates a new CodePatcher, first checking that `patch` decodes to complete instructions
+    ///
+    /// A hand-assembled patch that's missing the end of its last instruction would swallow the padding (or relocated code) after it.
+    /// [`CodePatcher::new`] only logs a warning for such patches; this returns [`CodeError::IncompletePatch`] instead.
+    ///
+    /// # Safety
+    ///
+    /// See [`CodePatcher::new`]
+    pub unsafe fn new_checked<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        if let Some(offset) = incomplete_instruction(A::bitness(), patch.as_ref()) {
+            return Err(CodeError::IncompletePatch(offset));
+        }
+        Self::new(patcher, location, patch)
+    }
+    /// Creates a new CodePatcher, first checking that the code to relocate doesn't contain a debugger's breakpoint
+    ///
+    /// Software breakpoints are `int3` instructions written over the start of an instruction, so patching over one saves it as part of the original code (see [`CodeError::Breakpoint`]).
+    /// Only instructions before the first `ret` or unconditional `jmp` are checked, since the `int3` padding after a short function isn't a breakpoint.
+    /// Otherwise this is the same as [`CodePatcher::new`].
+    ///
+    /// # Safety
+    ///
+    /// See [`CodePatcher::new`]
+    pub unsafe fn new_rejecting_breakpoints<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let arch = StaticArch::<A>::default();
+        let patch = patch.as_ref();
+        let data = readable(location, patch.len(), &arch);
+        if let Some(breakpoint) = breakpoint(&displaced(data, location as u64, patch.len(), &arch))
+        {
+            return Err(CodeError::Breakpoint(breakpoint as _));
+        }
+        Self::new(patcher, location, patch)
+    }
+    /// Creates a new CodePatcher that relocates at least `min_len` bytes from `location`
+    ///
+    /// Use this to reserve space after the patch (e.g. for a second patch later).
+    /// The relocated length is rounded up to the next instruction boundary, and everything after `patch` is filled with NOPs.
+    /// The trampoline always jumps back to the first instruction after the relocated bytes, never into the NOP padding.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// `location` must point to valid executable code, valid for the larger of `min_len` and the length of `patch`, + the max architecture
+    pub unsafe fn new_with_min_len<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+        min_len: usize,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let arch = StaticArch::<A>::default();
+        let patch = patch.as_ref();
+        validate(
+            readable(location, patch.len().max(min_len), &arch),
+            location as u64,
+            patch,
+            min_len,
+            &arch,
+        )?;
+        Self::decode(patcher, location, location as u64, patch, min_len, &arch)
+    }
+    /// Creates a new CodePatcher for code that's read from `location` but runs at `ip`
+    ///
+    /// Use this when the bytes aren't at the address they'll execute from, e.g. an image that's mapped locally before being moved into place.
+    /// RIP-relative operands, relative branches and the trampoline's back-jump are fixed up for `ip`, and the trampoline is allocated close to `ip`.
+    /// The patch is still written to `location`.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// `location` must point to valid executable code, valid for the length of `patch` + the max architecture.
+    /// The trampoline jumps back to `ip`, so it must only be called once the code is running there.
+    pub unsafe fn new_with_ip<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        ip: u64,
+        patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let arch = StaticArch::<A>::default();
+        let patch = patch.as_ref();
+        validate(readable(location, patch.len(), &arch), ip, patch, 0, &arch)?;
+        Self::decode(patcher, location, ip, patch, 0, &arch)
+    }
+    /// Creates a new CodePatcher that relocates exactly `relocate_len` bytes from `location`
+    ///
+    /// Use this when the length of the instructions to move is already known (e.g. from prior analysis), to skip searching for the instruction boundary after `patch`.
+    /// `relocate_len` must be at least the length of `patch` and must end on an instruction boundary.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// `location` must point to valid executable code, valid for `relocate_len` bytes
+    pub unsafe fn new_with_len<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+        relocate_len: usize,
     ) -> Result<Self, CodeError<P::Error>> {
         let patch = patch.as_ref();
         let patcher = PermissionWrapper::new(patcher);
 
-        // Length of patch + max instruction size
-        let patch_size = patch.len();
-        let max_size = patch_size + A::max_instr_len();
+        // Checked before reading so that a short `relocate_len` can't be used to read past what the caller validated
+        if patch.len() > relocate_len {
+            return Err(CodeError::PatchTooLarge(patch.len(), relocate_len));
+        }
+
+        // Safety: the caller is required to ensure that `location` is valid for `relocate_len`
+        let data = slice::from_raw_parts(location, relocate_len);
+        validate(
+            data,
+            location as u64,
+            patch,
+            relocate_len,
+            &StaticArch::<A>::default(),
+        )?;
 
-        // Actual patch data
+        // Every byte is relocated, so an instruction cut off by `relocate_len` decodes as invalid
+        let mut decoder =
+            Decoder::with_ip(A::bitness(), data, location as u64, DecoderOptions::NONE);
+        let instructions: Vec<_> = decoder.iter().collect();
+        if let Some(invalid) = instructions.iter().find(|i| i.is_invalid()) {
+            return Err(CodeError::InvalidRelocation(invalid.ip() as _));
+        }
+
+        Self::relocate(
+            patcher,
+            location,
+            location as u64,
+            patch,
+            instructions,
+            relocate_len,
+            &StaticArch::<A>::default(),
+        )
+    }
+    /// Creates a new CodePatcher that replaces the code at `location` without preserving it
+    ///
+    /// This is intended for hooks that will never call the original function.
+    /// The target is not disassembled and no trampoline is allocated, so only the bytes in `patch` are written.
+    /// [`CodePatcher::original`] will always return `None` for patchers created this way.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// `location` must point to valid executable code, valid for the length of `patch`
+    pub unsafe fn new_replace<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        Ok(Self {
+            patcher: PermissionWrapper::new(patcher),
+            original: None,
+            patch: patch.as_ref().to_vec(),
+            patch_len: patch.as_ref().len(),
+            offset_map: Vec::new(),
+            location,
+            ip: location as u64,
+            relocated: Vec::new(),
+            trampoline_len: 0,
+            bitness: A::bitness(),
+            _arch: Default::default(),
+        })
+    }
+}
+
+impl<P, A> CodePatcher<P, A>
+where
+    P: Patcher,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Describes what patching does in a single human-readable line, e.g. for logging or command-line tools
+    ///
+    /// The description lists the relocated instructions, what the patch does, and the trampoline's instructions:
+    /// `hooked 0x1400010a0 (sub rsp,28h; mov rbx,rcx) → 0x7ff612340000 via 14-byte abs jmp, trampoline at 0x140000000 (sub rsp,28h; mov rbx,rcx; jmp 00000001400010A7h)`.
+    /// [`jmp_abs`](crate::code::x64::jmp_abs) patches (or [`x86::jmp_abs`](crate::code::x86::jmp_abs) patches for 32-bit code) are shown by their destination, and any other patch is disassembled.
+    pub fn describe_install(&self) -> String {
+        let mut description = format!("hooked {:#x}", self.ip);
+        if !self.relocated.is_empty() {
+            let _ = write!(
+                description,
+                " ({})",
+                disassemble(self.bitness, &self.relocated, self.ip)
+            );
+        }
+
+        let patch = &self.patch[..self.patch_len];
+        let _ = match read_hook_jmp(self.bitness, patch) {
+            Some(target) => write!(
+                description,
+                " → {target:#x} via {}-byte abs jmp",
+                patch.len()
+            ),
+            None => write!(
+                description,
+                " → {}-byte patch ({})",
+                patch.len(),
+                disassemble(self.bitness, patch, self.ip)
+            ),
+        };
+        let padding = self.patch.len() - self.patch_len;
+        if padding > 0 {
+            let _ = write!(description, " + {padding} bytes of padding");
+        }
+
+        let _ = match &self.original {
+            Some(original) => write!(
+                description,
+                ", trampoline at {:#x} ({})",
+                original.addr(),
+                disassemble(
+                    self.bitness,
+                    self.trampoline_bytes(),
+                    original.addr() as u64
+                )
+            ),
+            None => write!(description, ", no trampoline"),
+        };
+        description
+    }
+    /// Gets the instructions at `location` that a patch of `patch_len` bytes would overwrite, which are the instructions [`CodePatcher::new`] relocates
+    ///
+    /// Only decodes: nothing is allocated or patched, and none of the checks [`CodePatcher::new`] runs on the code are done.
+    /// Returns [`CodeError::InvalidRelocation`] if one of the instructions is invalid.
+    ///
+    /// # Safety
+    ///
+    /// `location` must be valid for `patch_len` + the architecture's max instruction length
+    pub unsafe fn displaced_instructions(
+        location: *const u8,
+        patch_len: usize,
+        arch: &dyn ArchRuntime,
+    ) -> Result<Vec<Instruction>, CodeError<P::Error>> {
         // Safety: the caller is required to ensure that `location` is valid
-        let data = slice::from_raw_parts(location, max_size);
+        let data = readable(location, patch_len, arch);
+        let instructions = displaced(data, location as u64, patch_len, arch);
+        match instructions.iter().find(|i| i.is_invalid()) {
+            Some(invalid) => Err(CodeError::InvalidRelocation(invalid.ip() as _)),
+            None => Ok(instructions),
+        }
+    }
+    /// Decodes whole instructions covering at least `min_len` bytes and the patch at `location`, running at `ip`, then relocates them
+    ///
+    /// Nothing is checked here; the checked constructors call [`validate`] first.
+    ///
+    /// # Safety
+    ///
+    /// `location` must be valid for the larger of `min_len` and the length of `patch`, + the max architecture
+    unsafe fn decode(
+        patcher: P,
+        location: *const u8,
+        ip: u64,
+        patch: &[u8],
+        min_len: usize,
+        arch: &dyn ArchRuntime,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let patcher = PermissionWrapper::new(patcher);
 
-        // Create a decoder to figure out what length we need to patch
-        let decoder = Decoder::with_ip(A::bitness(), data, location as u64, DecoderOptions::NONE);
+        // Length of patch (or padding)
+        let patch_size = patch.len().max(min_len);
 
-        // Get the full patch length. This might be larger than the passed in patch if the location being patched has more instructions than the patch, but never smaller.
-        let mut size = 0usize;
-        let mut instructions: Vec<_> = decoder
-            .into_iter()
-            .take_while(|v| {
-                let ret = size < patch_size; // include this instruction if it would go past the end
-                size += v.len();
-                ret
-            })
-            .collect();
+        // Safety: the caller is required to ensure that `location` is valid
+        let data = readable(location, patch_size, arch);
 
-        // Now that we have the list of instructions, get the actual size
-        // Note: The old size will be 1 instruction too long, so we need to recalculate it here
+        let instructions = displaced(data, ip, patch_size, arch);
         let size = instructions.iter().fold(0, |c, i| c + i.len());
 
+        Self::relocate(patcher, location, ip, patch, instructions, size, arch)
+    }
+    /// Relocates `instructions` (the first `size` bytes at `location`) to a trampoline and prepares `patch` to be written over them
+    ///
+    /// `ip` is the address the code at `location` runs at, which the trampoline's fix-ups and back-jump are relative to.
+    ///
+    /// # Safety
+    ///
+    /// `instructions` must be decoded from `location` with `ip` as the instruction pointer, and `location` must be valid for `size` bytes
+    unsafe fn relocate(
+        patcher: PermissionWrapper<P>,
+        location: *const u8,
+        ip: u64,
+        patch: &[u8],
+        mut instructions: Vec<Instruction>,
+        size: usize,
+        arch: &dyn ArchRuntime,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let patch_size = patch.len();
+        let relocated = instructions.len();
+
+        // Decoding can stop short (e.g. running out of bytes), in which case the patch would overwrite code we didn't move
+        if patch_size > size {
+            return Err(CodeError::PatchTooLarge(patch_size, size));
+        }
+
+        // The trampoline is called indirectly, so it may need to start with a branch target marker
+        if let Some(marker) = arch.entry_marker() {
+            instructions.insert(0, marker);
+        }
+
         // Add a jmp to the previous location
+        let jmp = match arch.bitness() {
+            16 => Code::Jmp_rel16,
+            32 => Code::Jmp_rel32_32,
+            _ => Code::Jmp_rel32_64,
+        };
         instructions.push(Instruction::with_branch(
-            Code::Jmp_rel32_64,
+            jmp,
             // Jump to the end of the patched block
-            (location as usize + size) as u64,
+            ip + size as u64,
         )?);
 
-        // Allocate the place we'll be putting the old code
-        // Note: the original code may have some fixed up relative instructions, so we need to allocate a size larger than what we're moving in case the final code is larger
-        // doubling the size + max instruction length was chosen arbitrarilly (size * 2 isn't big enough for very small patches since we add an extra jmp)
-        let mut original = allocate_executable(location as _, size * 2 + A::max_instr_len())?;
-
-        // Create a block for the new location
-        let block = InstructionBlock::new(&instructions, original.as_ptr() as _);
+        // Relative instructions can be widened when they're fixed up (e.g. a `jmp rel8` that can't reach from the trampoline becomes a `jmp rel32`), so the trampoline can be larger than the code it holds.
+        // Encoding it away from the original code first gives the size it'll have once it's allocated, instead of guessing.
+        let trampoline_size = scratch_len(&instructions, ip, arch)
+            // doubling the size + max instruction length was chosen arbitrarilly (size * 2 isn't big enough for very small patches since we add an extra jmp)
+            .unwrap_or(size * 2 + arch.max_instr_len());
+        let resume = (ip + size as u64) as usize;
 
-        // This is where the magic happens. [`BlockEncoder`] re-encodes the instructions for the new location and fixes up all the relative instructions
-        // BlockEncoder requires a buffer be allocated *close* to where the original data came from, and our [`allocate_executable`] function handles that.
-        let encoded = BlockEncoder::encode(A::bitness(), block, BlockEncoderOptions::NONE)?;
+        let (mut original, encoded) = allocate_trampoline(
+            &instructions,
+            trampoline_size,
+            resume,
+            arch,
+            |size, max_distance| allocate_executable_within(ip as _, size, max_distance),
+            |original, needed| RelocationReport {
+                location: location as _,
+                trampoline: original.exec_ptr() as _,
+                allocated: original.len(),
+                needed,
+                original: slice::from_raw_parts(location, size).to_vec(),
+                instructions: relocated,
+                patch: patch.to_vec(),
+            },
+        )?;
         let bytes = encoded.code_buffer;
 
-        // Sanity check in case our allocation is too small
-        if bytes.len() > original.len() {
-            // This is a bug. Check [CodeError::BufferTooSmall] for what info to include in your issue
-            return Err(CodeError::BufferTooSmall(
-                original.len(),
-                bytes.len(),
-                original.as_ptr() as _,
-            ));
+        // The back-jump must resume execution at the first instruction after the patched block
+        debug_assert_eq!(
+            encoded
+                .new_instruction_offsets
+                .last()
+                .and_then(|&offset| jump_target(
+                    arch.bitness(),
+                    &bytes,
+                    original.addr() as u64,
+                    offset as usize
+                )),
+            Some(ip + size as u64),
+            "trampoline back-jump doesn't target the end of the patched block"
+        );
+
+        // The trampoline must start with the same instruction as the original code, otherwise relocation corrupted it
+        if cfg!(debug_assertions) && relocated > 0 {
+            // Skip the entry marker, if there is one
+            let first = instructions.len() - relocated - 1;
+            // Instructions that were rewritten to something else entirely don't have an offset
+            let offset = encoded.new_instruction_offsets[first];
+            if offset != u32::MAX {
+                let offset = offset as usize;
+                let mut decoder = Decoder::with_ip(
+                    arch.bitness(),
+                    &bytes[offset..],
+                    original.addr() as u64 + offset as u64,
+                    DecoderOptions::NONE,
+                );
+                debug_assert_eq!(
+                    decoder.decode().mnemonic(),
+                    instructions[first].mnemonic(),
+                    "trampoline doesn't start with the original's first instruction"
+                );
+            }
         }
 
+        // Instructions that were rewritten to something else entirely don't have an offset, so threads can't be moved to them
+        let first = instructions.len() - relocated - 1;
+        let offset_map = instructions[first..first + relocated]
+            .iter()
+            .zip(&encoded.new_instruction_offsets[first..])
+            .filter(|&(_, &offset)| offset != u32::MAX)
+            .map(|(instruction, &offset)| (instruction.ip() - ip, offset as u64))
+            .collect();
+
         // Finally, copy the fixed up buffer to its destination
+        debug_assert!(
+            original.protection().contains(Protection::WRITE),
+            "trampoline isn't writable"
+        );
         ptr::copy(bytes.as_ptr(), original.as_mut_ptr(), bytes.len());
+        let trampoline_len = bytes.len();
+
+        // The trampoline won't change again, so it doesn't need to stay writable
+        let original = original.finalize()?;
 
         // Re-generate the patch, filling the rest of the space with NOPs
         let patch = patch
             .iter()
             .copied()
-            .chain(iter::repeat(b'\x90')) // Fill extra space with nops
-            .take(size)
+            .chain(Fill::default().bytes(size - patch_size))
             .collect();
 
         Ok(Self {
             patcher,
-            original,
+            original: Some(original),
             patch,
+            patch_len: patch_size,
+            offset_map,
             location,
+            ip,
+            relocated: slice::from_raw_parts(location, size).to_vec(),
+            trampoline_len,
+            bitness: arch.bitness(),
             _arch: Default::default(),
         })
     }
+    /// Fills the relocated bytes after the patch according to `fill` instead of with single-byte NOPs
+    ///
+    /// The trampoline jumps back past the padding, so it's never executed either way; this only changes how the patched code looks (e.g. in a disassembler).
+    pub fn with_fill(mut self, fill: Fill) -> Self {
+        let padding = self.patch.len() - self.patch_len;
+        self.patch.truncate(self.patch_len);
+        self.patch.extend(fill.bytes(padding));
+        self
+    }
     /// Returns a pointer to the original function.
     ///
-    /// This pointer is directly callable regardless of patch status and will act as if you're calling the original unpatched function
-    pub fn original(&self) -> *const u8 {
-        self.original.as_ptr()
+    /// This pointer is directly callable regardless of patch status and will act as if you're calling the original unpatched function.
+    /// Returns `None` if the patcher was created with [`CodePatcher::new_replace`].
+    pub fn original(&self) -> Option<*const u8> {
+        self.original.as_ref().map(|original| original.exec_ptr())
+    }
+    /// Returns a pointer to the original function as a `c_void` pointer, for FFI declarations (e.g. bindgen-generated signatures).
+    ///
+    /// Same as [`CodePatcher::original`].
+    pub fn original_void(&self) -> Option<*const c_void> {
+        self.original().map(|original| original.cast())
+    }
+    /// Gets the offset of each relocated instruction in the original code, paired with the offset of its copy in the trampoline ([`CodePatcher::original`])
+    ///
+    /// Use this to move threads that were suspended partway through the patched bytes to the equivalent instruction in the trampoline.
+    /// Instructions that had to be rewritten into a different sequence when relocating have no equivalent and are left out.
+    /// Empty if the patcher was created with [`CodePatcher::new_replace`].
+    pub fn offset_map(&self) -> &[(u64, u64)] {
+        &self.offset_map
+    }
+    /// Gets the location this patcher patches
+    pub fn location(&self) -> *const u8 {
+        self.location
+    }
+    /// Gets the bytes written to [`CodePatcher::location`] when patching, padding included
+    pub fn patch_bytes(&self) -> &[u8] {
+        &self.patch
+    }
+    /// Gets the code in the trampoline ([`CodePatcher::original`]): the relocated instructions and the jump back, without the rest of its allocation
+    ///
+    /// Empty if the patcher was created with [`CodePatcher::new_replace`].
+    pub fn trampoline_bytes(&self) -> &[u8] {
+        match &self.original {
+            // Safety: the trampoline holds `trampoline_len` bytes of code and is only freed with the patcher
+            Some(original) => unsafe {
+                slice::from_raw_parts(original.exec_ptr(), self.trampoline_len)
+            },
+            None => &[],
+        }
+    }
+    /// Consumes the patcher and deliberately leaks its trampoline, returning a pointer to the original function that stays valid for the rest of the process.
+    ///
+    /// Dropping a patcher frees its trampoline, even if a jump to it (e.g. from a hook whose guard was forgotten) is still installed.
+    /// Use this for permanent shims that have to outlive the patcher. The trampoline's memory is never reclaimed.
+    /// Returns `None` if the patcher was created with [`CodePatcher::new_replace`].
+    pub fn leak_trampoline(self) -> Option<*const u8> {
+        self.original.map(|original| {
+            let trampoline = original.exec_ptr();
+            mem::forget(original);
+            trampoline
+        })
     }
     /// Patches the original location, returning a guard for the patch
     pub fn patch(
@@ -178,26 +764,1110 @@ where
     }
 }
 
-/// Helper functions for an architecture
-pub trait Architecture {
-    /// Gets the maximum instruction length for this architecture
-    fn max_instr_len() -> usize;
-    /// Gets the bitness of this architecture
-    fn bitness() -> u32;
+impl<P> CodePatcher<P, X86_64>
+where
+    P: Patcher,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Creates a new CodePatcher for the function that `location` ultimately jumps to
+    ///
+    /// If `location` is a thunk (e.g. an import stub or incremental linking table entry), every jump is followed and the final target is patched instead.
+    /// This is opt-in since sometimes the thunk itself is the intended patch location. Only 64-bit thunks are recognized, so this isn't available for other architectures.
+    /// See [`follow_thunks`] for which jumps are followed.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// `location` must point to valid executable code. The resolved target must be valid for the length of `patch` + the max architecture
+    pub unsafe fn new_following_thunks<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+    ) -> Result<Self, CodeError<P::Error>> {
+        Self::new(patcher, follow_thunks(location), patch)
+    }
 }
 
-/// x86_64 architecture
-pub struct X86_64;
-impl Architecture for X86_64 {
-    fn max_instr_len() -> usize {
-        16
+impl<P> CodePatcher<P, DynArch>
+where
+    P: Patcher,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Creates a new CodePatcher for an architecture that's only known at runtime
+    ///
+    /// `arch` is only used while decoding and relocating, so it doesn't need to outlive the patcher.
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    ///
+    /// # Safety
+    ///
+    /// `location` must point to valid executable code for `arch`, valid for the length of `patch` + the max architecture
+    pub unsafe fn new_with_arch<B: AsRef<[u8]>>(
+        patcher: P,
+        location: *const u8,
+        patch: B,
+        arch: &dyn ArchRuntime,
+    ) -> Result<Self, CodeError<P::Error>> {
+        let patch = patch.as_ref();
+        validate(
+            readable(location, patch.len(), arch),
+            location as u64,
+            patch,
+            0,
+            arch,
+        )?;
+        Self::decode(patcher, location, location as u64, patch, 0, arch)
+    }
+}
+
+/// Resolves the target of the jump at `offset` in `code`, where `code` is located at `ip`
+///
+/// Handles both relative jumps and RIP-relative indirect jumps whose target is stored within `code`.
+/// Returns `None` if the instruction isn't a jump or its target can't be resolved.
+fn jump_target(bitness: u32, code: &[u8], ip: u64, offset: usize) -> Option<u64> {
+    let mut decoder = Decoder::with_ip(
+        bitness,
+        code.get(offset..)?,
+        ip + offset as u64,
+        DecoderOptions::NONE,
+    );
+    let instruction = decoder.decode();
+
+    if instruction.is_jmp_short_or_near() {
+        return Some(instruction.near_branch_target());
+    }
+    if instruction.is_jmp_near_indirect() && instruction.is_ip_rel_memory_operand() {
+        // `jmp [rip + disp]`, the target is stored in memory
+        let address = instruction.ip_rel_memory_address().checked_sub(ip)? as usize;
+        let target = code.get(address..address + 8)?.try_into().ok()?;
+        return Some(u64::from_le_bytes(target));
+    }
+    None
+}
+
+/// Decodes the instructions at the start of `data` (running at `ip`) that a patch of `patch_size` bytes overwrites
+///
+/// This might cover more than `patch_size` bytes if the patch ends partway through an instruction, but never less unless `data` runs out.
+fn displaced(data: &[u8], ip: u64, patch_size: usize, arch: &dyn ArchRuntime) -> Vec<Instruction> {
+    let decoder = Decoder::with_ip(arch.bitness(), data, ip, DecoderOptions::NONE);
+    let mut size = 0usize;
+    decoder
+        .into_iter()
+        .take_while(|v| {
+            let ret = size < patch_size; // include this instruction if it would go past the end
+            size += v.len();
+            ret
+        })
+        .collect()
+}
+
+/// Reads the target of the absolute jump this crate generates for `bitness` ([`jmp_abs`](crate::code::x64::jmp_abs) or [`x86::jmp_abs`](crate::code::x86::jmp_abs)) at the start of `code`
+fn read_hook_jmp(bitness: u32, code: &[u8]) -> Option<usize> {
+    match bitness {
+        64 => read_jmp_abs(code),
+        32 => x86::read_jmp_abs(code).map(|target| target as usize),
+        _ => None,
+    }
+}
+
+/// Finds the offset of the first instruction in `patch` that's invalid or cut off by the end of the patch
+///
+/// The address after the `jmp [rip]` of a [`jmp_abs`](crate::code::x64::jmp_abs) is data rather than code, so it isn't decoded.
+fn incomplete_instruction(bitness: u32, patch: &[u8]) -> Option<usize> {
+    let start = match read_jmp_abs(patch) {
+        Some(_) if bitness == 64 => JMP_ABS_LEN,
+        _ => 0,
+    };
+    let mut decoder =
+        Decoder::with_ip(bitness, &patch[start..], start as u64, DecoderOptions::NONE);
+    decoder
+        .iter()
+        .find(|instruction| instruction.is_invalid())
+        .map(|instruction| instruction.ip() as usize)
+}
+
+/// Gets the bytes at `location` that may be read while decoding whole instructions covering `len` bytes
+///
+/// # Safety
+///
+/// `location` must be valid for `len` + the architecture's max instruction length
+unsafe fn readable<'a>(location: *const u8, len: usize, arch: &dyn ArchRuntime) -> &'a [u8] {
+    slice::from_raw_parts(location, len + arch.max_instr_len())
+}
+
+/// Checks that the code in `data` (running at `ip`) can safely be patched with `patch`, relocating at least `min_len` bytes
+///
+/// These are the checks [`CodePatcher::new_unchecked`] skips.
+fn validate<E>(
+    data: &[u8],
+    ip: u64,
+    patch: &[u8],
+    min_len: usize,
+    arch: &dyn ArchRuntime,
+) -> Result<(), CodeError<E>> {
+    let location = data.as_ptr();
+
+    // Relocating one of our own jumps would hook the existing hook instead of the original code
+    if let Some(target) = read_hook_jmp(arch.bitness(), data) {
+        return Err(CodeError::AlreadyHooked(target as _));
+    }
+
+    let instructions = displaced(data, ip, patch.len().max(min_len), arch);
+    let size = instructions.iter().fold(0, |c, i| c + i.len());
+
+    // Only an error for `new_checked`, since a patch doesn't have to be code
+    if let Some(offset) = incomplete_instruction(arch.bitness(), patch) {
+        log::warn!(
+            "patch for {location:?} ends partway through the instruction at offset {offset}"
+        );
+    }
+
+    // A patch that jumps into the patched bytes would loop forever (or run part of the patch) on the first call
+    let patched = ip..ip + size as u64;
+    if let Some(target) = jump_target(arch.bitness(), patch, ip, 0) {
+        if patched.contains(&target) {
+            return Err(CodeError::DegenerateJump(target as _));
+        }
+    }
+
+    // Immediates aren't fixed up, so a pointer into the patched bytes would point at the patch rather than the trampoline
+    if let Some(instruction) = instructions.iter().find(|instruction| {
+        let immediate = match instruction.code() {
+            Code::Mov_r64_imm64 => instruction.immediate64(),
+            Code::Mov_r32_imm32 if arch.bitness() == 32 => instruction.immediate32() as u64,
+            _ => return false,
+        };
+        patched.contains(&immediate)
+    }) {
+        return Err(CodeError::AbsoluteReference(instruction.ip() as _));
+    }
+    Ok(())
+}
+
+/// Finds the first `int3` in `instructions` that runs as part of the function, returning its address
+///
+/// A debugger's breakpoint replaces the first byte of an instruction, so it decodes as an `int3` of its own.
+/// Anything after the first `ret` or unconditional `jmp` is treated as padding rather than part of the function.
+fn breakpoint(instructions: &[Instruction]) -> Option<u64> {
+    instructions
+        .iter()
+        .take_while(|instruction| {
+            !matches!(
+                instruction.flow_control(),
+                FlowControl::Return
+                    | FlowControl::UnconditionalBranch
+                    | FlowControl::IndirectBranch
+            )
+        })
+        .find(|instruction| instruction.code() == Code::Int3)
+        .map(|instruction| instruction.ip())
+}
+
+/// Gets the length of `instructions` once relocated away from `ip`, where relative instructions that only reached from `ip` have been widened
+///
+/// Returns `None` if they can't be encoded there.
+fn scratch_len(instructions: &[Instruction], ip: u64, arch: &dyn ArchRuntime) -> Option<usize> {
+    // Out of rel8 range of the original code, but close enough that everything rel32 can reach stays in range
+    let scratch = ip
+        .checked_sub(SCRATCH_OFFSET)
+        .unwrap_or(ip + SCRATCH_OFFSET);
+    let block = InstructionBlock::new(instructions, scratch);
+    BlockEncoder::encode(arch.bitness(), block, BlockEncoderOptions::NONE)
+        .ok()
+        .map(|encoded| encoded.code_buffer.len())
+}
+
+/// Formats the instructions in `code`, running at `ip`, in Intel syntax separated by `; `
+fn disassemble(bitness: u32, code: &[u8], ip: u64) -> String {
+    let mut formatter = IntelFormatter::new();
+    Decoder::with_ip(bitness, code, ip, DecoderOptions::NONE)
+        .iter()
+        .map(|instruction| {
+            let mut text = String::new();
+            formatter.format(&instruction, &mut text);
+            text
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Allocates a trampoline with `allocate` and encodes `instructions` into it, ending with a back-jump to `resume`
+///
+/// `allocate` is called with the size of the trampoline and how far from the original code it may be.
+/// The allocator only tries to stay close, so an allocation near the edge of its range can leave displacements that don't fit.
+/// Rather than failing outright, memory that's closer is requested and the instructions are encoded again, up to [`RELOCATION_ATTEMPTS`] times.
+/// `report` describes a trampoline that turned out too small on the last attempt.
+fn allocate_trampoline<E>(
+    instructions: &[Instruction],
+    mut trampoline_size: usize,
+    resume: usize,
+    arch: &dyn ArchRuntime,
+    mut allocate: impl FnMut(usize, usize) -> Result<ExecutableMemory, ProximityError>,
+    report: impl FnOnce(&ExecutableMemory, usize) -> RelocationReport,
+) -> Result<(ExecutableMemory, BlockEncoderResult), CodeError<E>> {
+    let mut max_distance = DETOUR_RANGE;
+    let mut attempt = 1;
+    loop {
+        let original = allocate(trampoline_size, max_distance)?;
+        let retry = attempt < RELOCATION_ATTEMPTS;
+        attempt += 1;
+        match encode_trampoline(instructions, &original, resume, arch) {
+            // The scratch encoding can still be off, e.g. when a branch target happens to be close to the scratch address
+            Ok(encoded) if encoded.code_buffer.len() > original.len() => {
+                if !retry {
+                    // This is a bug. Check [CodeError::BufferTooSmall] for what info to include in your issue
+                    let needed = encoded.code_buffer.len();
+                    return Err(CodeError::BufferTooSmall(report(&original, needed)));
+                }
+                log::debug!(
+                    "trampoline at {:?} needs {} bytes rather than {}, retrying",
+                    original.exec_ptr(),
+                    encoded.code_buffer.len(),
+                    original.len()
+                );
+                trampoline_size = encoded.code_buffer.len();
+            }
+            Ok(encoded) => return Ok((original, encoded)),
+            Err(_) if retry => {
+                log::debug!(
+                    "encoding trampoline at {:?} failed, retrying closer",
+                    original.exec_ptr()
+                );
+                max_distance /= RELOCATION_DISTANCE_DIVISOR;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Encodes `instructions` into a trampoline at `trampoline`, ending with a back-jump to `resume`
+fn encode_trampoline<E>(
+    instructions: &[Instruction],
+    trampoline: &ExecutableMemory,
+    resume: usize,
+    arch: &dyn ArchRuntime,
+) -> Result<BlockEncoderResult, CodeError<E>> {
+    // Make sure the back-jump can actually reach rather than leaving it up to the encoder
+    if arch.bitness() == 64 && !rel32_reaches(trampoline.range(), resume) {
+        return Err(CodeError::BackJumpOutOfRange(
+            trampoline.exec_ptr() as _,
+            resume as _,
+        ));
     }
-    fn bitness() -> u32 {
-        64
+
+    // Create a block for the new location
+    let block = InstructionBlock::new(instructions, trampoline.addr() as u64);
+
+    // This is where the magic happens. [`BlockEncoder`] re-encodes the instructions for the new location and fixes up all the relative instructions
+    // BlockEncoder requires a buffer be allocated *close* to where the original data came from, and our [`allocate_executable_within`] function handles that.
+    Ok(BlockEncoder::encode(
+        arch.bitness(),
+        block,
+        BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+    )?)
+}
+
+/// Checks that a `jmp rel32` located anywhere in `code` can reach `target`
+fn rel32_reaches(code: Range<usize>, target: usize) -> bool {
+    within_rel32(code.start, target) && within_rel32(code.end - JMP_REL32_LEN, target)
+}
+
+/// Adapts a compile-time [`Architecture`] to [`ArchRuntime`]
+struct StaticArch<A>(PhantomData<A>);
+impl<A> Default for StaticArch<A> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+impl<A: Architecture> ArchRuntime for StaticArch<A> {
+    fn max_instr_len(&self) -> usize {
+        A::max_instr_len()
+    }
+    fn bitness(&self) -> u32 {
+        A::bitness()
+    }
+    fn abs_jmp_len(&self) -> usize {
+        A::abs_jmp_len()
+    }
+    fn entry_marker(&self) -> Option<Instruction> {
+        A::entry_marker()
     }
 }
 
 /// Patcher for patching x86_64 code
 pub type X64Patcher = CodePatcher<BytePatcher, X86_64>;
+/// Patcher for patching 32-bit x86 code
+pub type X86Patcher = CodePatcher<BytePatcher, X86>;
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, slice};
+
+    use iced_x86::{Code, Decoder, DecoderOptions, Instruction};
+    use region::Protection;
+
+    use crate::alloc::search::free_regions;
+    use crate::alloc::{allocate_executable_within, ThreadAllocator, DETOUR_RANGE};
+    use crate::code::x64::{jmp_abs, needs_endbr64, ENDBR64};
+    use crate::code::x86;
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::code::{
+        allocate_trampoline, rel32_reaches, scratch_len, CodeError, CodePatcher, DynArch, Fill,
+        RelocationReport, StaticArch, X64Patcher, X86Patcher, RELOCATION_DISTANCE_DIVISOR, X86_64,
+    };
+    use crate::patcher::PatchGuard;
+    use crate::test_util::TestFn;
+
+    /// Gets the length of the branch target marker at the start of trampolines
+    fn entry_len() -> usize {
+        if needs_endbr64() {
+            ENDBR64.len()
+        } else {
+            0
+        }
+    }
+
+    #[test]
+    /// Tests the back-jump reachability check at the edges of the rel32 range
+    fn test_rel32_reaches() {
+        let target = 0x1_0000_0000usize;
+        let max = i32::MAX as usize;
+        let min = 0x8000_0000usize;
+
+        assert!(rel32_reaches(target - max - 5..target, target));
+        assert!(!rel32_reaches(target - max - 6..target, target));
+        assert!(rel32_reaches(target..target + min, target));
+        assert!(!rel32_reaches(target..target + min + 1, target));
+
+        // the whole trampoline has to be in range, not just its start
+        assert!(!rel32_reaches(
+            target + min - 0x10..target + min + 0x10,
+            target
+        ));
+    }
+
+    #[test]
+    /// Tests that short branches are sized as the rel32 branches they become in the trampoline
+    fn test_widened_branch() {
+        let mut code = vec![0x74, 0x10]; // je +0x10
+        code.resize(32, 0x90);
+        let location = code.as_ptr();
+        let ip = location as u64;
+
+        let je = Instruction::with_branch(Code::Je_rel8_64, ip + 0x12).unwrap();
+        assert_eq!(scratch_len(&[je], ip, &DynArch::X86_64), Some(6));
+
+        let patcher = unsafe { X64Patcher::new(BytePatcher::new(), location, jmp_abs(0)) }.unwrap();
+        let original = patcher.original().unwrap() as u64 + entry_len() as u64;
+        let trampoline = unsafe { slice::from_raw_parts(original as *const u8, 6) };
+        let branch = Decoder::with_ip(64, trampoline, original, DecoderOptions::NONE).decode();
+        assert_eq!(branch.code(), Code::Je_rel32_64);
+        assert_eq!(branch.near_branch_target(), ip + 0x12);
+    }
+
+    #[test]
+    /// Tests that a replace patcher writes only the patch and has no trampoline
+    fn test_replace() {
+        let vec = vec![0x55u8, 0x48, 0x89, 0xe5, 0xc3];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher =
+            unsafe { X64Patcher::new_replace(BytePatcher::new(), ptr, [0xcc, 0xcc]) }.unwrap();
+
+        // no trampoline should have been created
+        assert!(patcher.original().is_none());
+        assert!(patcher.original_void().is_none());
+        assert!(patcher.trampoline_bytes().is_empty());
+        assert_eq!(
+            patcher.describe_install(),
+            format!(
+                "hooked {:#x} → 2-byte patch (int3; int3), no trampoline",
+                ptr as usize
+            )
+        );
+
+        // patch the vec's data
+        let patch = patcher.patch().unwrap();
+
+        // make sure only the patch bytes were changed
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0xcc, 0xcc, 0x89, 0xe5, 0xc3]
+        );
+
+        // restore the patch
+        patch.restore();
+
+        // make sure the patch was restored
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0x55, 0x48, 0x89, 0xe5, 0xc3]
+        );
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests describing a hook's relocated code, patch and trampoline
+    fn test_describe_install() {
+        let mut vec = vec![0x55u8, 0x48, 0x89, 0xe5];
+        vec.resize(32, 0x90);
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = unsafe { X64Patcher::new(BytePatcher::new(), ptr, jmp_abs(0x1234)) }.unwrap();
+        let trampoline = patcher.original().unwrap() as usize;
+        let marker = if needs_endbr64() { "endbr64; " } else { "" };
+        let nops = ["nop"; 10].join("; ");
+        assert_eq!(
+            patcher.describe_install(),
+            format!(
+                "hooked {:#x} (push rbp; mov rbp,rsp; {nops}) → 0x1234 via 14-byte abs jmp, trampoline at {trampoline:#x} ({marker}push rbp; mov rbp,rsp; {nops}; jmp {:016X}h)",
+                ptr as usize,
+                ptr as usize + 14
+            )
+        );
+
+        // clean up
+        drop(patcher);
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that hooking a location that's already hooked by this library is rejected
+    fn test_already_hooked() {
+        // existing hook followed by enough padding to decode a full patch
+        let mut code = jmp_abs(0x1234).to_vec();
+        code.resize(64, 0x90);
+
+        let result = unsafe { X64Patcher::new(BytePatcher::new(), code.as_ptr(), jmp_abs(0)) };
+
+        assert!(
+            matches!(result, Err(CodeError::AlreadyHooked(target)) if target as usize == 0x1234)
+        );
+
+        // forcing the patch relocates the existing hook instead
+        let patcher =
+            unsafe { X64Patcher::new_unchecked(BytePatcher::new(), code.as_ptr(), jmp_abs(0)) };
+        assert!(patcher.unwrap().original().is_some());
+    }
+
+    #[test]
+    /// Tests that 32-bit hooks are recognized as this library's hooks in 32-bit code
+    fn test_already_hooked_x86() {
+        let mut code = x86::jmp_abs(0x1234).to_vec();
+        code.resize(64, 0x90);
+
+        let result = unsafe { X86Patcher::new(BytePatcher::new(), code.as_ptr(), x86::jmp_abs(0)) };
+        assert!(
+            matches!(result, Err(CodeError::AlreadyHooked(target)) if target as usize == 0x1234)
+        );
+    }
+
+    #[test]
+    /// Tests that the trampoline ends with a jump back to the first instruction after the patched block
+    fn test_back_jump() {
+        let code = [
+            0x55, // push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0x89, 0x7d, 0xfc, // mov [rbp-4], edi
+            0x8b, 0x45, 0xfc, // mov eax, [rbp-4]
+            0x01, 0xc0, // add eax, eax
+            0xc9, // leave
+            0xc3, // ret
+            0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
+        ];
+        let location = code.as_ptr();
+
+        // a 14-byte patch covers exactly the first 5 instructions
+        let patcher = unsafe { X64Patcher::new(BytePatcher::new(), location, jmp_abs(0)) }.unwrap();
+        let original = patcher.original().unwrap();
+
+        let trampoline = unsafe { slice::from_raw_parts(original, 64) };
+        let (entry, trampoline) = trampoline.split_at(entry_len());
+        assert!(entry.is_empty() || entry == ENDBR64);
+
+        let ip = original as u64 + entry.len() as u64;
+        let mut decoder = Decoder::with_ip(64, trampoline, ip, DecoderOptions::NONE);
+        let instructions: Vec<_> = decoder.iter().take(6).collect();
+
+        // relocated instructions are copied as-is
+        assert_eq!(&trampoline[..14], &code[..14]);
+        let entry = entry.len() as u64;
+        assert_eq!(
+            patcher.offset_map(),
+            [0, 1, 4, 8, 11].map(|offset| (offset, entry + offset))
+        );
+
+        // followed by the jump back to `add eax, eax`
+        let back_jump = instructions[5];
+        assert!(back_jump.is_jmp_short_or_near());
+        assert_eq!(back_jump.near_branch_target(), location as u64 + 14);
+        assert_eq!(
+            patcher.trampoline_bytes().len(),
+            entry as usize + back_jump.next_ip() as usize - ip as usize
+        );
+    }
 
-// TODO: figure out how to test this
+    #[test]
+    /// Tests that a trampoline allocated out of range of its back-jump is allocated again, closer
+    fn test_back_jump_retry() {
+        let resume = test_back_jump_retry as *const () as usize;
+        let instructions = [Instruction::with_branch(Code::Jmp_rel32_64, resume as u64).unwrap()];
+
+        // a separate allocator whose memory is always further away than a rel32 can reach
+        let far = ThreadAllocator::new(0x4000_0000);
+        let far_origin = (resume + 0x1_0000_0000) & !(region::page::size() - 1);
+        let mut distances = Vec::new();
+        let (trampoline, _) = allocate_trampoline::<()>(
+            &instructions,
+            16,
+            resume,
+            &StaticArch::<X86_64>::default(),
+            |size, max_distance| {
+                distances.push(max_distance);
+                if distances.len() == 1 {
+                    far.allocate(far_origin, size)
+                } else {
+                    allocate_executable_within(resume, size, max_distance)
+                }
+            },
+            |_, _| unreachable!("the trampoline is large enough"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            distances,
+            [DETOUR_RANGE, DETOUR_RANGE / RELOCATION_DISTANCE_DIVISOR]
+        );
+        assert!(rel32_reaches(trampoline.range(), resume));
+    }
+
+    #[test]
+    /// Tests that code read from one address is relocated for the address it runs at
+    fn test_new_with_ip() {
+        let mut code = vec![0x48, 0x8d, 0x05, 0x00, 0x01, 0x00, 0x00]; // lea rax, [rip+0x100]
+        code.extend([0x90; 7]);
+        code.push(0xc3); // ret
+        code.resize(32, 0xcc);
+        let location = code.as_ptr();
+
+        // somewhere else that's mapped, so the trampoline can be allocated close to it
+        let runtime = [0xccu8; 32];
+        let ip = runtime.as_ptr() as u64;
+
+        let patcher =
+            unsafe { X64Patcher::new_with_ip(BytePatcher::new(), location, ip, jmp_abs(0)) }
+                .unwrap();
+        assert_eq!(patcher.location(), location);
+        let original = patcher.original().unwrap();
+
+        let trampoline = unsafe { slice::from_raw_parts(original, 64) };
+        let trampoline = &trampoline[entry_len()..];
+        let trampoline_ip = original as u64 + entry_len() as u64;
+        let mut decoder = Decoder::with_ip(64, trampoline, trampoline_ip, DecoderOptions::NONE);
+        let instructions: Vec<_> = decoder.iter().take(9).collect();
+
+        // the lea still loads the address relative to where the code runs
+        assert_eq!(instructions[0].ip_rel_memory_address(), ip + 7 + 0x100);
+
+        // and the back-jump resumes at the `ret` there
+        assert!(instructions[8].is_jmp_short_or_near());
+        assert_eq!(instructions[8].near_branch_target(), ip + 14);
+    }
+
+    #[test]
+    /// Tests that padding from a minimum length is NOP-filled and the back-jump skips over it
+    fn test_min_len_resume() {
+        let mut code = vec![
+            0x55, // push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0x89, 0x7d, 0xfc, // mov [rbp-4], edi
+            0x8b, 0x45, 0xfc, // mov eax, [rbp-4]
+            0x01, 0xc0, // add eax, eax
+            0xc9, // leave
+            0xc3, // ret
+        ];
+        code.resize(48, 0xcc);
+        let (ptr, size, capacity) = code.into_raw_parts();
+
+        // 15 bytes ends inside `add eax, eax`, so the first 6 instructions are relocated
+        let patcher =
+            unsafe { X64Patcher::new_with_min_len(BytePatcher::new(), ptr, jmp_abs(0), 15) }
+                .unwrap();
+        let original = patcher.original().unwrap();
+
+        let trampoline = unsafe { slice::from_raw_parts(original, 64) };
+        let ip = original as u64 + entry_len() as u64;
+        let mut decoder =
+            Decoder::with_ip(64, &trampoline[entry_len()..], ip, DecoderOptions::NONE);
+        let back_jump = decoder.iter().nth(6).unwrap();
+
+        // execution resumes at `leave`, past both the patch and its padding
+        assert!(back_jump.is_jmp_short_or_near());
+        assert_eq!(back_jump.near_branch_target(), ptr as u64 + 16);
+
+        let patch = patcher.patch().unwrap();
+        assert_eq!(patch.len(), 16);
+        let patched = unsafe { slice::from_raw_parts(ptr, 18) };
+        assert_eq!(&patched[..14], jmp_abs(0));
+        assert_eq!(&patched[14..], [0x90, 0x90, 0xc9, 0xc3]);
+
+        // clean up
+        patch.restore();
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that checked patchers reject patches that end partway through an instruction
+    fn test_incomplete_patch() {
+        let mut code = vec![0x90u8; 16];
+        code.push(0xc3);
+        let (ptr, size, capacity) = code.into_raw_parts();
+
+        // mov eax, imm32 with only 3 bytes of its immediate
+        let result = unsafe {
+            X64Patcher::new_checked(BytePatcher::new(), ptr, [0x90, 0xb8, 0x01, 0x02, 0x03])
+        };
+        assert!(matches!(result, Err(CodeError::IncompletePatch(1))));
+
+        // complete instructions are fine
+        unsafe { X64Patcher::new_checked(BytePatcher::new(), ptr, jmp_abs(0)) }.unwrap();
+
+        // the address after an absolute jump is data, however it happens to decode
+        let function = test_incomplete_patch as *const () as usize;
+        unsafe { X64Patcher::new_checked(BytePatcher::new(), ptr, jmp_abs(function)) }.unwrap();
+        for target in [0x4011a0, 0x7f12_3456_7890] {
+            unsafe { X64Patcher::new_checked(BytePatcher::new(), ptr, jmp_abs(target)) }.unwrap();
+        }
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that the padding after the patch follows the fill strategy
+    fn test_fill() {
+        let mut code = vec![0x90u8; 12];
+        code.extend([0x48, 0x83, 0xec, 0x20]); // sub rsp, 0x20
+        code.extend([0x90; 4]);
+        code.push(0xc3); // ret
+        code.resize(48, 0xcc);
+        let (ptr, size, capacity) = code.into_raw_parts();
+
+        // the sub is relocated, leaving 2 bytes of padding after the 14-byte jump
+        let new = || unsafe { X64Patcher::new(BytePatcher::new(), ptr, jmp_abs(0)) }.unwrap();
+        for (fill, padding) in [
+            (Fill::Nop, [0x90, 0x90]),
+            (Fill::MultiByteNop, [0x66, 0x90]),
+            (Fill::Byte(0xcc), [0xcc, 0xcc]),
+        ] {
+            let patcher = new().with_fill(fill);
+            assert_eq!(patcher.location(), ptr as *const u8);
+            assert_eq!(patcher.patch_bytes()[14..], padding);
+            let patch = patcher.patch().unwrap();
+            assert_eq!(patch.len(), 16);
+            assert_eq!(unsafe { slice::from_raw_parts(ptr.add(14), 2) }, padding);
+            patch.restore();
+        }
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that the runtime architecture's bitness decides how the target is decoded
+    fn test_dyn_arch() {
+        // 32-bit code jumps with rel32, so it has to live (and be relocated) in the low 4 GiB
+        let page_size = region::page::size();
+        let free = free_regions(0x1000_0000..0x8000_0000)
+            .unwrap()
+            .into_iter()
+            .find(|free| free.len() >= page_size)
+            .unwrap();
+        let memory =
+            region::alloc_at(free.start as *const u8, page_size, Protection::READ_WRITE).unwrap();
+        let location = memory.as_ptr::<u8>() as *mut u8;
+
+        // 32-bit: inc eax; inc eax; inc eax; mov ebp, esp; ret
+        // 64-bit: mov ebp, esp (with 3 REX prefixes); ret
+        let code = [0x40, 0x40, 0x40, 0x89, 0xe5, 0xc3];
+        unsafe {
+            location.copy_from(code.as_ptr(), code.len());
+            location.add(code.len()).write_bytes(0xcc, 32);
+        }
+
+        let patcher = unsafe {
+            CodePatcher::new_with_arch(BytePatcher::new(), location, [0xcc, 0xcc], &DynArch::X86)
+        }
+        .unwrap();
+        assert_eq!(patcher.patch().unwrap().len(), 2);
+
+        // the trampoline is 32-bit code too
+        let original = patcher.original().unwrap();
+        let trampoline = unsafe { slice::from_raw_parts(original, 32) };
+        let mut decoder = Decoder::with_ip(32, trampoline, original as u64, DecoderOptions::NONE);
+        let mut instructions = decoder.iter().skip_while(|i| i.code() == Code::Endbr32);
+        assert_eq!(instructions.next().unwrap().code(), Code::Inc_r32);
+        assert_eq!(instructions.next().unwrap().code(), Code::Inc_r32);
+        let back_jump = instructions.next().unwrap();
+        assert_eq!(back_jump.code(), Code::Jmp_rel32_32);
+        assert_eq!(back_jump.near_branch_target(), location as u64 + 2);
+
+        let patcher = unsafe {
+            CodePatcher::new_with_arch(BytePatcher::new(), location, [0xcc, 0xcc], &DynArch::X86_64)
+        }
+        .unwrap();
+        assert_eq!(patcher.patch().unwrap().len(), 5);
+    }
+
+    #[test]
+    /// Tests relocating a 32-bit prologue with a 32-bit absolute jump
+    fn test_x86() {
+        // 32-bit code jumps with rel32, so it has to live (and be relocated) in the low 4 GiB
+        let page_size = region::page::size();
+        let free = free_regions(0x1000_0000..0x8000_0000)
+            .unwrap()
+            .into_iter()
+            .find(|free| free.len() >= page_size)
+            .unwrap();
+        let memory =
+            region::alloc_at(free.start as *const u8, page_size, Protection::READ_WRITE).unwrap();
+        let location = memory.as_ptr::<u8>() as *mut u8;
+
+        let code = [
+            0x55, // push ebp
+            0x89, 0xe5, // mov ebp, esp
+            0x83, 0xec, 0x20, // sub esp, 0x20
+            0x8b, 0x45, 0x08, // mov eax, [ebp + 8]
+            0xc9, // leave
+            0xc3, // ret
+        ];
+        unsafe {
+            location.copy_from(code.as_ptr(), code.len());
+            location.add(code.len()).write_bytes(0x90, 32);
+        }
+
+        // the 6-byte jump covers exactly the first 3 instructions
+        let patch = x86::jmp_abs(0x1234_5678);
+        let patcher = unsafe { X86Patcher::new(BytePatcher::new(), location, patch) }.unwrap();
+        assert_eq!(patcher.patch_bytes(), patch);
+
+        // the trampoline re-encodes them as 32-bit code and jumps back to `mov eax, [ebp + 8]`
+        let original = patcher.original().unwrap();
+        let trampoline = patcher.trampoline_bytes();
+        let mut decoder = Decoder::with_ip(32, trampoline, original as u64, DecoderOptions::NONE);
+        let instructions: Vec<_> = decoder
+            .iter()
+            .skip_while(|i| i.code() == Code::Endbr32)
+            .collect();
+        assert_eq!(
+            instructions.iter().map(|i| i.code()).collect::<Vec<_>>(),
+            [
+                Code::Push_r32,
+                Code::Mov_rm32_r32,
+                Code::Sub_rm32_imm8,
+                Code::Jmp_rel32_32
+            ]
+        );
+        assert_eq!(instructions[3].near_branch_target(), location as u64 + 6);
+
+        let guard = patcher.patch().unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(location, 6) }, patch);
+        guard.restore();
+        assert_eq!(unsafe { slice::from_raw_parts(location, code.len()) }, code);
+    }
+
+    #[test]
+    /// Tests that the relocation report is included in the error message
+    fn test_relocation_report() {
+        let error = CodeError::<String>::BufferTooSmall(RelocationReport {
+            location: 0x1000 as _,
+            trampoline: 0x2000 as _,
+            allocated: 16,
+            needed: 20,
+            original: vec![0x55, 0x48, 0x89, 0xe5],
+            instructions: 2,
+            patch: vec![0xcc],
+        });
+
+        assert_eq!(
+            error.to_string(),
+            "Buffer size was too small (allocated: 16, needed: 20, location: 0x1000, trampoline: 0x2000, instructions: 2, original: [55 48 89 e5], patch: [cc])"
+        );
+    }
+
+    #[test]
+    /// Tests that loading a pointer into the patched bytes is rejected, but pointers past them are allowed
+    fn test_absolute_reference() {
+        let mut code = [0x90u8; 32];
+        // Note: the code is read through a fresh pointer after every write, since the array is written to directly
+        let location = code.as_ptr();
+
+        // mov rax, location + 12
+        code[..2].copy_from_slice(&[0x48, 0xb8]);
+        code[2..10].copy_from_slice(&(location as u64 + 12).to_le_bytes());
+        let result = unsafe { X64Patcher::new(BytePatcher::new(), code.as_ptr(), jmp_abs(0)) };
+        assert!(matches!(result, Err(CodeError::AbsoluteReference(ip)) if ip == location as _));
+
+        // mov rax, location + 14 points at the first byte that isn't patched
+        code[2..10].copy_from_slice(&(location as u64 + 14).to_le_bytes());
+        assert!(unsafe { X64Patcher::new(BytePatcher::new(), code.as_ptr(), jmp_abs(0)) }.is_ok());
+    }
+
+    #[test]
+    /// Tests that a breakpoint in the relocated code is only rejected when asked to, and that `int3` padding isn't mistaken for one
+    fn test_breakpoint() {
+        let mut code = [0x90u8; 32];
+        // Note: the code is read through a fresh pointer after every write, since the array is written to directly
+        let location = code.as_ptr();
+
+        // a debugger's breakpoint over the first byte of `mov rbp, rsp`
+        code[1..4].copy_from_slice(&[0xcc, 0x89, 0xe5]);
+        let result = unsafe {
+            X64Patcher::new_rejecting_breakpoints(BytePatcher::new(), code.as_ptr(), jmp_abs(0))
+        };
+        assert!(
+            matches!(result, Err(CodeError::Breakpoint(ip)) if ip as usize == location as usize + 1)
+        );
+        assert!(unsafe { X64Patcher::new(BytePatcher::new(), code.as_ptr(), jmp_abs(0)) }.is_ok());
+
+        // breakpoints after the relocated code aren't saved, so they don't matter
+        code[1..4].fill(0x90);
+        code[20] = 0xcc;
+        assert!(unsafe {
+            X64Patcher::new_rejecting_breakpoints(BytePatcher::new(), code.as_ptr(), jmp_abs(0))
+        }
+        .is_ok());
+
+        // a short function followed by `int3` padding (xor eax, eax; ret)
+        code.fill(0xcc);
+        code[..3].copy_from_slice(&[0x31, 0xc0, 0xc3]);
+        assert!(unsafe {
+            X64Patcher::new_rejecting_breakpoints(BytePatcher::new(), code.as_ptr(), jmp_abs(0))
+        }
+        .is_ok());
+    }
+
+    #[test]
+    /// Tests that segment-override prefixes (e.g. stack canary loads) are relocated byte-for-byte
+    fn test_segment_override() {
+        for prefix in [0x64, 0x65] {
+            // mov rax, fs/gs:[0x28]
+            let mut code = vec![prefix, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00];
+            code.extend([0x48, 0x89, 0x44, 0x24, 0x08]); // mov [rsp+8], rax
+            code.extend([0x31, 0xc0]); // xor eax, eax
+            code.push(0xc3); // ret
+            code.resize(32, 0xcc);
+            let location = code.as_ptr();
+
+            // a 14-byte patch covers the canary load and store
+            let patcher =
+                unsafe { X64Patcher::new(BytePatcher::new(), location, jmp_abs(0)) }.unwrap();
+            let original = patcher.original().unwrap();
+
+            let trampoline = unsafe { slice::from_raw_parts(original, 64) };
+            let trampoline = &trampoline[entry_len()..];
+            let ip = original as u64 + entry_len() as u64;
+            let mut decoder = Decoder::with_ip(64, trampoline, ip, DecoderOptions::NONE);
+            let instructions: Vec<_> = decoder.iter().take(3).collect();
+
+            // the prefix and absolute displacement must be kept as-is
+            assert_eq!(&trampoline[..14], &code[..14]);
+            assert!(!instructions[0].is_ip_rel_memory_operand());
+
+            // followed by the jump back to `xor eax, eax`
+            assert!(instructions[2].is_jmp_short_or_near());
+            assert_eq!(instructions[2].near_branch_target(), location as u64 + 14);
+        }
+    }
+
+    #[test]
+    /// Tests that a patch jumping into the patched bytes is rejected
+    fn test_degenerate_jump() {
+        let mut code = vec![0x90u8; 48];
+        let location = code.as_mut_ptr();
+
+        // `jmp $` and an absolute jump into the nops it replaces
+        let patches = [vec![0xeb, 0xfe], jmp_abs(location as usize + 4).to_vec()];
+        for patch in patches {
+            let result = unsafe { X64Patcher::new(BytePatcher::new(), location, patch) };
+            assert!(matches!(result, Err(CodeError::DegenerateJump(_))));
+        }
+
+        // jumping past the patched bytes is fine
+        let patch = jmp_abs(location as usize + 14);
+        assert!(unsafe { X64Patcher::new(BytePatcher::new(), location, patch) }.is_ok());
+    }
+
+    #[test]
+    /// Tests listing the instructions a patch would displace without creating a patcher
+    fn test_displaced_instructions() {
+        let mut code = vec![
+            0x55, // push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0xc3, // ret
+        ];
+        code.resize(32, 0xcc);
+
+        // a 5-byte patch ends partway through `sub rsp, 0x20`, so it's displaced too
+        let instructions =
+            unsafe { X64Patcher::displaced_instructions(code.as_ptr(), 5, &DynArch::X86_64) }
+                .unwrap();
+        assert_eq!(
+            instructions.iter().map(|i| i.code()).collect::<Vec<_>>(),
+            [Code::Push_r64, Code::Mov_rm64_r64, Code::Sub_rm64_imm8]
+        );
+        assert_eq!(instructions[2].ip(), code.as_ptr() as u64 + 4);
+
+        // `push es` doesn't exist in 64-bit code
+        code[1] = 0x06;
+        let result =
+            unsafe { X64Patcher::displaced_instructions(code.as_ptr(), 5, &DynArch::X86_64) };
+        assert!(
+            matches!(result, Err(CodeError::InvalidRelocation(ip)) if ip as usize == code.as_ptr() as usize + 1)
+        );
+    }
+
+    #[test]
+    /// Tests relocating a known length without searching for the instruction boundary
+    fn test_new_with_len() {
+        let code = [
+            0x55, // push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x83, 0xec, 0x20, // sub rsp, 0x20
+            0xc9, // leave
+            0xc3, // ret
+        ];
+        let location = code.as_ptr();
+
+        // relocate more than the patch needs
+        let patcher =
+            unsafe { X64Patcher::new_with_len(BytePatcher::new(), location, [0xcc], 8) }.unwrap();
+        let original = patcher.original().unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(original.add(entry_len()), 8) },
+            &code[..8]
+        );
+
+        // the relocation must cover the patch
+        let result =
+            unsafe { X64Patcher::new_with_len(BytePatcher::new(), location, [0xcc; 4], 3) };
+        assert!(matches!(result, Err(CodeError::PatchTooLarge(4, 3))));
+
+        // and end on an instruction boundary
+        let result = unsafe { X64Patcher::new_with_len(BytePatcher::new(), location, [0xcc], 3) };
+        assert!(
+            matches!(result, Err(CodeError::InvalidRelocation(ip)) if ip as usize == location as usize + 1)
+        );
+    }
+
+    #[test]
+    /// Tests that a patch longer than the instruction it lands on relocates the following instructions too
+    fn test_patch_longer_than_instruction() {
+        let vec = vec![
+            0x55, // push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0xc3, // ret
+            0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
+            0xcc, 0xcc, 0xcc,
+        ];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        // the 2-byte patch covers `push rbp` and part of `mov rbp, rsp`
+        let patcher = unsafe { X64Patcher::new(BytePatcher::new(), ptr, [0xcc, 0xcc]) }.unwrap();
+
+        // both instructions should have been relocated
+        let original = patcher.original().unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(original.add(entry_len()), 4) },
+            [0x55, 0x48, 0x89, 0xe5]
+        );
+
+        // patch the vec's data
+        let patch = patcher.patch().unwrap();
+
+        // the rest of the covered instruction should be filled with nops
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, 5) },
+            [0xcc, 0xcc, 0x90, 0x90, 0xc3]
+        );
+
+        // restore the patch
+        patch.restore();
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that a relocated prologue with an aligned SIMD spill runs without faulting
+    fn test_aligned_prologue() {
+        let function = TestFn::<extern "C" fn() -> u32>::new(&[
+            0x48, 0x83, 0xec, 0x18, // sub rsp, 0x18
+            0x0f, 0x29, 0x04, 0x24, // movaps [rsp], xmm0
+            0xb8, 0x2a, 0x00, 0x00, 0x00, // mov eax, 42
+            0x48, 0x83, 0xc4, 0x18, // add rsp, 0x18
+            0xc3, // ret
+        ]);
+
+        let patcher =
+            unsafe { X64Patcher::new(BytePatcher::new(), function.ptr() as _, jmp_abs(0)) }
+                .unwrap();
+        assert_eq!(
+            patcher.original_void(),
+            patcher.original().map(|original| original.cast())
+        );
+        let original: extern "C" fn() -> u32 =
+            unsafe { mem::transmute(patcher.original_void().unwrap()) };
+
+        // `movaps` faults if the trampoline misaligned the stack
+        assert_eq!(original(), 42);
+    }
+
+    #[test]
+    /// Tests that a leaked trampoline stays callable after the patcher is dropped
+    fn test_leak_trampoline() {
+        let function = TestFn::<extern "C" fn() -> u32>::new(&[
+            0xb8, 0x2a, 0x00, 0x00, 0x00, // mov eax, 42
+            0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, // nop
+            0xc3, // ret
+        ]);
+
+        let patcher =
+            unsafe { X64Patcher::new(BytePatcher::new(), function.ptr() as _, jmp_abs(0)) }
+                .unwrap();
+        let trampoline = patcher.leak_trampoline().unwrap();
+
+        let original: extern "C" fn() -> u32 = unsafe { mem::transmute(trampoline) };
+        assert_eq!(original(), 42);
+    }
+
+    #[test]
+    /// Tests that the trampoline is read/execute-only once the patcher is constructed
+    fn test_trampoline_not_writable() {
+        // Note: the target lives in a private mapping so no other test's allocations share the trampoline's page
+        let code = mmap::MemoryMap::new(
+            region::page::size(),
+            &[mmap::MapOption::MapReadable, mmap::MapOption::MapWritable],
+        )
+        .unwrap();
+        unsafe {
+            code.data().write_bytes(0x90, 15);
+            code.data().add(15).write(0xc3);
+        }
+
+        let patcher =
+            unsafe { X64Patcher::new(BytePatcher::new(), code.data(), jmp_abs(0)) }.unwrap();
+        let original = patcher.original().unwrap();
+
+        assert_eq!(
+            region::query(original).unwrap().protection(),
+            region::Protection::READ_EXECUTE
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! This module contains a patcher whose patches can be restored from a signal handler
+//!
+//! Guards restore their patches through the normal patcher stack, which allocates, takes locks and logs, none of which is async-signal-safe.
+//! A crash handler that wants to put the original code back before the process dies (e.g. for a clean core dump) can't use them.
+//!
+//! [`EmergencyPatcher`] copies the original bytes of every patch into a fixed table of atomics as the patch is applied.
+//! [`restore_all`] writes them back using only that table, `mprotect` and plain memory writes, so it's safe to call from a signal handler.
+//! Patches are restored newest first, so patches stacked on the same location unwind to the bytes that were there before the first one.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{ptr, slice};
+
+use region::Protection;
+use thiserror::Error;
+
+use super::{PatchGuard, PatchStage, Patcher};
+
+/// Maximum number of patches that can be registered for emergency restore at once
+pub const MAX_RESTORES: usize = 64;
+/// Maximum length of a patch that can be registered for emergency restore
+pub const MAX_RESTORE_LEN: usize = 32;
+/// Maximum number of pages a registered patch can span. Pages are much larger than [`MAX_RESTORE_LEN`], so a patch crosses at most one page boundary
+const MAX_RESTORE_PAGES: usize = 2;
+
+#[derive(Debug, Error)]
+/// Error types for [`EmergencyPatcher`]
+pub enum EmergencyError<E> {
+    /// Every one of the [`MAX_RESTORES`] slots is in use
+    #[error("Too many patches are registered for emergency restore")]
+    TooManyPatches,
+    /// The patch is longer than [`MAX_RESTORE_LEN`]
+    #[error("Patch of {0} bytes is too long for emergency restore")]
+    PatchTooLong(usize),
+    /// Error from the underlying patcher
+    #[error("patcher error")]
+    PatcherError(E),
+}
+
+/// Restore data for a registered patch
+///
+/// A slot belongs to its [`SlotGuard`] while `claimed` is set, whether or not it has been restored yet.
+struct Slot {
+    /// Whether a [`SlotGuard`] owns the slot. Only changed while [`REGISTRATIONS`] is locked
+    claimed: AtomicBool,
+    /// Location of the patch, or 0 once it's been restored (or before it's ready)
+    location: AtomicUsize,
+    /// Position of the patch in registration order
+    sequence: AtomicUsize,
+    /// Number of bytes in `original`
+    len: AtomicUsize,
+    /// Start of the pages containing the patch
+    pages: AtomicUsize,
+    /// Number of pages containing the patch
+    page_count: AtomicUsize,
+    /// `mprotect` flags of each page when the patch was registered
+    protection: [AtomicUsize; MAX_RESTORE_PAGES],
+    /// Bytes that were there before the patch
+    original: [AtomicU8; MAX_RESTORE_LEN],
+}
+
+/// Registered patches. These are atomics rather than behind a lock so [`restore_all`] can read them from a signal handler
+static SLOTS: [Slot; MAX_RESTORES] = [const {
+    Slot {
+        claimed: AtomicBool::new(false),
+        location: AtomicUsize::new(0),
+        sequence: AtomicUsize::new(0),
+        len: AtomicUsize::new(0),
+        pages: AtomicUsize::new(0),
+        page_count: AtomicUsize::new(0),
+        protection: [const { AtomicUsize::new(0) }; MAX_RESTORE_PAGES],
+        original: [const { AtomicU8::new(0) }; MAX_RESTORE_LEN],
+    }
+}; MAX_RESTORES];
+
+/// Locked while registering or unregistering, so two registrations can't claim the same slot
+static REGISTRATIONS: Mutex<()> = Mutex::new(());
+/// Sequence number of the next registered patch
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+/// Page size, stored while registering since looking it up isn't async-signal-safe
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Patcher that registers the original bytes of every patch for [`restore_all`]
+///
+/// Wrap the patcher that's given to hooks, e.g. `JmpHook::new(EmergencyPatcher::new(PermissionWrapper::new(BytePatcher::new())))`.
+/// Patches are unregistered when their guard is dropped.
+pub struct EmergencyPatcher<P> {
+    /// Internal patcher that will actually write the data
+    patcher: P,
+}
+impl<P: Patcher> EmergencyPatcher<P> {
+    /// Creates a new emergency patcher wrapping `patcher`
+    pub fn new(patcher: P) -> Self {
+        Self { patcher }
+    }
+}
+unsafe impl<P: Patcher> Patcher for EmergencyPatcher<P> {
+    type Error = EmergencyError<P::Error>;
+    type Guard<'a>
+        = EmergencyPatchGuard<P::Guard<'a>>
+    where
+        Self: 'a;
+    const STAGE: PatchStage = P::STAGE;
+
+    unsafe fn patch<'a>(
+        &'a self,
+        target: *mut u8,
+        patch: &[u8],
+    ) -> Result<Self::Guard<'a>, Self::Error> {
+        // Safety: the caller must ensure that `target` is valid for the length of the patch
+        let original = slice::from_raw_parts(target, patch.len());
+        // Registered first so that a crash while patching still restores
+        let slot = register(target, original)?;
+
+        let guard = self
+            .patcher
+            .patch(target, patch)
+            .map_err(EmergencyError::PatcherError)?;
+        Ok(EmergencyPatchGuard { guard, _slot: slot })
+    }
+}
+
+/// Claims a free slot and fills it with the restore data for `original` at `location`
+fn register<E>(location: *const u8, original: &[u8]) -> Result<SlotGuard, EmergencyError<E>> {
+    if original.len() > MAX_RESTORE_LEN {
+        return Err(EmergencyError::PatchTooLong(original.len()));
+    }
+
+    // Everything that isn't signal-safe (the page size and protection lookups) happens here rather than while restoring
+    let page_size = region::page::size();
+    let pages = location as usize - location as usize % page_size;
+    let end = location as usize + original.len().max(1);
+    let page_count = (end - pages).div_ceil(page_size);
+    let mut protection = [Protection::READ_EXECUTE; MAX_RESTORE_PAGES];
+    for (page, protection) in protection.iter_mut().enumerate().take(page_count) {
+        if let Ok(region) = region::query((pages + page * page_size) as *const u8) {
+            *protection = region.protection();
+        }
+    }
+
+    let _registrations = REGISTRATIONS.lock().unwrap();
+    PAGE_SIZE.store(page_size, Ordering::Relaxed);
+    let index = SLOTS
+        .iter()
+        .position(|slot| !slot.claimed.load(Ordering::Acquire))
+        .ok_or(EmergencyError::TooManyPatches)?;
+
+    // The rest of the slot must be visible before the location, since a non-zero location marks the slot as ready
+    let slot = &SLOTS[index];
+    slot.claimed.store(true, Ordering::Release);
+    for (saved, &byte) in slot.original.iter().zip(original) {
+        saved.store(byte, Ordering::Relaxed);
+    }
+    slot.len.store(original.len(), Ordering::Relaxed);
+    slot.pages.store(pages, Ordering::Relaxed);
+    slot.page_count.store(page_count, Ordering::Relaxed);
+    for (saved, &protection) in slot.protection.iter().zip(&protection) {
+        saved.store(mprotect_flags(protection) as usize, Ordering::Relaxed);
+    }
+    slot.sequence
+        .store(SEQUENCE.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    slot.location.store(location as usize, Ordering::Release);
+
+    Ok(SlotGuard { index })
+}
+
+/// Converts `protection` to `mprotect` flags
+fn mprotect_flags(protection: Protection) -> libc::c_int {
+    [
+        (Protection::READ, libc::PROT_READ),
+        (Protection::WRITE, libc::PROT_WRITE),
+        (Protection::EXECUTE, libc::PROT_EXEC),
+    ]
+    .into_iter()
+    .filter(|&(flag, _)| protection.contains(flag))
+    .fold(libc::PROT_NONE, |flags, (_, prot)| flags | prot)
+}
+
+/// Writes the original bytes of every registered patch back, returning how many were restored
+///
+/// This is async-signal-safe: it only reads the pre-filled table, calls `mprotect` and writes memory.
+/// Patches are restored in reverse registration order, and each page gets back the protection it had when the patch was registered.
+/// Restored patches aren't restored again, and dropping their guards later restores the same bytes again, which is harmless.
+///
+/// # Safety
+///
+/// The patched locations must still be mapped, and nothing may be executing the bytes being restored (e.g. every other thread is stopped or about to be killed).
+/// Patches registered while this runs may or may not be restored.
+pub unsafe fn restore_all() -> usize {
+    let mut restored = 0;
+    // Sequence number of the last patch visited. The table is searched again for each patch, since sorting it would need to allocate
+    let mut below = usize::MAX;
+    while let Some(slot) = SLOTS
+        .iter()
+        .filter(|slot| slot.location.load(Ordering::Acquire) != 0)
+        .filter(|slot| slot.sequence.load(Ordering::Relaxed) < below)
+        .max_by_key(|slot| slot.sequence.load(Ordering::Relaxed))
+    {
+        below = slot.sequence.load(Ordering::Relaxed);
+
+        // Taking the location means it's only restored once, even if two threads crash at the same time
+        let location = slot.location.swap(0, Ordering::AcqRel);
+        if location == 0 {
+            continue;
+        }
+
+        let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+        let pages = slot.pages.load(Ordering::Relaxed);
+        let page_count = slot.page_count.load(Ordering::Relaxed);
+        let pages_len = page_count * page_size;
+        if libc::mprotect(
+            pages as *mut libc::c_void,
+            pages_len,
+            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+        ) != 0
+        {
+            continue;
+        }
+        for (offset, byte) in slot.original[..slot.len.load(Ordering::Relaxed)]
+            .iter()
+            .enumerate()
+        {
+            ptr::write_volatile(
+                (location as *mut u8).add(offset),
+                byte.load(Ordering::Relaxed),
+            );
+        }
+        for (page, protection) in slot.protection.iter().enumerate().take(page_count) {
+            libc::mprotect(
+                (pages + page * page_size) as *mut libc::c_void,
+                page_size,
+                protection.load(Ordering::Relaxed) as libc::c_int,
+            );
+        }
+        restored += 1;
+    }
+    restored
+}
+
+/// Registration of a patch in the emergency restore table. Unregisters when dropped
+///
+/// The slot stays claimed after [`restore_all`] restores it, so it can't be handed to another patch while this guard still refers to it.
+struct SlotGuard {
+    /// Index of the patch in [`SLOTS`]
+    index: usize,
+}
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let _registrations = REGISTRATIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        SLOTS[self.index].location.store(0, Ordering::Release);
+        SLOTS[self.index].claimed.store(false, Ordering::Release);
+    }
+}
+
+/// Guard for [`EmergencyPatcher`]
+///
+/// The patch is restored normally first, then unregistered from the emergency restore table.
+pub struct EmergencyPatchGuard<G: PatchGuard> {
+    /// Guard for the underlying patch
+    guard: G,
+    /// Registration of the patch. Dropped after `guard`
+    _slot: SlotGuard,
+}
+impl<G: PatchGuard> EmergencyPatchGuard<G> {
+    /// Get the underlying patch guard in case info is needed
+    pub fn patch(&self) -> &G {
+        &self.guard
+    }
+}
+unsafe impl<G: PatchGuard> PatchGuard for EmergencyPatchGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+    use std::sync::Mutex;
+
+    use region::Protection;
+
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::emergency::{restore_all, EmergencyError, EmergencyPatcher};
+    use crate::patcher::mem::PermissionWrapper;
+    use crate::patcher::{PatchGuard, Patcher};
+
+    /// Held by tests that register patches, since [`restore_all`] restores every test's patches
+    static TABLE: Mutex<()> = Mutex::new(());
+
+    #[test]
+    /// Tests that registered patches are restored with their original protection, and that guards still restore afterwards
+    fn test_restore_all() {
+        let _table = TABLE.lock().unwrap();
+        let map = mmap::MemoryMap::new(
+            region::page::size(),
+            &[mmap::MapOption::MapReadable, mmap::MapOption::MapWritable],
+        )
+        .unwrap();
+        let ptr = map.data();
+        unsafe { ptr.write_bytes(0x90, 4) };
+        unsafe { region::protect(ptr, 4, Protection::READ_EXECUTE) }.unwrap();
+
+        let patcher = EmergencyPatcher::new(PermissionWrapper::new(BytePatcher::new()));
+        let guard = unsafe { patcher.patch(ptr, &[0xcc; 4]) }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 4) }, [0xcc; 4]);
+
+        assert_eq!(unsafe { restore_all() }, 1);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 4) }, [0x90; 4]);
+        assert_eq!(
+            region::query(ptr).unwrap().protection(),
+            Protection::READ_EXECUTE
+        );
+
+        // already restored, so there's nothing left to do
+        assert_eq!(unsafe { restore_all() }, 0);
+        guard.restore();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 4) }, [0x90; 4]);
+    }
+
+    #[test]
+    /// Tests that patches stacked on the same location are restored newest first, and that restored slots aren't reused while their guard is alive
+    fn test_restore_order() {
+        let _table = TABLE.lock().unwrap();
+        let mut data = [0x90u8; 4];
+        let patcher = EmergencyPatcher::new(BytePatcher::new());
+
+        let first = unsafe { patcher.patch(data.as_mut_ptr(), &[0xcc; 4]) }.unwrap();
+        let second = unsafe { patcher.patch(data.as_mut_ptr(), &[0xcd; 4]) }.unwrap();
+        assert_eq!(unsafe { restore_all() }, 2);
+        assert_eq!(data, [0x90; 4]);
+
+        // a patch registered after the restore gets its own slot, so dropping the restored guards doesn't unregister it
+        let third = unsafe { patcher.patch(data.as_mut_ptr(), &[0xce; 4]) }.unwrap();
+        drop(second);
+        drop(first);
+        assert_eq!(unsafe { restore_all() }, 1);
+        assert_eq!(data, [0x90; 4]);
+        drop(third);
+    }
+
+    #[test]
+    /// Tests that a patch spanning two pages restores each page's own protection
+    fn test_restore_pages() {
+        let _table = TABLE.lock().unwrap();
+        let page_size = region::page::size();
+        let map = mmap::MemoryMap::new(
+            page_size * 2,
+            &[mmap::MapOption::MapReadable, mmap::MapOption::MapWritable],
+        )
+        .unwrap();
+        let ptr = unsafe { map.data().add(page_size - 2) };
+        unsafe { ptr.write_bytes(0x90, 4) };
+        unsafe { region::protect(map.data(), page_size, Protection::READ_EXECUTE) }.unwrap();
+        unsafe { region::protect(map.data().add(page_size), page_size, Protection::READ) }.unwrap();
+
+        let patcher = EmergencyPatcher::new(PermissionWrapper::new(BytePatcher::new()));
+        let guard = unsafe { patcher.patch(ptr, &[0xcc; 4]) }.unwrap();
+        assert_eq!(unsafe { restore_all() }, 1);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 4) }, [0x90; 4]);
+        assert_eq!(
+            region::query(map.data()).unwrap().protection(),
+            Protection::READ_EXECUTE
+        );
+        assert_eq!(
+            region::query(unsafe { map.data().add(page_size) })
+                .unwrap()
+                .protection(),
+            Protection::READ
+        );
+        drop(guard);
+    }
+
+    #[test]
+    /// Tests that patches that don't fit in the table are refused before anything is written
+    fn test_too_long() {
+        let mut data = [0u8; 64];
+        let patcher = EmergencyPatcher::new(BytePatcher::new());
+        let result = unsafe { patcher.patch(data.as_mut_ptr(), &[1; 33]) };
+        assert!(matches!(result, Err(EmergencyError::PatchTooLong(33))));
+        assert_eq!(data, [0; 64]);
+    }
+}
@@ -0,0 +1,291 @@
+//! This module contains a patcher which records each patch in a journal file while it's applied
+//!
+//! If the process dies without running guard destructors, the journal still lists every patch that was applied, so an external tool (or the next startup) can put the original bytes back.
+//!
+//! # Format
+//!
+//! The journal is a UTF-8 text file with one line per applied patch:
+//!
+//! ```text
+//! <location> <original>
+//! ```
+//!
+//! `location` is the patched address in lowercase hex with a `0x` prefix, and `original` is the bytes that were overwritten as lowercase hex with no separators.
+//! Lines are in the order the patches were applied, so restoring them in reverse order undoes nested patches correctly.
+//! The file is replaced atomically (written to `<path>.tmp` and renamed) every time a patch is applied or restored, and contains no lines once every patch has been restored.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io, slice};
+
+use thiserror::Error;
+
+use super::{PatchGuard, PatchStage, Patcher};
+
+#[derive(Debug, Error)]
+/// Error types for [`JournalingPatcher`]
+pub enum JournalError<E> {
+    /// Error while writing the journal
+    #[error("{0}")]
+    IoError(#[from] io::Error),
+    /// Error from the wrapped patcher
+    #[error("patcher error")]
+    PatcherError(E),
+}
+
+/// A patch recorded in a journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    /// Location that was patched
+    pub location: usize,
+    /// Bytes at `location` before it was patched
+    pub original: Vec<u8>,
+}
+impl JournalEntry {
+    /// Formats the entry as a journal line (without the newline)
+    fn to_line(&self) -> String {
+        let mut line = format!("{:#x} ", self.location);
+        for byte in &self.original {
+            let _ = write!(line, "{byte:02x}");
+        }
+        line
+    }
+    /// Parses a journal line, returning `None` if it's malformed
+    fn from_line(line: &str) -> Option<Self> {
+        let (location, original) = line.split_once(' ')?;
+        let location = usize::from_str_radix(location.strip_prefix("0x")?, 16).ok()?;
+        if original.len() % 2 != 0 {
+            return None;
+        }
+        let original = (0..original.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(original.get(i..i + 2)?, 16).ok())
+            .collect::<Option<_>>()?;
+        Some(Self { location, original })
+    }
+}
+
+/// Reads every entry from the journal at `path`
+///
+/// Returns an [`io::ErrorKind::InvalidData`] error if a line is malformed.
+pub fn read_journal(path: impl AsRef<Path>) -> io::Result<Vec<JournalEntry>> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            JournalEntry::from_line(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed journal line: {line}"),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Journal file and the entries it currently contains
+struct Journal {
+    /// Path of the journal file
+    path: PathBuf,
+    /// Applied patches, keyed by a unique id so identical patches can be told apart
+    entries: Vec<(u64, JournalEntry)>,
+    /// Id of the next entry
+    next_id: u64,
+}
+impl Journal {
+    /// Replaces the journal file with the current entries
+    fn flush(&self) -> io::Result<()> {
+        let contents: String = self
+            .entries
+            .iter()
+            .map(|(_, entry)| entry.to_line() + "\n")
+            .collect();
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, &self.path)
+    }
+    /// Records an entry, returning its id
+    fn add(&mut self, entry: JournalEntry) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push((id, entry));
+        if let Err(e) = self.flush() {
+            self.entries.pop();
+            return Err(e);
+        }
+        Ok(id)
+    }
+    /// Removes the entry with `id`
+    fn remove(&mut self, id: u64) -> io::Result<()> {
+        self.entries.retain(|(entry_id, _)| *entry_id != id);
+        self.flush()
+    }
+}
+
+/// Patcher that records every applied patch in a journal file
+///
+/// Entries are written before the patch is applied and removed after it's restored, so the journal never misses a patch that might be in memory.
+/// See the [module documentation](self) for the journal format.
+pub struct JournalingPatcher<P> {
+    /// Internal patcher that will actually write the data
+    patcher: P,
+    /// Journal of applied patches
+    journal: Mutex<Journal>,
+}
+impl<P: Patcher> JournalingPatcher<P> {
+    /// Creates a new journaling patcher that records patches at `path`
+    ///
+    /// The journal is created (or truncated) immediately. Recover from any existing journal with [`read_journal`] before creating the patcher.
+    pub fn new(patcher: P, path: impl Into<PathBuf>) -> io::Result<Self> {
+        let journal = Journal {
+            path: path.into(),
+            entries: Vec::new(),
+            next_id: 0,
+        };
+        journal.flush()?;
+        Ok(Self {
+            patcher,
+            journal: Mutex::new(journal),
+        })
+    }
+    /// Gets the path of the journal file
+    pub fn path(&self) -> PathBuf {
+        self.journal.lock().unwrap().path.clone()
+    }
+}
+unsafe impl<P: Patcher> Patcher for JournalingPatcher<P> {
+    type Error = JournalError<P::Error>;
+    type Guard<'a> = JournalPatchGuard<'a, P::Guard<'a>>
+    where
+        Self: 'a;
+    const STAGE: PatchStage = P::STAGE;
+
+    unsafe fn patch<'a>(
+        &'a self,
+        target: *mut u8,
+        patch: &[u8],
+    ) -> Result<Self::Guard<'a>, Self::Error> {
+        // Safety: the caller must ensure that `target` is valid for the length of the patch
+        let original = slice::from_raw_parts(target, patch.len()).to_vec();
+
+        let id = self.journal.lock().unwrap().add(JournalEntry {
+            location: target as usize,
+            original,
+        })?;
+
+        match self.patcher.patch(target, patch) {
+            Ok(guard) => Ok(JournalPatchGuard {
+                guard: Some(guard),
+                journal: &self.journal,
+                id,
+                location: target,
+                len: patch.len(),
+            }),
+            Err(e) => {
+                // The patch was never applied, so it doesn't need recovering
+                if let Err(e) = self.journal.lock().unwrap().remove(id) {
+                    log::warn!("unable to remove journal entry for failed patch: {e}");
+                }
+                Err(JournalError::PatcherError(e))
+            }
+        }
+    }
+}
+
+/// Guard for journaled patches
+///
+/// The patch is restored before its journal entry is removed.
+pub struct JournalPatchGuard<'a, G: PatchGuard> {
+    /// Underlying patch guard. Only `None` while dropping
+    guard: Option<G>,
+    /// Journal the patch is recorded in
+    journal: &'a Mutex<Journal>,
+    /// Id of the journal entry for this patch
+    id: u64,
+    /// Location of the patch
+    location: *const u8,
+    /// Length of the patch
+    len: usize,
+}
+unsafe impl<'a, G: PatchGuard> PatchGuard for JournalPatchGuard<'a, G> {
+    fn location(&self) -> *const u8 {
+        self.location
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+impl<'a, G: PatchGuard> Drop for JournalPatchGuard<'a, G> {
+    fn drop(&mut self) {
+        if let Some(guard) = self.guard.take() {
+            guard.restore();
+        }
+        if let Err(e) = self.journal.lock().unwrap().remove(self.id) {
+            log::warn!("unable to remove journal entry for restored patch: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, slice};
+
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::journal::{read_journal, JournalEntry, JournalingPatcher};
+    use crate::patcher::{PatchGuard, Patcher};
+
+    #[test]
+    /// Tests that entries are journaled while patches are applied
+    fn test_journal() {
+        let path = env::temp_dir().join(format!("libhook-journal-{}", process::id()));
+
+        let vec = b"journal".to_vec();
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = JournalingPatcher::new(BytePatcher::new(), &path).unwrap();
+        assert!(read_journal(&path).unwrap().is_empty());
+
+        let first = unsafe { patcher.patch(ptr, b"J") }.unwrap();
+        let second = unsafe { patcher.patch(ptr.add(4), b"NAL") }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"JourNAL");
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{:#x} 6a\n{:#x} 6e616c\n", ptr as usize, ptr as usize + 4)
+        );
+        assert_eq!(
+            read_journal(&path).unwrap(),
+            [
+                JournalEntry {
+                    location: ptr as usize,
+                    original: b"j".to_vec(),
+                },
+                JournalEntry {
+                    location: ptr as usize + 4,
+                    original: b"nal".to_vec(),
+                },
+            ]
+        );
+
+        // restoring removes the entry
+        first.restore();
+        assert_eq!(
+            read_journal(&path).unwrap(),
+            [JournalEntry {
+                location: ptr as usize + 4,
+                original: b"nal".to_vec(),
+            }]
+        );
+
+        second.restore();
+        assert!(read_journal(&path).unwrap().is_empty());
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"journal");
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,9 +1,12 @@
 //! This module contains a patcher which adjusts memory permissions to patch read-only data
 
-use region::Protection;
+use std::cell::UnsafeCell;
+use std::mem;
+
+use region::{ProtectGuard, Protection};
 use thiserror::Error;
 
-use super::{PatchGuard, Patcher};
+use super::{PatchGuard, PatchStage, Patcher};
 
 /// Errors when using permission patching
 #[derive(Debug, Error)]
@@ -31,15 +34,142 @@ impl From<()> for PermissionError<()> {
 ///
 /// As always, casting a `&T` or `&mut T` to a `*mut u8` for use with `PermissionWrapper` can result in  undefined behavior because rust assumes `&T` will never change and `&mut T` will only be changed via that reference.
 /// The `*mut u8` **MUST** be memory not tracked by Rust, or ensured that reading from and writing to data tracked by Rust will not trigger undefined behavior.
+///
+/// # Multiple regions
+///
+/// A patch can span regions with different protections (e.g. the end of `.text` and the start of `.rdata`).
+/// Each region's protection is recorded before the patch is written, and each one is restored to its own protection afterwards rather than all of them being set to the same thing.
+///
+/// # Nesting
+///
+/// The wrapped patcher must not change permissions itself ([`PatchStage::Protect`]), since the inner patcher would restore the permissions before the outer one is done.
+/// Nesting them is rejected at compile time:
+///
+/// ```compile_fail
+/// use libhook::patcher::byte::BytePatcher;
+/// use libhook::patcher::mem::PermissionWrapper;
+///
+/// let wrapper = PermissionWrapper::new(PermissionWrapper::new(BytePatcher::new()));
+/// ```
 pub struct PermissionWrapper<P: Patcher> {
     /// Underlying patcher.
     patcher: P,
+    /// When memory permissions should be changed
+    mode: ProtectionMode,
 }
 impl<P: Patcher> PermissionWrapper<P> {
     /// Creates a new PermissionWrapper
     pub fn new(patcher: P) -> Self {
-        Self { patcher }
+        Self::with_mode(patcher, ProtectionMode::default())
+    }
+    /// Creates a new PermissionWrapper that only changes memory permissions according to `mode`
+    pub fn with_mode(patcher: P, mode: ProtectionMode) -> Self {
+        const {
+            assert!(
+                matches!(P::STAGE, PatchStage::Write),
+                "PermissionWrapper can't wrap a patcher that already changes memory permissions"
+            )
+        };
+        Self { patcher, mode }
+    }
+}
+
+/// Controls when [`PermissionWrapper`] changes memory permissions
+///
+/// Changing permissions to read/write/execute and back can be unnecessary for memory that's already writable (e.g. JIT code),
+/// so the skip modes write directly if the target already has the permissions the patch needs.
+/// If the target is missing any of the required permissions, the permissions are changed as usual.
+///
+/// Skipping also matters on kernels that refuse to map pages writable and executable at the same time: protecting an RWX page (e.g. a trampoline) can fail there even though it's already writable.
+/// Use [`PermissionWrapper::with_mode`] with [`ProtectionMode::SkipWritableExecutable`] to write RWX memory without changing its permissions. The default, [`ProtectionMode::Always`], always changes them.
+///
+/// | Target protection | `Always` | `SkipWritable` | `SkipWritableExecutable` |
+/// |-------------------|----------|----------------|--------------------------|
+/// | `R`               | changed  | changed        | changed                  |
+/// | `RW`              | changed  | skipped        | changed                  |
+/// | `RX`              | changed  | changed        | changed                  |
+/// | `RWX`             | changed  | skipped        | skipped                  |
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionMode {
+    /// Always change permissions for the duration of the patch
+    #[default]
+    Always,
+    /// Skip changing permissions if the target is already readable and writable.
+    /// Use this when patching data.
+    SkipWritable,
+    /// Skip changing permissions if the target is already readable, writable, and executable.
+    /// Use this when patching code, since a writable page that isn't executable still needs to be handled.
+    SkipWritableExecutable,
+}
+impl ProtectionMode {
+    /// Gets the permissions the target must already have for permission changes to be skipped
+    fn required(&self) -> Option<Protection> {
+        match self {
+            Self::Always => None,
+            Self::SkipWritable => Some(Protection::READ_WRITE),
+            Self::SkipWritableExecutable => Some(Protection::READ_WRITE_EXECUTE),
+        }
+    }
+    /// Checks whether permissions need to be changed to write `len` bytes at `location`
+    fn needs_protect(&self, location: *const u8, len: usize) -> Result<bool, region::Error> {
+        let required = match self.required() {
+            Some(required) => required,
+            None => return Ok(true),
+        };
+        for region in region::query_range(location, len)? {
+            if !region?.protection().contains(required) {
+                return Ok(true);
+            }
+        }
+        // unmapped gaps aren't returned by the query, so make sure they're handled by changing permissions
+        Ok(!is_mapped(location, len)?)
+    }
+    /// Makes `len` bytes at `location` writable if needed, returning a handle that reverts the permissions when dropped
+    ///
+    /// # Safety
+    ///
+    /// See [`region::protect_with_handle`]
+    unsafe fn make_writable(
+        &self,
+        location: *const u8,
+        len: usize,
+    ) -> Result<Option<ProtectGuard>, region::Error> {
+        if self.needs_protect(location, len)? {
+            region::protect_with_handle(location, len, Protection::all()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Runs `write` with the `len` bytes at `location` made writable, then reverts the permissions
+///
+/// Unlike [`PermissionWrapper`], nothing is kept to restore what `write` writes.
+///
+/// # Safety
+///
+/// See [`region::protect_with_handle`]. `write` must not write outside the `len` bytes at `location`
+pub(crate) unsafe fn with_writable<R>(
+    location: *const u8,
+    len: usize,
+    write: impl FnOnce() -> R,
+) -> Result<R, region::Error> {
+    let _handle = ProtectionMode::default().make_writable(location, len)?;
+    Ok(write())
+}
+
+/// Checks whether all `len` bytes at `location` are mapped
+fn is_mapped(location: *const u8, len: usize) -> Result<bool, region::Error> {
+    let mut next = location as usize;
+    for region in region::query_range(location, len)? {
+        let range = region?.as_range();
+        if range.start > next {
+            // there's a gap before this region
+            return Ok(false);
+        }
+        next = next.max(range.end);
     }
+    Ok(next >= location as usize + len)
 }
 
 /// Converts a const pointer to a mutable pointer to be passed into our [`Patcher::patch`] implementation.
@@ -55,28 +185,63 @@ pub unsafe fn to_mut<T>(ptr: *const T) -> *mut T {
     ptr as _
 }
 
+/// Patches the bytes inside `cell` with `patcher`, starting at the beginning of the cell
+///
+/// This is the supported way to patch Rust data (e.g. a `static` buffer): the pointer comes from [`UnsafeCell::get`], so Rust knows the bytes can change behind a shared reference.
+/// Casting a `&[u8]` or `&[u8; N]` to a `*mut u8` instead is undefined behavior, even through [`to_mut`].
+///
+/// # Safety
+///
+/// - No references to the contents of `cell` may be alive while the patch is written or restored
+/// - Nothing else (including other threads) may access the contents of `cell` while the patch is written or restored
+/// - See [`Patcher::patch`]
+///
+/// # Panics
+///
+/// Panics if `patch` is longer than the cell
+pub unsafe fn patch_cell<'a, P: Patcher, const N: usize>(
+    patcher: &'a P,
+    cell: &UnsafeCell<[u8; N]>,
+    patch: &[u8],
+) -> Result<P::Guard<'a>, P::Error> {
+    assert!(
+        patch.len() <= N,
+        "patch of {} bytes doesn't fit in a cell of {N} bytes",
+        patch.len()
+    );
+    // The cell's pointer is valid for writes of the whole array, unlike a pointer cast from a shared reference
+    patcher.patch(cell.get().cast(), patch)
+}
+
 unsafe impl<P> Patcher for PermissionWrapper<P>
 where
     P: Patcher,
     PermissionError<P::Error>: From<P::Error>,
 {
     type Error = PermissionError<P::Error>;
-    type Guard<'a> = PermissionWrapperGuard<P::Guard<'a>> where Self: 'a;
+    type Guard<'a>
+        = PermissionWrapperGuard<P::Guard<'a>>
+    where
+        Self: 'a;
+    const STAGE: PatchStage = PatchStage::Protect;
 
     unsafe fn patch<'a>(
         &'a self,
         location: *mut u8,
         patch: &[u8],
     ) -> Result<Self::Guard<'a>, Self::Error> {
-        let _guard = region::protect_with_handle(location, patch.len(), Protection::all())?;
+        let _guard = self.mode.make_writable(location, patch.len())?;
         self.patcher
             .patch(location, patch)
-            .map(|g| PermissionWrapperGuard::guard(g, location, patch.len()))
+            .map(|g| PermissionWrapperGuard::guard(g, location, patch.len(), self.mode))
             .map_err(Into::into)
     }
 }
 
 /// Permission guard for the underlying patch guard
+///
+/// If the patched memory has been unmapped by the time the guard is dropped (e.g. the module containing it was unloaded),
+/// restoring would fault, so the patch is left in place and a warning is logged instead.
 pub struct PermissionWrapperGuard<G: PatchGuard> {
     /// Underlying patch guard for the wrapped patcher. `Option` so that we can drop it in our [`Drop::drop`] impl
     guard: Option<G>,
@@ -84,42 +249,66 @@ pub struct PermissionWrapperGuard<G: PatchGuard> {
     location: *const u8,
     /// Length of the patch
     len: usize,
+    /// When memory permissions should be changed
+    mode: ProtectionMode,
 }
 impl<G: PatchGuard> PermissionWrapperGuard<G> {
     /// Wrap a patcher's guard. When this guard is dropped, the underlying guard will also be dropped with its target location made writable
-    fn guard(guard: G, location: *const u8, len: usize) -> Self {
+    fn guard(guard: G, location: *const u8, len: usize, mode: ProtectionMode) -> Self {
         let guard = Some(guard);
         Self {
             guard,
             location,
             len,
+            mode,
         }
     }
 }
-unsafe impl<G: PatchGuard> PatchGuard for PermissionWrapperGuard<G> {}
+unsafe impl<G: PatchGuard> PatchGuard for PermissionWrapperGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.location
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+}
 
 impl<P: PatchGuard> Drop for PermissionWrapperGuard<P> {
     fn drop(&mut self) {
-        unsafe {
-            // SAFETY: We already changed memory permissions to construct the wrapper, so we shouldn't run into errors here
-            let _guard =
-                region::protect_with_handle(self.location, self.len, Protection::all()).unwrap();
-            // `self.patcher` should never be `None` while we are alive
-            self.guard.take().unwrap().restore();
+        // `self.patcher` should never be `None` while we are alive
+        let guard = self.guard.take().unwrap();
+
+        if !matches!(is_mapped(self.location, self.len), Ok(true)) {
+            log::warn!(
+                "Not restoring {} bytes at {:?}, the memory is no longer mapped",
+                self.len,
+                self.location
+            );
+            // Dropping the underlying guard would write to the unmapped memory
+            mem::forget(guard);
+            return;
         }
+
+        // SAFETY: We already queried and changed memory permissions to construct the wrapper, so we shouldn't run into errors here
+        let _handle = unsafe { self.mode.make_writable(self.location, self.len) }.unwrap();
+        guard.restore();
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::UnsafeCell;
     use std::slice;
 
     use region::Protection;
 
     use crate::patcher::byte::BytePatcher;
-    use crate::patcher::mem::{to_mut, PermissionWrapper};
+    use crate::patcher::mem::{
+        patch_cell, to_mut, PermissionWrapper, PermissionWrapperGuard, ProtectionMode,
+    };
+    use crate::patcher::undo::UndoPatcher;
     use crate::patcher::PatchGuard;
-    use crate::patcher::Patcher;
+    use crate::patcher::{PatchStage, Patcher};
 
     #[test]
     /// Test patch and revert functionality
@@ -150,6 +339,17 @@ mod tests {
         let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
     }
 
+    #[test]
+    /// Tests that wrappers report the stage of what they wrap, so nested permission changes can be rejected
+    fn test_stage() {
+        assert_eq!(BytePatcher::STAGE, PatchStage::Write);
+        assert_eq!(PermissionWrapper::<BytePatcher>::STAGE, PatchStage::Protect);
+        assert_eq!(
+            UndoPatcher::<PermissionWrapper<BytePatcher>>::STAGE,
+            PatchStage::Protect
+        );
+    }
+
     #[test]
     /// Tests to ensure permissions are actually set
     fn test_perms() {
@@ -206,4 +406,200 @@ mod tests {
             assert_eq!(region.protection(), Protection::READ);
         }
     }
+
+    /// Buffer that can be patched in place while being shared
+    struct PatchableBuffer(UnsafeCell<[u8; 4]>);
+    // Safety: only accessed by `test_patch_cell`
+    unsafe impl Sync for PatchableBuffer {}
+
+    static BUFFER: PatchableBuffer = PatchableBuffer(UnsafeCell::new([1, 2, 3, 4]));
+
+    #[test]
+    /// Tests patching and restoring a static through its cell
+    fn test_patch_cell() {
+        let patcher = BytePatcher::new();
+
+        let patch = unsafe { patch_cell(&patcher, &BUFFER.0, &[4, 3]) }.unwrap();
+        assert_eq!(unsafe { *BUFFER.0.get() }, [4, 3, 3, 4]);
+
+        patch.restore();
+        assert_eq!(unsafe { *BUFFER.0.get() }, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit in a cell")]
+    /// Tests that patches longer than the cell are refused
+    fn test_patch_cell_overflow() {
+        let cell = UnsafeCell::new([0u8; 2]);
+        let _ = unsafe { patch_cell(&BytePatcher::new(), &cell, &[1, 2, 3]) };
+    }
+
+    /// Patches and restores `ptr` using `mode`, checking that the data and permissions are correct afterwards
+    fn patch_with_mode(ptr: *const u8, mode: ProtectionMode, expected: Protection) {
+        let original = unsafe { slice::from_raw_parts(ptr, 4) }.to_vec();
+        for region in region::query_range(ptr, 4).unwrap() {
+            assert_eq!(region.unwrap().protection(), expected);
+        }
+
+        let wrapper = PermissionWrapper::with_mode(BytePatcher::new(), mode);
+        let patch = unsafe { wrapper.patch(to_mut(ptr), &[4, 3, 2, 1]).unwrap() };
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 4) }, [4, 3, 2, 1]);
+
+        patch.restore();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, 4) }, original);
+
+        // permissions must be the same as before the patch, regardless of whether they were changed
+        for region in region::query_range(ptr, 4).unwrap() {
+            assert_eq!(region.unwrap().protection(), expected);
+        }
+    }
+
+    #[test]
+    /// Tests every protection mode against read-only data
+    fn test_mode_read_only() {
+        // Note: this uses a private mapping rather than a global so it can't race with `test_perms` over the same page
+        let data =
+            mmap::MemoryMap::new(region::page::size(), &[mmap::MapOption::MapReadable]).unwrap();
+        for mode in [
+            ProtectionMode::Always,
+            ProtectionMode::SkipWritable,
+            ProtectionMode::SkipWritableExecutable,
+        ] {
+            patch_with_mode(data.data(), mode, Protection::READ);
+        }
+    }
+
+    #[test]
+    /// Tests every protection mode against writable, non-executable data
+    fn test_mode_writable() {
+        let mut data = vec![1u8, 2, 3, 4];
+        for mode in [
+            ProtectionMode::Always,
+            ProtectionMode::SkipWritable,
+            ProtectionMode::SkipWritableExecutable,
+        ] {
+            patch_with_mode(data.as_mut_ptr(), mode, Protection::READ_WRITE);
+        }
+    }
+
+    #[test]
+    /// Tests every protection mode against writable and executable memory
+    fn test_mode_writable_executable() {
+        // Note: executable allocations are only RWX while they share a page with finalized code, so this maps its own RWX page
+        let data = mmap::MemoryMap::new(
+            region::page::size(),
+            &[
+                mmap::MapOption::MapReadable,
+                mmap::MapOption::MapWritable,
+                mmap::MapOption::MapExecutable,
+            ],
+        )
+        .unwrap();
+        unsafe { data.data().copy_from([1, 2, 3, 4].as_ptr(), 4) };
+        for mode in [
+            ProtectionMode::Always,
+            ProtectionMode::SkipWritable,
+            ProtectionMode::SkipWritableExecutable,
+        ] {
+            patch_with_mode(data.data(), mode, Protection::READ_WRITE_EXECUTE);
+        }
+    }
+
+    #[test]
+    /// Tests that skipping RWX memory writes to a fresh RWX buffer without changing its permissions
+    fn test_skip_rwx() {
+        let data = mmap::MemoryMap::new(
+            region::page::size(),
+            &[
+                mmap::MapOption::MapReadable,
+                mmap::MapOption::MapWritable,
+                mmap::MapOption::MapExecutable,
+            ],
+        )
+        .unwrap();
+        assert!(ProtectionMode::default()
+            .needs_protect(data.data(), 4)
+            .unwrap());
+        let mode = ProtectionMode::SkipWritableExecutable;
+        assert!(!mode.needs_protect(data.data(), 4).unwrap());
+
+        let wrapper = PermissionWrapper::with_mode(BytePatcher::new(), mode);
+        let patch = unsafe { wrapper.patch(data.data(), &[4, 3, 2, 1]).unwrap() };
+        assert_eq!(
+            unsafe { slice::from_raw_parts(data.data(), 4) },
+            [4, 3, 2, 1]
+        );
+        drop(patch);
+        assert_eq!(unsafe { slice::from_raw_parts(data.data(), 4) }, [0; 4]);
+    }
+
+    #[test]
+    /// Tests that a patch across regions with different protections restores each region's own protection
+    fn test_mixed_protections() {
+        let page = region::page::size();
+        let data = mmap::MemoryMap::new(
+            page * 2,
+            &[mmap::MapOption::MapReadable, mmap::MapOption::MapWritable],
+        )
+        .unwrap();
+        let boundary = unsafe { data.data().add(page) };
+        let start = unsafe { boundary.sub(2) };
+        unsafe { region::protect(data.data(), page, Protection::READ_EXECUTE) }.unwrap();
+        unsafe { region::protect(boundary, page, Protection::READ) }.unwrap();
+
+        let wrapper = PermissionWrapper::new(BytePatcher::new());
+        let patch = unsafe { wrapper.patch(start, &[1, 2, 3, 4]) }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(start, 4) }, [1, 2, 3, 4]);
+        assert_eq!(
+            region::query(start).unwrap().protection(),
+            Protection::READ_EXECUTE
+        );
+        assert_eq!(
+            region::query(boundary).unwrap().protection(),
+            Protection::READ
+        );
+
+        drop(patch);
+        assert_eq!(unsafe { slice::from_raw_parts(start, 4) }, [0; 4]);
+        assert_eq!(
+            region::query(start).unwrap().protection(),
+            Protection::READ_EXECUTE
+        );
+        assert_eq!(
+            region::query(boundary).unwrap().protection(),
+            Protection::READ
+        );
+    }
+
+    #[test]
+    /// Tests that dropping a guard for memory that's no longer mapped skips restoring instead of faulting
+    fn test_unmapped() {
+        let vec = vec![1u8, 2, 3, 4];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        // The first page is never mapped, so it stands in for memory that was unmapped while the guard was alive
+        let unmapped = region::page::size() as *const u8;
+        assert!(matches!(
+            region::query(unmapped),
+            Err(region::Error::UnmappedRegion)
+        ));
+
+        for mode in [
+            ProtectionMode::Always,
+            ProtectionMode::SkipWritable,
+            ProtectionMode::SkipWritableExecutable,
+        ] {
+            let patch = unsafe { BytePatcher::new().patch(ptr, &[4, 3, 2, 1]).unwrap() };
+            let guard = PermissionWrapperGuard::guard(patch, unmapped, size, mode);
+
+            // restoring must not be attempted, so the patch stays in place
+            guard.restore();
+            assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [4, 3, 2, 1]);
+
+            unsafe { ptr.copy_from([1, 2, 3, 4].as_ptr(), size) };
+        }
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
 }
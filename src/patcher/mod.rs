@@ -1,10 +1,42 @@
 //! # Patch
 //!
 //! This module covers patchers, which are used to overwrite and restore locations in memory
+//!
+//! ## Composition order
+//!
+//! Patchers are stacked by wrapping one in another, and the nesting decides the order things happen in.
+//! Each patcher reports where it belongs with [`Patcher::STAGE`]:
+//!
+//! 1. Code is decoded and relocated ([`CodePatcher`](code::CodePatcher), [`RelPatcher`](rel::RelPatcher)). These aren't [`Patcher`]s themselves, and they wrap their patcher in a [`PermissionWrapper`](mem::PermissionWrapper) automatically
+//! 2. Memory is made writable ([`PatchStage::Protect`]: [`PermissionWrapper`](mem::PermissionWrapper)). [`BatchPatcher`](batch::BatchPatcher) also changes permissions, but isn't a [`Patcher`] itself; like [`PermissionWrapper`](mem::PermissionWrapper), it only wraps [`PatchStage::Write`] patchers
+//! 3. Bytes are written, optionally recording what they overwrite ([`PatchStage::Write`]: [`BytePatcher`](byte::BytePatcher), [`UndoPatcher`](undo::UndoPatcher), [`JournalingPatcher`](journal::JournalingPatcher))
+//!
+//! Once the guard is dropped, the same steps run in reverse.
+//! Changing permissions around a patcher that already changes them is always a mistake (e.g. passing a [`PermissionWrapper`](mem::PermissionWrapper) to a [`CodePatcher`](code::CodePatcher)), so it fails to compile.
 
+pub mod batch;
 pub mod byte;
+#[cfg(feature = "trampolines")]
 pub mod code;
+#[cfg(all(unix, feature = "emergency"))]
+pub mod emergency;
+pub mod journal;
 pub mod mem;
+pub mod nop;
+pub mod rel;
+pub mod slice;
+pub mod undo;
+
+/// Step of applying a patch that a [`Patcher`] performs. See [the composition order](self#composition-order)
+///
+/// The stages are fixed by this crate, so every patcher is one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchStage {
+    /// Writes bytes (or forwards them to a patcher that does) without changing memory permissions
+    Write,
+    /// Changes memory permissions around a patcher that writes the bytes
+    Protect,
+}
 
 /// All patchers save state from where they patched and are able to revert on-command
 ///
@@ -18,6 +50,11 @@ pub unsafe trait Patcher {
     type Guard<'a>: PatchGuard + 'a
     where
         Self: 'a;
+    /// Step of applying a patch that this patcher performs
+    ///
+    /// There's no default, so every patcher has to say whether it changes permissions.
+    /// Patchers that wrap another patcher without changing permissions should report the stage of the patcher they wrap.
+    const STAGE: PatchStage;
 
     /// Patches a given location.
     ///
@@ -37,6 +74,14 @@ pub unsafe trait Patcher {
 ///
 /// Guard must fully unpatch the location when dropped, even if `restore` is not called
 pub unsafe trait PatchGuard: Sized {
+    /// Gets the start of the patched memory
+    fn location(&self) -> *const u8;
+    /// Gets the number of patched bytes
+    fn len(&self) -> usize;
+    /// Returns `true` if the patch is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     /// Restores the original value of a patch
     fn restore(self) {
         // most implementations have their functionality in their [`Drop::drop`] implementation
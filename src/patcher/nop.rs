@@ -0,0 +1,89 @@
+//! This module contains a patcher which blanks out code with NOPs
+//!
+//! Removing a call or a few instructions ("nopping them out") only needs the length of the code to remove, not the bytes to write.
+//! [`NopPatcher`] fills that length with [`multi_byte_nops`], the same padding [`CodePatcher`](super::code::CodePatcher) uses, so disassemblers show a few whole NOPs rather than a run of single bytes or garbage.
+
+use crate::code::multi_byte_nops;
+
+use super::byte::{BytePatchGuard, BytePatcher};
+use super::{PatchStage, Patcher};
+
+/// Patcher that overwrites code with NOPs
+///
+/// Only the length of the patch passed to [`Patcher::patch`] is used; its contents are ignored.
+/// The NOPs are valid in both 32 and 64-bit code. Patches are restored like [`BytePatcher`]'s.
+#[derive(Default, Clone, Copy)]
+pub struct NopPatcher;
+impl NopPatcher {
+    /// Creates a new [`NopPatcher`]
+    pub fn new() -> Self {
+        Self
+    }
+    /// Overwrites `len` bytes at `location` with NOPs
+    ///
+    /// # Safety
+    ///
+    /// `location` must be valid and writable for `len` bytes, and `len` should end on an instruction boundary
+    pub unsafe fn nop_out(&self, location: *mut u8, len: usize) -> BytePatchGuard {
+        // `BytePatcher` never fails
+        BytePatcher::new()
+            .patch(location, &multi_byte_nops(len))
+            .unwrap()
+    }
+}
+unsafe impl Patcher for NopPatcher {
+    type Error = ();
+    type Guard<'a>
+        = BytePatchGuard
+    where
+        Self: 'a;
+    const STAGE: PatchStage = PatchStage::Write;
+
+    unsafe fn patch<'a>(
+        &'a self,
+        location: *mut u8,
+        patch: &[u8],
+    ) -> Result<Self::Guard<'a>, Self::Error> {
+        Ok(self.nop_out(location, patch.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use iced_x86::{Code, Decoder, DecoderOptions};
+
+    use crate::patcher::nop::NopPatcher;
+    use crate::patcher::{PatchGuard, Patcher};
+
+    #[test]
+    /// Tests nopping out a call and the instruction after it, then restoring them
+    fn test_nop_out() {
+        let code = [
+            0xe8, 0x00, 0x10, 0x00, 0x00, // call rel32
+            0x89, 0xe5, // mov ebp, esp
+            0xc3, // ret
+        ];
+        let vec = code.to_vec();
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let guard = unsafe { NopPatcher::new().patch(ptr, &[0; 7]) }.unwrap();
+        assert_eq!(guard.len(), 7);
+
+        // the 7 bytes decode as a single NOP, followed by the untouched `ret`
+        let patched = unsafe { slice::from_raw_parts(ptr, size) };
+        let mut decoder = Decoder::new(64, patched, DecoderOptions::NONE);
+        let instructions: Vec<_> = decoder.iter().collect();
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[0].code(), Code::Nop_rm32);
+        assert_eq!(instructions[0].len(), 7);
+        assert_eq!(instructions[1].code(), Code::Retnq);
+
+        guard.restore();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, code);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
@@ -0,0 +1,125 @@
+//! This module contains a patcher for retargeting relative operands, such as the displacement of a `call rel32` or `jmp rel32`
+//!
+//! Like [`CodePatcher`](super::code::CodePatcher), [`RelPatcher`] does *not* implement [`Patcher`], since it takes a target address rather than raw bytes.
+
+use thiserror::Error;
+
+use crate::code::displacement;
+
+use super::byte::BytePatcher;
+use super::mem::{PermissionError, PermissionWrapper};
+use super::Patcher;
+
+/// Size of a rel32 operand
+const REL32_LEN: usize = 4;
+
+/// Error types for `RelPatcher`
+#[derive(Debug, Error)]
+pub enum RelError<E> {
+    /// Error while writing data to memory
+    #[error("{0}")]
+    PermissionError(#[from] PermissionError<E>),
+    /// The target is too far away to be reached with a rel32 displacement
+    #[error("Target {0:?} is out of range of the operand at {1:?}")]
+    OutOfRange(*const (), *const ()),
+}
+
+/// Patcher for rewriting rel32 operands to point to a new absolute target
+///
+/// The displacement is calculated relative to the end of the operand, which is the address of the next instruction for `call rel32`, `jmp rel32`, and `jcc rel32`.
+///
+/// Because code is often read-only, this patcher wraps the main patcher with a `PermissionWrapper` automatically
+pub struct RelPatcher<P: Patcher> {
+    /// Internal patcher that will actually write the displacement
+    patcher: PermissionWrapper<P>,
+}
+impl<P> RelPatcher<P>
+where
+    P: Patcher,
+    PermissionError<P::Error>: From<P::Error>,
+{
+    /// Creates a new RelPatcher
+    ///
+    /// Note: The patcher will be wrapped in a [`PermissionWrapper`], so there is no need to wrap it yourself
+    pub fn new(patcher: P) -> Self {
+        Self {
+            patcher: PermissionWrapper::new(patcher),
+        }
+    }
+    /// Rewrites the rel32 operand at `operand` so the instruction it belongs to targets `target`, returning a guard for the patch
+    ///
+    /// # Safety
+    ///
+    /// - `operand` must point to the rel32 operand of an instruction, and be valid for 4 bytes
+    /// - The operand must be the last part of the instruction, since the displacement is calculated from the end of the operand
+    pub unsafe fn patch(
+        &self,
+        operand: *mut u8,
+        target: usize,
+    ) -> Result<<PermissionWrapper<P> as Patcher>::Guard<'_>, RelError<P::Error>> {
+        let displacement = displacement::<i32>(operand as usize + REL32_LEN, target)
+            .ok_or(RelError::OutOfRange(target as _, operand as _))?;
+        Ok(self.patcher.patch(operand, &displacement.to_le_bytes())?)
+    }
+}
+
+/// Patcher for rewriting rel32 operands with a [`BytePatcher`]
+pub type ByteRelPatcher = RelPatcher<BytePatcher>;
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::rel::{RelError, RelPatcher};
+    use crate::patcher::PatchGuard;
+
+    #[test]
+    /// Test patch and revert of a `call rel32`
+    fn test_patch() {
+        // call $+5
+        let vec = vec![0xe8u8, 0x00, 0x00, 0x00, 0x00];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+        let operand = (ptr as usize + 1) as *mut u8;
+
+        let patcher = RelPatcher::new(BytePatcher::new());
+
+        // retarget the call 0x100 bytes after the end of the instruction
+        let patch = unsafe { patcher.patch(operand, ptr as usize + 5 + 0x100) }.unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0xe8, 0x00, 0x01, 0x00, 0x00]
+        );
+
+        // restore the patch
+        patch.restore();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0xe8, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        // backwards targets are negative displacements
+        let patch = unsafe { patcher.patch(operand, ptr as usize - 0x10) }.unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr, size) },
+            [0xe8, 0xeb, 0xff, 0xff, 0xff]
+        );
+        drop(patch);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests that targets out of rel32 range are rejected without patching
+    fn test_out_of_range() {
+        let mut code = [0xe8u8, 0x00, 0x00, 0x00, 0x00];
+        let operand = unsafe { code.as_mut_ptr().add(1) };
+
+        let patcher = RelPatcher::new(BytePatcher::new());
+        let result = unsafe { patcher.patch(operand, operand as usize + 0x1_0000_0000) };
+
+        assert!(matches!(result, Err(RelError::OutOfRange(..))));
+        assert_eq!(code, [0xe8, 0x00, 0x00, 0x00, 0x00]);
+    }
+}
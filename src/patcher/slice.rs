@@ -0,0 +1,186 @@
+//! This module contains a patcher for byte buffers
+//!
+//! [`SlicePatcher`] patches a `&mut [u8]` by offset, so buffers (e.g. a file image that hasn't been mapped yet) can be patched without raw pointers or `unsafe`.
+//! It also implements [`Patcher`] for pointers into the buffer, so hooks can be tested against plain buffers.
+
+use std::cell::{Ref, RefCell};
+
+use thiserror::Error;
+
+use super::{PatchGuard, PatchStage, Patcher};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+/// Error types for [`SlicePatcher`]
+pub enum SliceError {
+    /// The patch doesn't fit in the buffer at the requested offset
+    #[error("Patch of {len} bytes at offset {offset:#x} is out of bounds (buffer length: {buffer_len:#x})")]
+    OutOfBounds {
+        /// Offset of the patch
+        offset: usize,
+        /// Length of the patch
+        len: usize,
+        /// Length of the buffer
+        buffer_len: usize,
+    },
+}
+
+/// Patcher for byte buffers
+///
+/// Patches are restored when their guard is dropped, like any other patcher.
+pub struct SlicePatcher<'a> {
+    /// Buffer being patched
+    buffer: RefCell<&'a mut [u8]>,
+}
+impl<'a> SlicePatcher<'a> {
+    /// Creates a new patcher for `buffer`
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer: RefCell::new(buffer),
+        }
+    }
+    /// Gets the current contents of the buffer, patches included
+    ///
+    /// # Panics
+    ///
+    /// Guards restore the buffer when dropped, which panics if the returned reference is still alive
+    pub fn buffer(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.buffer.borrow(), |buffer| &**buffer)
+    }
+    /// Patches the buffer with `patch` starting at `offset`
+    pub fn patch_at(
+        &self,
+        offset: usize,
+        patch: &[u8],
+    ) -> Result<SlicePatchGuard<'_, 'a>, SliceError> {
+        let mut buffer = self.buffer.borrow_mut();
+        let range = offset
+            .checked_add(patch.len())
+            .filter(|&end| end <= buffer.len())
+            .map(|end| offset..end)
+            .ok_or(SliceError::OutOfBounds {
+                offset,
+                len: patch.len(),
+                buffer_len: buffer.len(),
+            })?;
+
+        let original = buffer[range.clone()].to_vec();
+        buffer[range].copy_from_slice(patch);
+
+        Ok(SlicePatchGuard {
+            patcher: self,
+            offset,
+            original,
+        })
+    }
+}
+unsafe impl<'a> Patcher for SlicePatcher<'a> {
+    type Error = SliceError;
+    type Guard<'b>
+        = SlicePatchGuard<'b, 'a>
+    where
+        Self: 'b;
+    const STAGE: PatchStage = PatchStage::Write;
+
+    /// Patches the buffer at `target`, which must point into the buffer
+    ///
+    /// Pointers outside the buffer are reported as [`SliceError::OutOfBounds`] rather than written to.
+    unsafe fn patch<'b>(
+        &'b self,
+        target: *mut u8,
+        patch: &[u8],
+    ) -> Result<Self::Guard<'b>, Self::Error> {
+        let start = self.buffer.borrow().as_ptr() as usize;
+        // Pointers before the buffer wrap around to an offset that's out of bounds
+        self.patch_at((target as usize).wrapping_sub(start), patch)
+    }
+}
+
+/// Guard for patches made by a [`SlicePatcher`]
+pub struct SlicePatchGuard<'p, 'a> {
+    /// Patcher owning the patched buffer
+    patcher: &'p SlicePatcher<'a>,
+    /// Offset of the patch in the buffer
+    offset: usize,
+    /// Bytes that were overwritten by the patch
+    original: Vec<u8>,
+}
+impl SlicePatchGuard<'_, '_> {
+    /// Gets the offset of the patch in the buffer
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+unsafe impl PatchGuard for SlicePatchGuard<'_, '_> {
+    fn location(&self) -> *const u8 {
+        self.patcher.buffer.borrow()[self.offset..].as_ptr()
+    }
+    fn len(&self) -> usize {
+        self.original.len()
+    }
+}
+impl Drop for SlicePatchGuard<'_, '_> {
+    fn drop(&mut self) {
+        self.patcher.buffer.borrow_mut()[self.offset..self.offset + self.original.len()]
+            .copy_from_slice(&self.original);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::{JmpHook, JmpHookError};
+    use crate::hook::Hook;
+    use crate::patcher::slice::{SliceError, SlicePatcher};
+    use crate::patcher::{PatchGuard, Patcher};
+
+    #[test]
+    /// Tests patching and restoring a buffer by offset
+    fn test_patch_at() {
+        let mut buffer = *b"slice patcher";
+        let patcher = SlicePatcher::new(&mut buffer);
+
+        let first = patcher.patch_at(0, b"SLICE").unwrap();
+        let second = patcher.patch_at(6, b"PATCHER").unwrap();
+        assert_eq!(&*patcher.buffer(), b"SLICE PATCHER");
+        assert_eq!(second.offset(), 6);
+        assert_eq!(second.len(), 7);
+
+        first.restore();
+        assert_eq!(&*patcher.buffer(), b"slice PATCHER");
+        drop(second);
+        assert_eq!(&*patcher.buffer(), b"slice patcher");
+
+        assert_eq!(
+            patcher.patch_at(10, b"patch").err(),
+            Some(SliceError::OutOfBounds {
+                offset: 10,
+                len: 5,
+                buffer_len: 13
+            })
+        );
+        assert!(patcher.patch_at(usize::MAX, b"x").is_err());
+    }
+
+    #[test]
+    /// Tests patching through pointers, which must point into the buffer
+    fn test_patch_pointer() {
+        let mut buffer = [0x90u8; 16];
+        let ptr = buffer.as_mut_ptr();
+        let patcher = SlicePatcher::new(&mut buffer);
+
+        let guard = unsafe { patcher.patch(ptr.wrapping_add(2), &jmp_abs(0x1234)) }.unwrap();
+        assert_eq!(guard.offset(), 2);
+        assert_eq!(guard.location(), ptr.wrapping_add(2) as *const u8);
+        assert_eq!(patcher.buffer()[2..], jmp_abs(0x1234));
+        drop(guard);
+        assert_eq!(&*patcher.buffer(), [0x90; 16]);
+
+        // hooks report pointers outside the buffer instead of writing to them
+        let hook = JmpHook::new(patcher);
+        let result = unsafe { hook.hook(ptr.wrapping_sub(1), 0x1234 as _) };
+        assert!(matches!(
+            result,
+            Err(JmpHookError::PatcherError(SliceError::OutOfBounds { .. }))
+        ));
+    }
+}
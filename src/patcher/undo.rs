@@ -0,0 +1,197 @@
+//! This module contains a patcher which hands out undo tokens for its patches
+//!
+//! Dropping a guard restores its patch when the guard goes out of scope, which forces patches to be undone in scope order.
+//! An [`UndoToken`] holds the restore data for a patch separately from its guard, so tools with an undo stack can restore any patch at any time.
+
+use std::slice;
+
+use super::byte::write_fenced;
+use super::mem::{to_mut, with_writable, PermissionError};
+use super::{PatchGuard, PatchStage, Patcher};
+
+/// Restore data for a single patch
+///
+/// Undoing writes the bytes from before the patch back to its location, whether or not the patch's guard is still alive.
+/// If the guard is dropped afterwards, it writes the same bytes again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoToken {
+    /// Location that was patched
+    location: *const u8,
+    /// Bytes at `location` before it was patched
+    original: Vec<u8>,
+    /// Whether each byte of `original` is written back. Bytes that were already restored are skipped
+    patched: Vec<bool>,
+}
+impl UndoToken {
+    /// Creates restore data for a patch at `location` that overwrote `original`, where only the bytes marked in `patched` are still patched
+    pub(crate) fn new(location: *const u8, original: Vec<u8>, patched: Vec<bool>) -> Self {
+        Self {
+            location,
+            original,
+            patched,
+        }
+    }
+    /// Gets the patched location
+    pub fn location(&self) -> *const u8 {
+        self.location
+    }
+    /// Gets the bytes from before the patch
+    ///
+    /// Bytes that were already restored before the token was made (see [`BytePatchGuard::into_undo`](super::byte::BytePatchGuard::into_undo)) aren't written back by [`UndoToken::undo`].
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+    /// Writes the original bytes back to the patched location
+    ///
+    /// The location is made writable for the write the same way [`PermissionWrapper`](super::mem::PermissionWrapper) does, so this works on read-only and executable memory.
+    /// Any patch applied over the same bytes since this one is overwritten as well.
+    ///
+    /// # Safety
+    ///
+    /// The patched location must still be mapped, and nothing may be executing the bytes being restored
+    pub unsafe fn undo(self) -> Result<(), PermissionError<()>> {
+        with_writable(self.location, self.original.len(), || {
+            let mut offset = 0;
+            while offset < self.original.len() {
+                if !self.patched[offset] {
+                    offset += 1;
+                    continue;
+                }
+                let start = offset;
+                while offset < self.original.len() && self.patched[offset] {
+                    offset += 1;
+                }
+                // Safety: the caller must ensure that the location is still mapped, and it was made writable for the write
+                write_fenced(
+                    &self.original[start..offset],
+                    to_mut(self.location.add(start)),
+                );
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Patcher that records restore data for every patch so it can be undone out of order
+///
+/// See [`UndoPatchGuard::undo_token`].
+pub struct UndoPatcher<P> {
+    /// Internal patcher that will actually write the data
+    patcher: P,
+}
+impl<P: Patcher> UndoPatcher<P> {
+    /// Creates a new undo patcher wrapping `patcher`
+    pub fn new(patcher: P) -> Self {
+        Self { patcher }
+    }
+}
+unsafe impl<P: Patcher> Patcher for UndoPatcher<P> {
+    type Error = P::Error;
+    type Guard<'a>
+        = UndoPatchGuard<P::Guard<'a>>
+    where
+        Self: 'a;
+    const STAGE: PatchStage = P::STAGE;
+
+    unsafe fn patch<'a>(
+        &'a self,
+        target: *mut u8,
+        patch: &[u8],
+    ) -> Result<Self::Guard<'a>, Self::Error> {
+        // Safety: the caller must ensure that `target` is valid for the length of the patch
+        let original = slice::from_raw_parts(target, patch.len()).to_vec();
+
+        self.patcher
+            .patch(target, patch)
+            .map(|guard| UndoPatchGuard {
+                guard,
+                token: UndoToken::new(target, original, vec![true; patch.len()]),
+            })
+    }
+}
+
+/// Guard for patches made by an [`UndoPatcher`]
+pub struct UndoPatchGuard<G: PatchGuard> {
+    /// Underlying patch guard
+    guard: G,
+    /// Restore data for the patch
+    token: UndoToken,
+}
+impl<G: PatchGuard> UndoPatchGuard<G> {
+    /// Gets a token that restores this patch when undone
+    ///
+    /// Tokens can be taken any number of times and outlive the guard.
+    pub fn undo_token(&self) -> UndoToken {
+        self.token.clone()
+    }
+}
+unsafe impl<G: PatchGuard> PatchGuard for UndoPatchGuard<G> {
+    fn location(&self) -> *const u8 {
+        self.guard.location()
+    }
+    fn len(&self) -> usize {
+        self.guard.len()
+    }
+    fn restore(self) {
+        self.guard.restore();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::hook::jmphook::JmpHook;
+    use crate::hook::Hook;
+    use crate::patcher::byte::BytePatcher;
+    use crate::patcher::undo::UndoPatcher;
+    use crate::patcher::Patcher;
+
+    #[test]
+    /// Tests that tokens undo their own patch in any order, while the guards are still alive
+    fn test_undo_out_of_order() {
+        let vec = b"undo order".to_vec();
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let patcher = UndoPatcher::new(BytePatcher::new());
+        let first = unsafe { patcher.patch(ptr, b"UN") }.unwrap();
+        let second = unsafe { patcher.patch(ptr.add(5), b"ORDER") }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"UNdo ORDER");
+
+        // undo the first patch before the second, unlike drop order
+        unsafe { first.undo_token().undo() }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"undo ORDER");
+
+        unsafe { second.undo_token().undo() }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"undo order");
+
+        // dropping the guards afterwards doesn't change anything
+        drop(first);
+        drop(second);
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, b"undo order");
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+
+    #[test]
+    /// Tests undoing a hook through its patch guard
+    fn test_undo_hook() {
+        let vec = vec![0x90u8; 16];
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = JmpHook::new(UndoPatcher::new(BytePatcher::new()));
+        let guard = unsafe { hook.hook(ptr, 0x1234 as _) }.unwrap();
+        let token = guard.patch().undo_token();
+        assert_eq!(token.location(), ptr as *const u8);
+        assert_eq!(token.original(), [0x90; 14]);
+
+        unsafe { token.undo() }.unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(ptr, size) }, [0x90; 16]);
+
+        drop(guard);
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
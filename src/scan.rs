@@ -0,0 +1,134 @@
+//! # Scan
+//!
+//! This module covers locating code by byte pattern, for targets that don't export symbols.
+//!
+//! Patterns are written as a slice of `Option<u8>`, where `None` matches any byte.
+//!
+//! The module is passed as a raw range rather than a slice, since a match is usually patched afterwards, and writing through a pointer derived from a live `&[u8]` is undefined behavior.
+
+use std::slice;
+
+use thiserror::Error;
+
+use crate::hook::Hook;
+
+#[derive(Debug, Error)]
+/// Error types for [`hook_pattern`]
+pub enum ScanError<E> {
+    /// The pattern wasn't found in the module
+    #[error("pattern not found")]
+    NotFound,
+    /// Error while hooking the match
+    #[error("hook error")]
+    HookError(E),
+}
+
+/// Finds the first location in the `len` bytes at `module` that matches `pattern`
+///
+/// Returns `None` if the pattern is empty or doesn't match anywhere.
+///
+/// # Safety
+///
+/// `module` must be valid for reads of `len` bytes, and nothing may write to them during the scan
+pub unsafe fn scan(module: *const u8, len: usize, pattern: &[Option<u8>]) -> Option<*const u8> {
+    if pattern.is_empty() {
+        return None;
+    }
+    // Safety: the caller must ensure that `module` is valid for `len` bytes. The slice doesn't outlive the scan
+    let bytes = slice::from_raw_parts(module, len);
+    bytes
+        .windows(pattern.len())
+        .position(|window| {
+            window
+                .iter()
+                .zip(pattern)
+                .all(|(byte, expected)| expected.is_none_or(|expected| *byte == expected))
+        })
+        .map(|offset| module.add(offset))
+}
+
+/// Hooks the first location in the `len` bytes at `module` that matches `pattern`, redirecting it to `destination`
+///
+/// # Safety
+///
+/// - `module` must be valid for reads of `len` bytes (see [`scan`])
+/// - The match must be a valid location for `hook` (see [`Hook::hook`])
+/// - `destination` must be valid executable code
+pub unsafe fn hook_pattern<'a, H: Hook>(
+    hook: &'a H,
+    module: *const u8,
+    len: usize,
+    pattern: &[Option<u8>],
+    destination: *const u8,
+) -> Result<H::Guard<'a>, ScanError<H::Error>> {
+    let source = scan(module, len, pattern).ok_or(ScanError::NotFound)?;
+    hook.hook(source, destination).map_err(ScanError::HookError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::code::x64::jmp_abs;
+    use crate::hook::jmphook::JmpHook;
+    use crate::patcher::byte::BytePatcher;
+    use crate::scan::{hook_pattern, scan, ScanError};
+
+    #[test]
+    /// Tests matching with and without wildcards
+    fn test_scan() {
+        let module = [0x90, 0x55, 0x48, 0x89, 0xe5, 0x55, 0x48, 0x8b, 0xec, 0xc3];
+        let (ptr, len) = (module.as_ptr(), module.len());
+
+        assert_eq!(
+            unsafe { scan(ptr, len, &[Some(0x55), Some(0x48), Some(0x8b)]) },
+            Some(ptr.wrapping_add(5))
+        );
+        assert_eq!(
+            unsafe { scan(ptr, len, &[Some(0x55), Some(0x48), None, Some(0xec)]) },
+            Some(ptr.wrapping_add(5))
+        );
+        assert_eq!(
+            unsafe { scan(ptr, len, &[Some(0x55), None]) },
+            Some(ptr.wrapping_add(1))
+        );
+        assert_eq!(unsafe { scan(ptr, len, &[Some(0xc3), Some(0x90)]) }, None);
+        assert_eq!(unsafe { scan(ptr, len, &[]) }, None);
+        // matches can't run past the end of the range
+        assert_eq!(
+            unsafe { scan(ptr, len - 1, &[Some(0xec), Some(0xc3)]) },
+            None
+        );
+    }
+
+    #[test]
+    /// Tests hooking the location found by a pattern
+    fn test_hook_pattern() {
+        let mut vec = vec![0x90; 32];
+        vec[8..12].copy_from_slice(&[0x55, 0x48, 0x89, 0xe5]);
+        let (ptr, size, capacity) = vec.into_raw_parts();
+
+        let hook = JmpHook::new(BytePatcher::new());
+        let pattern = [Some(0x55), Some(0x48), None, Some(0xe5)];
+
+        let guard = unsafe { hook_pattern(&hook, ptr, size, &pattern, 0x1234 as _) }.unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(ptr.add(8), 14) },
+            jmp_abs(0x1234)
+        );
+        drop(guard);
+
+        // the pattern is back once the hook is restored
+        assert_eq!(
+            unsafe { scan(ptr, size, &pattern) },
+            Some(unsafe { ptr.add(8) } as *const u8)
+        );
+
+        let missing = [Some(0xaa), Some(0xbb)];
+        let result = unsafe { hook_pattern(&hook, ptr, size, &missing, 0x1234 as _) };
+        assert!(matches!(result, Err(ScanError::NotFound)));
+
+        // clean up
+        let _ = unsafe { Vec::from_raw_parts(ptr, size, capacity) };
+    }
+}
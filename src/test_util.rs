@@ -0,0 +1,67 @@
+//! # Test utilities
+//!
+//! This module contains helpers shared between unit tests that need to run generated code
+
+use std::marker::PhantomData;
+use std::mem;
+
+use crate::alloc::{allocate_executable, ExecutableMemory, Finalized};
+
+/// Machine code in executable memory that can be called as `F`
+///
+/// The code is freed when this is dropped, so keep it alive for as long as the function may be called.
+pub struct TestFn<F> {
+    /// Memory holding the code
+    memory: ExecutableMemory<Finalized>,
+    /// Type of the function
+    _fn: PhantomData<F>,
+}
+impl<F: Copy> TestFn<F> {
+    /// Copies `code` into newly allocated executable memory
+    ///
+    /// # Panics
+    ///
+    /// Panics if `F` isn't pointer-sized, or the memory can't be allocated or made executable
+    pub fn new(code: &[u8]) -> Self {
+        assert_eq!(
+            mem::size_of::<F>(),
+            mem::size_of::<*const u8>(),
+            "test functions must be called through a fn pointer"
+        );
+
+        // Allocated near the test binary so relative jumps from the code can reach it
+        let origin = Self::new as *const () as usize;
+        let mut memory = allocate_executable(origin, code.len()).unwrap();
+        memory.copy_from_slice(code);
+
+        Self {
+            memory: memory.finalize().unwrap(),
+            _fn: PhantomData,
+        }
+    }
+    /// Gets the address of the code
+    pub fn ptr(&self) -> *const u8 {
+        self.memory.exec_ptr()
+    }
+    /// Gets the code as a callable function
+    ///
+    /// # Safety
+    ///
+    /// The code must be a valid function with the signature of `F`, and must not be called after `self` is dropped
+    pub unsafe fn get(&self) -> F {
+        mem::transmute_copy(&self.ptr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TestFn;
+
+    #[test]
+    /// Tests that the generated function can be called
+    fn test_call() {
+        // lea eax, [rdi + rsi]; ret
+        let add = TestFn::<extern "C" fn(u32, u32) -> u32>::new(&[0x8d, 0x04, 0x37, 0xc3]);
+        assert_eq!(unsafe { add.get() }(2, 3), 5);
+    }
+}
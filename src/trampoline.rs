@@ -0,0 +1,118 @@
+//! # Trampoline
+//!
+//! This module provides typed wrappers for calling code through raw pointers, such as the pointer returned by [`CodePatcher::original`](crate::patcher::code::CodePatcher::original)
+//!
+//! Wrappers are declared once with [`trampoline_fn!`](crate::trampoline_fn) and can then be called without transmuting the pointer for every call.
+
+/// Declares a typed wrapper around a pointer to a function with the given signature
+///
+/// The generated type has an `unsafe fn new(ptr: *const u8)` constructor and a `call` method with the declared arguments.
+/// Arguments must be named so that `call` can forward them.
+///
+/// ```
+/// use libhook::trampoline_fn;
+///
+/// trampoline_fn! {
+///     /// Trampoline for an `add` function
+///     pub struct AddFn(extern "C" fn(a: i32, b: i32) -> i32);
+/// }
+///
+/// extern "C" fn add(a: i32, b: i32) -> i32 {
+///     a + b
+/// }
+///
+/// let add_fn = unsafe { AddFn::new(add as *const u8) };
+/// assert_eq!(add_fn.call(1, 2), 3);
+/// ```
+#[macro_export]
+macro_rules! trampoline_fn {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident(extern $abi:literal fn($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?);
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug)]
+        $vis struct $name(*const u8);
+        impl $name {
+            /// Wraps a pointer to a function with this signature
+            ///
+            /// # Safety
+            ///
+            /// `ptr` must point to executable code with this signature for as long as the wrapper is used
+            $vis unsafe fn new(ptr: *const u8) -> Self {
+                Self(ptr)
+            }
+            /// Returns the wrapped pointer
+            $vis fn as_ptr(&self) -> *const u8 {
+                self.0
+            }
+            /// Calls the wrapped function
+            #[allow(clippy::too_many_arguments)]
+            $vis fn call(&self, $($arg: $ty),*) $(-> $ret)? {
+                // Safety: `new` requires the pointer to be a function with this signature
+                let function: extern $abi fn($($ty),*) $(-> $ret)? =
+                    unsafe { ::core::mem::transmute(self.0) };
+                function($($arg),*)
+            }
+        }
+    };
+}
+
+trampoline_fn! {
+    /// Trampoline for functions that take no arguments and return nothing
+    pub struct VoidFn(extern "C" fn());
+}
+
+trampoline_fn! {
+    /// Trampoline for functions that take a single pointer-sized argument and return a pointer-sized value
+    pub struct UsizeFn(extern "C" fn(arg: usize) -> usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::trampoline::{UsizeFn, VoidFn};
+
+    trampoline_fn! {
+        /// Trampoline used to test multiple arguments
+        struct AddFn(extern "C" fn(a: i32, b: i32) -> i32);
+    }
+
+    /// Set by [`set_called`]
+    static CALLED: AtomicBool = AtomicBool::new(false);
+
+    /// Test target with no arguments
+    extern "C" fn set_called() {
+        CALLED.store(true, Ordering::SeqCst);
+    }
+
+    /// Test target with a single argument
+    extern "C" fn double(arg: usize) -> usize {
+        arg * 2
+    }
+
+    /// Test target with multiple arguments
+    extern "C" fn add(a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    #[test]
+    /// Tests calling through the provided trampolines
+    fn test_provided() {
+        let void = unsafe { VoidFn::new(set_called as *const u8) };
+        void.call();
+        assert!(CALLED.load(Ordering::SeqCst));
+
+        let double = unsafe { UsizeFn::new(double as *const u8) };
+        assert_eq!(double.call(21), 42);
+    }
+
+    #[test]
+    /// Tests calling through a declared trampoline
+    fn test_declared() {
+        let add_fn = unsafe { AddFn::new(add as *const u8) };
+        assert_eq!(add_fn.as_ptr(), add as *const u8);
+        assert_eq!(add_fn.call(1, 2), 3);
+    }
+}
@@ -0,0 +1,347 @@
+//! # Trap
+//!
+//! This module contains the shared runtime for hooks that redirect execution with a trap (e.g. `int3` or a hardware breakpoint) rather than a jump.
+//!
+//! While any trap is registered, a `SIGTRAP` handler is installed for the whole process. When a registered address traps, the handler moves the instruction pointer to that address's detour.
+//! The handler is reference counted: the first registration installs it, and the handler that was there before is put back when the last registration is dropped.
+//! Traps at addresses that aren't registered are passed on to the previous handler.
+//!
+//! With the `step` feature, traps can also single-step the instruction they replaced instead of redirecting it. See [`step`].
+//! The same feature provides [`capture`], which steps a function's first instruction to record its bytes the first time it runs.
+//!
+//! Only Linux on x86_64 is supported. Windows (vectored exception handlers) isn't implemented yet.
+
+#[cfg(feature = "step")]
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{io, mem, ptr};
+
+use thiserror::Error;
+
+#[cfg(feature = "step")]
+pub mod capture;
+#[cfg(feature = "step")]
+pub mod step;
+
+/// Maximum number of traps that can be registered at once
+pub const MAX_TRAPS: usize = 64;
+
+/// `si_code` of hardware breakpoints, which trap before the instruction runs rather than after it
+const TRAP_HWBKPT: i32 = 4;
+/// `si_code` of single-step traps, raised once an instruction has run with the trap flag set
+#[cfg(feature = "step")]
+const TRAP_TRACE: i32 = 2;
+/// Trap flag in `EFLAGS`
+#[cfg(feature = "step")]
+const TRAP_FLAG: i64 = 0x100;
+
+#[derive(Debug, Error)]
+/// Error types for trap registration
+pub enum TrapError {
+    /// A trap is already registered at the address
+    #[error("A trap is already registered at {0:?}")]
+    AlreadyRegistered(*const ()),
+    /// Every one of the [`MAX_TRAPS`] slots is in use
+    #[error("Too many traps are registered")]
+    TooManyTraps,
+    /// Error installing or restoring the signal handler
+    #[error("{0}")]
+    SignalError(#[from] io::Error),
+}
+
+/// A registered trap. The slot is free while `address` is 0
+struct Slot {
+    /// Address that traps
+    address: AtomicUsize,
+    /// Address execution is redirected to
+    detour: AtomicUsize,
+    /// [`step::StepCallback`] to single-step with instead of redirecting, or 0
+    #[cfg(feature = "step")]
+    on_step: AtomicUsize,
+    /// Byte the `int3` replaced, put back while single-stepping
+    #[cfg(feature = "step")]
+    original: AtomicUsize,
+}
+
+/// Registered traps. These are atomics rather than behind a lock so the signal handler can read them
+static SLOTS: [Slot; MAX_TRAPS] = [const {
+    Slot {
+        address: AtomicUsize::new(0),
+        detour: AtomicUsize::new(0),
+        #[cfg(feature = "step")]
+        on_step: AtomicUsize::new(0),
+        #[cfg(feature = "step")]
+        original: AtomicUsize::new(0),
+    }
+}; MAX_TRAPS];
+
+#[cfg(feature = "step")]
+thread_local! {
+    /// Slot (plus one) of the trap this thread is single-stepping, or 0 if it isn't stepping
+    static STEPPING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Handler that was installed before ours (`sa_sigaction`), for traps that aren't registered
+static PREVIOUS_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+/// Flags of the handler that was installed before ours (`sa_flags`)
+static PREVIOUS_FLAGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Registration state. Only used outside of the signal handler
+struct Registrations {
+    /// Number of live registrations
+    count: usize,
+    /// Action to restore once the last registration is dropped
+    previous: Option<libc::sigaction>,
+}
+// Safety: `sigaction` only contains plain data and function pointers
+unsafe impl Send for Registrations {}
+
+/// Registration state, locked while registering or unregistering
+static REGISTRATIONS: Mutex<Registrations> = Mutex::new(Registrations {
+    count: 0,
+    previous: None,
+});
+
+/// Redirects execution that traps at `address` to `detour`
+///
+/// This only handles the trap. Writing the `int3` to `address` or setting a hardware breakpoint on it is up to the caller, and must only be done after registering.
+/// For `int3`, `address` is the location of the `int3` itself.
+///
+/// The trap is unregistered when the returned guard is dropped, so remove the `int3` or breakpoint before dropping it.
+pub fn register(address: *const u8, detour: *const u8) -> Result<TrapGuard, TrapError> {
+    register_slot(address, |slot| {
+        slot.detour.store(detour as usize, Ordering::Release);
+        #[cfg(feature = "step")]
+        slot.on_step.store(0, Ordering::Release);
+    })
+}
+
+/// Single-steps the instruction at `address` whenever its `int3` is hit, calling `on_step` once the instruction has run
+///
+/// `original` is the byte the `int3` replaced. It's written back while the instruction runs and the `int3` is written again afterwards,
+/// so `address` must stay writable for as long as the trap is registered.
+#[cfg(feature = "step")]
+pub(crate) fn register_step(
+    address: *const u8,
+    original: u8,
+    on_step: step::StepCallback,
+) -> Result<TrapGuard, TrapError> {
+    register_slot(address, |slot| {
+        slot.original.store(original as usize, Ordering::Release);
+        slot.on_step
+            .store(on_step as *const () as usize, Ordering::Release);
+    })
+}
+
+/// Claims a free slot for `address`, filling in the rest of the slot with `init` before it's made visible to the handler
+fn register_slot(address: *const u8, init: impl FnOnce(&Slot)) -> Result<TrapGuard, TrapError> {
+    let mut registrations = REGISTRATIONS.lock().unwrap();
+
+    if find(address as usize).is_some() {
+        return Err(TrapError::AlreadyRegistered(address as _));
+    }
+    let slot = SLOTS
+        .iter()
+        .position(|slot| slot.address.load(Ordering::Acquire) == 0)
+        .ok_or(TrapError::TooManyTraps)?;
+
+    if registrations.count == 0 {
+        // Safety: the handler only touches atomics and the trapping thread's context
+        registrations.previous = Some(unsafe { install() }?);
+    }
+    registrations.count += 1;
+
+    // The rest of the slot must be visible before the address, since the handler looks the slot up by address
+    init(&SLOTS[slot]);
+    SLOTS[slot]
+        .address
+        .store(address as usize, Ordering::Release);
+
+    Ok(TrapGuard { slot })
+}
+
+/// Guard for a registered trap
+///
+/// See [`register`].
+pub struct TrapGuard {
+    /// Index of the trap in [`SLOTS`]
+    slot: usize,
+}
+impl TrapGuard {
+    /// Gets the address that traps
+    pub fn address(&self) -> *const u8 {
+        SLOTS[self.slot].address.load(Ordering::Acquire) as _
+    }
+    /// Gets the address execution is redirected to
+    pub fn detour(&self) -> *const u8 {
+        SLOTS[self.slot].detour.load(Ordering::Acquire) as _
+    }
+}
+impl Drop for TrapGuard {
+    fn drop(&mut self) {
+        let mut registrations = REGISTRATIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        SLOTS[self.slot].address.store(0, Ordering::Release);
+
+        registrations.count -= 1;
+        if registrations.count == 0 {
+            if let Some(previous) = registrations.previous.take() {
+                // Safety: `previous` was the handler before ours was installed
+                if unsafe { libc::sigaction(libc::SIGTRAP, &previous, ptr::null_mut()) } != 0 {
+                    log::warn!(
+                        "unable to restore the previous SIGTRAP handler: {}",
+                        io::Error::last_os_error()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Finds the slot registered for `address`
+fn find(address: usize) -> Option<usize> {
+    SLOTS
+        .iter()
+        .position(|slot| slot.address.load(Ordering::Acquire) == address)
+}
+
+/// Installs [`handle_trap`] as the `SIGTRAP` handler, returning the previous action
+///
+/// # Safety
+///
+/// Replaces the process-wide `SIGTRAP` handler
+unsafe fn install() -> io::Result<libc::sigaction> {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = handle_trap as *const () as usize;
+    action.sa_flags = libc::SA_SIGINFO;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    let mut previous: libc::sigaction = mem::zeroed();
+    if libc::sigaction(libc::SIGTRAP, &action, &mut previous) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    PREVIOUS_HANDLER.store(previous.sa_sigaction, Ordering::Release);
+    PREVIOUS_FLAGS.store(previous.sa_flags as usize, Ordering::Release);
+    Ok(previous)
+}
+
+/// `SIGTRAP` handler that redirects registered traps to their detour
+extern "C" fn handle_trap(signal: i32, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    // Safety: the kernel passes a valid `ucontext_t` to `SA_SIGINFO` handlers
+    let context = unsafe { &mut *(context as *mut libc::ucontext_t) };
+    let rip = &mut context.uc_mcontext.gregs[libc::REG_RIP as usize];
+
+    // Safety: the kernel passes a valid `siginfo_t` to `SA_SIGINFO` handlers
+    let code = unsafe { (*info).si_code };
+
+    #[cfg(feature = "step")]
+    if code == TRAP_TRACE {
+        let stepping = STEPPING.with(|stepping| stepping.replace(0));
+        if stepping != 0 {
+            // Safety: the thread was single-stepping this slot's trap
+            unsafe { finish_step(&SLOTS[stepping - 1], context) };
+            return;
+        }
+    }
+
+    // `int3` traps after it runs, so the instruction pointer is already past it
+    let address = if code == TRAP_HWBKPT {
+        *rip as usize
+    } else {
+        (*rip as usize).wrapping_sub(1)
+    };
+    if let Some(slot) = find(address) {
+        #[cfg(feature = "step")]
+        if SLOTS[slot].on_step.load(Ordering::Acquire) != 0 {
+            // Run the original instruction with the trap flag set, so the thread traps again right after it
+            // Safety: `register_step` requires the page to be writable
+            unsafe {
+                (address as *mut u8).write(SLOTS[slot].original.load(Ordering::Acquire) as u8)
+            };
+            *rip = address as _;
+            context.uc_mcontext.gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+            STEPPING.with(|stepping| stepping.set(slot + 1));
+            return;
+        }
+        *rip = SLOTS[slot].detour.load(Ordering::Acquire) as _;
+        return;
+    }
+
+    // Not one of ours, so let the previous handler deal with it
+    match PREVIOUS_HANDLER.load(Ordering::Acquire) {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => unsafe {
+            // The signal is blocked while it's being handled, so this takes effect (and usually kills the process) once we return
+            libc::signal(libc::SIGTRAP, libc::SIG_DFL);
+            libc::raise(libc::SIGTRAP);
+        },
+        previous => unsafe {
+            if PREVIOUS_FLAGS.load(Ordering::Acquire) as i32 & libc::SA_SIGINFO != 0 {
+                let previous: extern "C" fn(i32, *mut libc::siginfo_t, *mut libc::c_void) =
+                    mem::transmute(previous);
+                previous(signal, info, context as *mut _ as _);
+            } else {
+                let previous: extern "C" fn(i32) = mem::transmute(previous);
+                previous(signal);
+            }
+        },
+    }
+}
+
+/// Ends a single step of `slot`'s instruction: stops stepping, puts the `int3` back and reports where execution continues
+///
+/// # Safety
+///
+/// Must only be called from the handler, for the slot the thread was stepping
+#[cfg(feature = "step")]
+unsafe fn finish_step(slot: &Slot, context: &mut libc::ucontext_t) {
+    context.uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+
+    // The trap may have been unregistered while the instruction ran, in which case the `int3` must stay gone
+    let address = slot.address.load(Ordering::Acquire);
+    let on_step = slot.on_step.load(Ordering::Acquire);
+    if address == 0 || on_step == 0 {
+        return;
+    }
+    (address as *mut u8).write(0xcc);
+
+    let on_step: step::StepCallback = mem::transmute(on_step);
+    on_step(&step::Step {
+        address: address as _,
+        next: context.uc_mcontext.gregs[libc::REG_RIP as usize] as _,
+    });
+}
+
+// The tests run generated code from allocated executable memory
+#[cfg(all(test, feature = "trampolines"))]
+mod tests {
+    use crate::code::x64::ret_const;
+    use crate::test_util::TestFn;
+    use crate::trap::{register, TrapError};
+
+    #[test]
+    /// Tests that executing a registered `int3` runs the detour instead
+    fn test_int3_redirect() {
+        // int3; ret
+        let target = TestFn::<extern "C" fn() -> u64>::new(&[0xcc, 0xc3]);
+        let other = TestFn::<extern "C" fn() -> u64>::new(&[0xcc, 0xc3]);
+        let detour = TestFn::<extern "C" fn() -> u64>::new(&ret_const(42));
+
+        let guard = register(target.ptr(), detour.ptr()).unwrap();
+        assert_eq!(guard.address(), target.ptr());
+        assert!(matches!(
+            register(target.ptr(), detour.ptr()),
+            Err(TrapError::AlreadyRegistered(_))
+        ));
+
+        // the handler is shared, and stays installed until the last registration is dropped
+        let other_guard = register(other.ptr(), detour.ptr()).unwrap();
+        assert_eq!(unsafe { target.get() }(), 42);
+        drop(other_guard);
+        assert_eq!(unsafe { target.get() }(), 42);
+
+        drop(guard);
+    }
+}
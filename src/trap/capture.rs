@@ -0,0 +1,248 @@
+//! # Capture
+//!
+//! This module contains the int3-assisted capture mode for self-modifying targets.
+//!
+//! Functions that decrypt or rewrite their own prologue at runtime can't be patched before they first run: the patcher would save the bytes as they are *before* the rewrite.
+//! [`Capture`] puts an `int3` on the first byte of such a function and records the bytes that follow the first time it runs, so the original can be captured when it's actually in its final form.
+//! Once [`CaptureGuard::captured`] returns the bytes, drop the guard and patch the function as usual, e.g. with [`CodePatcher`](crate::patcher::code::CodePatcher) (which reads the now current bytes) or with
+//! [`BytePatcher::patch_with_restore`](crate::patcher::byte::BytePatcher::patch_with_restore) to restore exactly the captured bytes.
+//!
+//! The first instruction is single-stepped with a [`StepHook`], so the bytes are recorded right after it runs. The first byte itself is always the byte that was there when the capture was installed,
+//! since it's under the `int3`. A function that rewrites its first byte removes the `int3` and is never captured.
+
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use thiserror::Error;
+
+use super::step::{Step, StepError, StepHook, StepHookGuard};
+
+/// Maximum number of captures that can be installed at once
+pub const MAX_CAPTURES: usize = 16;
+/// Maximum number of bytes a single capture can record
+pub const MAX_CAPTURE_LEN: usize = 32;
+
+/// [`Slot::state`] of a slot waiting for its function to run
+const WAITING: u8 = 0;
+/// [`Slot::state`] of a slot whose bytes are being recorded
+const RECORDING: u8 = 1;
+/// [`Slot::state`] of a slot whose bytes have been recorded
+const CAPTURED: u8 = 2;
+
+#[derive(Debug, Error)]
+/// Error types for [`Capture`]
+pub enum CaptureError {
+    /// More than [`MAX_CAPTURE_LEN`] bytes were requested
+    #[error("Unable to capture {0} bytes")]
+    TooLong(usize),
+    /// Every one of the [`MAX_CAPTURES`] slots is in use
+    #[error("Too many captures are installed")]
+    TooManyCaptures,
+    /// Error installing the `int3`
+    #[error("{0}")]
+    StepError(#[from] StepError),
+}
+
+/// An installed capture. The slot is free while `address` is 0
+struct Slot {
+    /// Address of the captured function
+    address: AtomicUsize,
+    /// Number of bytes to record
+    len: AtomicUsize,
+    /// Byte under the `int3`
+    first: AtomicU8,
+    /// Whether the bytes have been recorded yet
+    state: AtomicU8,
+    /// Recorded bytes
+    bytes: [AtomicU8; MAX_CAPTURE_LEN],
+}
+
+/// Installed captures. These are atomics rather than behind a lock so [`record`] can use them from the `SIGTRAP` handler
+static SLOTS: [Slot; MAX_CAPTURES] = [const {
+    Slot {
+        address: AtomicUsize::new(0),
+        len: AtomicUsize::new(0),
+        first: AtomicU8::new(0),
+        state: AtomicU8::new(WAITING),
+        bytes: [const { AtomicU8::new(0) }; MAX_CAPTURE_LEN],
+    }
+}; MAX_CAPTURES];
+/// Locked while claiming or freeing a slot
+static CLAIM: Mutex<()> = Mutex::new(());
+
+/// Records the bytes of a function the first time it runs
+pub struct Capture {
+    /// Function to capture
+    address: *const u8,
+    /// Number of bytes to record
+    len: usize,
+}
+impl Capture {
+    /// Creates a new capture of the first `len` bytes at `address`
+    pub fn new(address: *const u8, len: usize) -> Self {
+        Self { address, len }
+    }
+    /// Writes an `int3` over the first byte of the function, waiting for it to run
+    ///
+    /// # Safety
+    ///
+    /// See [`StepHook::install`]. `address` must also be valid for reads of `len` bytes whenever the function runs
+    pub unsafe fn install(&self) -> Result<CaptureGuard, CaptureError> {
+        if self.len > MAX_CAPTURE_LEN {
+            return Err(CaptureError::TooLong(self.len));
+        }
+
+        let slot = {
+            let _claim = CLAIM.lock().unwrap();
+            let slot = SLOTS
+                .iter()
+                .position(|slot| slot.address.load(Ordering::Acquire) == 0)
+                .ok_or(CaptureError::TooManyCaptures)?;
+
+            // The rest of the slot must be visible before the address, since `record` looks the slot up by address
+            SLOTS[slot].len.store(self.len, Ordering::Release);
+            SLOTS[slot]
+                .first
+                .store(self.address.read(), Ordering::Release);
+            SLOTS[slot].state.store(WAITING, Ordering::Release);
+            SLOTS[slot]
+                .address
+                .store(self.address as usize, Ordering::Release);
+            slot
+        };
+
+        match StepHook::new(self.address, record).install() {
+            Ok(step) => Ok(CaptureGuard { slot, _step: step }),
+            Err(e) => {
+                SLOTS[slot].address.store(0, Ordering::Release);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Records the bytes of the function that was just stepped, unless they've already been recorded
+///
+/// Runs in the `SIGTRAP` handler, so it only touches atomics and the function's memory.
+fn record(step: &Step) {
+    let Some(slot) = SLOTS
+        .iter()
+        .find(|slot| slot.address.load(Ordering::Acquire) == step.address as usize)
+    else {
+        return;
+    };
+    if slot
+        .state
+        .compare_exchange(WAITING, RECORDING, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+
+    // The first byte is the `int3` again by now
+    slot.bytes[0].store(slot.first.load(Ordering::Acquire), Ordering::Relaxed);
+    for (i, byte) in slot
+        .bytes
+        .iter()
+        .enumerate()
+        .take(slot.len.load(Ordering::Acquire))
+        .skip(1)
+    {
+        // Safety: `Capture::install` requires the function to be readable while it runs
+        byte.store(
+            unsafe { step.address.add(i).read_volatile() },
+            Ordering::Relaxed,
+        );
+    }
+    slot.state.store(CAPTURED, Ordering::Release);
+}
+
+/// Guard for an installed capture. The `int3` is removed when the guard is dropped
+///
+/// Every run of the function traps until then, so drop it as soon as the bytes are captured.
+pub struct CaptureGuard {
+    /// Index of the capture in [`SLOTS`]
+    slot: usize,
+    /// Hook stepping the function's first instruction
+    _step: StepHookGuard,
+}
+impl CaptureGuard {
+    /// Gets the address of the captured function
+    pub fn address(&self) -> *const u8 {
+        SLOTS[self.slot].address.load(Ordering::Acquire) as _
+    }
+    /// Gets the bytes of the function as they were the first time it ran, or `None` if it hasn't run yet
+    pub fn captured(&self) -> Option<Vec<u8>> {
+        let slot = &SLOTS[self.slot];
+        if slot.state.load(Ordering::Acquire) != CAPTURED {
+            return None;
+        }
+        Some(
+            slot.bytes[..slot.len.load(Ordering::Acquire)]
+                .iter()
+                .map(|byte| byte.load(Ordering::Relaxed))
+                .collect(),
+        )
+    }
+}
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        // The step hook is dropped after this, so a trap in the meantime finds no slot and records nothing
+        let _claim = CLAIM
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        SLOTS[self.slot].address.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trap::capture::{Capture, CaptureError, MAX_CAPTURE_LEN};
+
+    #[test]
+    /// Tests that the bytes are captured as they are when the function first runs, not when the capture is installed
+    fn test_capture() {
+        // Note: the code lives in a private mapping so changing its permissions can't affect other tests' allocations
+        let code = mmap::MemoryMap::new(
+            region::page::size(),
+            &[
+                mmap::MapOption::MapReadable,
+                mmap::MapOption::MapWritable,
+                mmap::MapOption::MapExecutable,
+            ],
+        )
+        .unwrap();
+        // mov eax, 42; ret
+        unsafe {
+            code.data()
+                .copy_from([0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3].as_ptr(), 6)
+        };
+        let function: extern "C" fn() -> u32 = unsafe { std::mem::transmute(code.data()) };
+
+        let guard = unsafe { Capture::new(code.data(), 6).install() }.unwrap();
+        assert_eq!(guard.address(), code.data() as *const u8);
+        assert_eq!(guard.captured(), None);
+
+        // the function rewrites its prologue before it first runs (mov eax, 43)
+        unsafe { code.data().add(1).write(0x2b) };
+        assert_eq!(function(), 43);
+        assert_eq!(
+            guard.captured().unwrap(),
+            [0xb8, 0x2b, 0x00, 0x00, 0x00, 0xc3]
+        );
+
+        // later rewrites aren't captured
+        unsafe { code.data().add(1).write(0x2c) };
+        assert_eq!(function(), 44);
+        assert_eq!(guard.captured().unwrap()[1], 0x2b);
+
+        drop(guard);
+        assert_eq!(unsafe { code.data().read() }, 0xb8);
+        assert_eq!(function(), 44);
+
+        assert!(matches!(
+            unsafe { Capture::new(code.data(), MAX_CAPTURE_LEN + 1).install() },
+            Err(CaptureError::TooLong(_))
+        ));
+    }
+}
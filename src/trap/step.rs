@@ -0,0 +1,152 @@
+//! # Step Hook
+//!
+//! This module contains a hook for tracing a single instruction. Each time execution reaches the hooked instruction, it runs exactly once under the trap flag and a callback is told where execution continues.
+//! Unlike the other hooks, nothing is redirected: the instruction runs in place, which also makes branches, calls and returns report their real destination.
+//!
+//! While an instruction is being stepped its original byte is back in place, so another thread running through it at the same moment isn't traced.
+//! The page containing the instruction is kept writable and executable while hooked, which kernels that refuse writable and executable pages don't allow.
+//!
+//! This is meant for debugging and tracing; every step costs two signals.
+
+use std::ptr;
+
+use region::{ProtectGuard, Protection};
+use thiserror::Error;
+
+use super::{register_step, TrapError, TrapGuard};
+
+/// A single step of a hooked instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// Address of the instruction that ran
+    pub address: *const u8,
+    /// Address execution continues at
+    pub next: *const u8,
+}
+
+/// Called after each step, from the `SIGTRAP` handler
+///
+/// Callbacks run inside a signal handler, so they must be async-signal-safe (e.g. only touch atomics).
+pub type StepCallback = fn(&Step);
+
+#[derive(Debug, Error)]
+/// Error types for [`StepHook`]
+pub enum StepError {
+    /// Error registering the trap
+    #[error("{0}")]
+    TrapError(#[from] TrapError),
+    /// Error making the instruction writable
+    #[error("Error setting memory protections")]
+    ProtectionError(#[from] region::Error),
+}
+
+/// Hook that single-steps the instruction at an address and reports each step
+pub struct StepHook {
+    /// Instruction to step
+    address: *const u8,
+    /// Called after each step
+    callback: StepCallback,
+}
+impl StepHook {
+    /// Creates a new step hook for the instruction at `address`
+    pub fn new(address: *const u8, callback: StepCallback) -> Self {
+        Self { address, callback }
+    }
+    /// Starts stepping the instruction by writing an `int3` over its first byte
+    ///
+    /// # Safety
+    ///
+    /// `address` must be the start of an instruction in code that isn't being executed while the `int3` is written
+    pub unsafe fn install(&self) -> Result<StepHookGuard, StepError> {
+        let protection = region::protect_with_handle(self.address, 1, Protection::all())?;
+        let original = self.address.read();
+        let trap = register_step(self.address, original, self.callback)?;
+        ptr::write_volatile(self.address as *mut u8, 0xcc);
+
+        Ok(StepHookGuard {
+            address: self.address,
+            original,
+            _trap: trap,
+            _protection: protection,
+        })
+    }
+}
+
+/// Guard for step hooks. The instruction is put back when the guard is dropped
+///
+/// Don't drop the guard while another thread may be stepping the instruction.
+pub struct StepHookGuard {
+    /// Address of the stepped instruction
+    address: *const u8,
+    /// Byte the `int3` replaced
+    original: u8,
+    /// Registration of the trap. Dropped after the original byte is written back
+    _trap: TrapGuard,
+    /// Keeps the instruction writable while hooked. Dropped last, restoring the original permissions
+    _protection: ProtectGuard,
+}
+impl StepHookGuard {
+    /// Gets the address of the stepped instruction
+    pub fn address(&self) -> *const u8 {
+        self.address
+    }
+}
+impl Drop for StepHookGuard {
+    fn drop(&mut self) {
+        // Safety: the page is still writable, since `_protection` hasn't been dropped yet
+        unsafe { ptr::write_volatile(self.address as *mut u8, self.original) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::trap::step::{Step, StepHook};
+
+    /// Number of steps taken
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+    /// Where execution continued after the last step
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Records each step
+    fn record(step: &Step) {
+        NEXT.store(step.next as usize, Ordering::SeqCst);
+        STEPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    /// Tests that the instruction is stepped every time it runs, and no longer once unhooked
+    fn test_step() {
+        // Note: the code lives in a private mapping so changing its permissions can't affect other tests' allocations
+        let code = mmap::MemoryMap::new(
+            region::page::size(),
+            &[
+                mmap::MapOption::MapReadable,
+                mmap::MapOption::MapWritable,
+                mmap::MapOption::MapExecutable,
+            ],
+        )
+        .unwrap();
+        // mov eax, 42; ret
+        unsafe {
+            code.data()
+                .copy_from([0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3].as_ptr(), 6)
+        };
+        let function: extern "C" fn() -> u32 = unsafe { std::mem::transmute(code.data()) };
+
+        let guard = unsafe { StepHook::new(code.data(), record).install() }.unwrap();
+        assert_eq!(guard.address(), code.data() as *const u8);
+        assert_eq!(function(), 42);
+        assert_eq!(STEPS.load(Ordering::SeqCst), 1);
+        assert_eq!(NEXT.load(Ordering::SeqCst), code.data() as usize + 5);
+
+        // the int3 is put back after each step
+        assert_eq!(function(), 42);
+        assert_eq!(STEPS.load(Ordering::SeqCst), 2);
+
+        drop(guard);
+        assert_eq!(function(), 42);
+        assert_eq!(STEPS.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,9 @@
+//! # CDecl
+//!
+//! This module provides a call wrapper for the cdecl calling convention
+
+use super::convention::cdecl::CDeclWrapperGenerator;
+use super::ConventionWrapper;
+
+/// Call wrapper for functions using the cdecl calling convention
+pub type CDeclWrapper = ConventionWrapper<CDeclWrapperGenerator>;
@@ -2,6 +2,13 @@
 //!
 //! This takes code execution from a given calling convention specification and standardizes it
 
+use std::marker::PhantomData;
+
+use crate::alloc::{allocate_executable, proximity::ProximityError, ExecutableMemory, Finalized};
+use crate::code::x64::{needs_endbr64, ENDBR64};
+
+use self::convention::WrapperGenerator;
+
 pub mod cdecl;
 pub mod convention;
 
@@ -40,8 +47,94 @@ pub unsafe trait CallWrapper {
 ///
 /// Guards must clean up fully even if [`drop`] is not called
 pub unsafe trait CallWrapperGuard: Sized {
+    /// Errors that could happen while cleaning up
+    type Error;
+
+    /// Gets the entry point of the wrapper, which is where execution from `src` should be sent
+    fn entry(&self) -> *const u8;
     /// Drops the guard
     fn drop(self) {
         // most guards will cleanup in [`Drop::drop`]
     }
+    /// Drops the guard, returning any error that happened while cleaning up
+    ///
+    /// By default this is [`CallWrapperGuard::drop`], which cleans up on a best-effort basis and never fails.
+    fn try_drop(self) -> Result<(), Self::Error> {
+        CallWrapperGuard::drop(self);
+        Ok(())
+    }
+}
+
+/// Call wrapper that uses a [`WrapperGenerator`] to generate a stub near the source location
+pub struct ConventionWrapper<G: WrapperGenerator> {
+    /// Placeholder for the generator
+    _generator: PhantomData<G>,
+}
+impl<G: WrapperGenerator> ConventionWrapper<G> {
+    /// Creates a new ConventionWrapper
+    pub fn new() -> Self {
+        Self {
+            _generator: PhantomData,
+        }
+    }
+}
+impl<G: WrapperGenerator> Default for ConventionWrapper<G> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+unsafe impl<G: WrapperGenerator> CallWrapper for ConventionWrapper<G> {
+    type Error = ProximityError;
+    type Guard<'a> = ConventionWrapperGuard where Self: 'a;
+
+    unsafe fn activate(
+        &self,
+        src: *const u8,
+        dst: *const u8,
+    ) -> Result<Self::Guard<'_>, Self::Error> {
+        // The stub is reached by an indirect jump, so it may need to start with `endbr64`
+        let mut code = if needs_endbr64() {
+            ENDBR64.to_vec()
+        } else {
+            Vec::new()
+        };
+        code.extend(G::generate(dst as _));
+
+        // Allocate the stub close to the source so it can be reached with short jumps
+        let mut stub = allocate_executable(src as _, code.len())?;
+        stub.copy_from_slice(&code);
+        let stub = stub.finalize().map_err(ProximityError::RegionError)?;
+
+        Ok(ConventionWrapperGuard { stub })
+    }
+}
+
+/// Guard for [`ConventionWrapper`]. The generated stub is freed when the guard is dropped
+pub struct ConventionWrapperGuard {
+    /// Executable memory containing the generated stub
+    stub: ExecutableMemory<Finalized>,
+}
+unsafe impl CallWrapperGuard for ConventionWrapperGuard {
+    type Error = ProximityError;
+
+    fn entry(&self) -> *const u8 {
+        self.stub.exec_ptr()
+    }
+    fn try_drop(self) -> Result<(), Self::Error> {
+        self.stub.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wrapper::cdecl::CDeclWrapper;
+    use crate::wrapper::{CallWrapper, CallWrapperGuard};
+
+    #[test]
+    /// Tests that dropping a wrapper guard explicitly frees its stub without errors
+    fn test_try_drop() {
+        let wrapper = CDeclWrapper::new();
+        let guard = unsafe { wrapper.activate(test_try_drop as *const u8, 0x1234 as _) }.unwrap();
+        guard.try_drop().unwrap();
+    }
 }
@@ -0,0 +1,183 @@
+//! End-to-end tests that hook real functions and call them
+
+use std::hint::black_box;
+#[cfg(feature = "trampolines")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[cfg(feature = "trampolines")]
+use libhook::code::x64::jmp_abs;
+#[cfg(feature = "trampolines")]
+use libhook::hook::detour::DetourHook;
+use libhook::hook::jmphook::JmpHook;
+#[cfg(feature = "trampolines")]
+use libhook::hook::jmphook::NearJumpEncoder;
+use libhook::hook::{Hook, HookGuard};
+use libhook::patcher::byte::BytePatcher;
+#[cfg(feature = "trampolines")]
+use libhook::patcher::code::X64Patcher;
+use libhook::patcher::mem::PermissionWrapper;
+#[cfg(feature = "trampolines")]
+use libhook::patcher::PatchGuard;
+#[cfg(feature = "trampolines")]
+use libhook::trampoline::UsizeFn;
+
+/// Serializes tests, since hooking changes the protection of code pages that other tests may be patching
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Function hooked by [`test_jmp_hook`]
+///
+/// The loop keeps the function large enough for a 14-byte absolute jump
+#[inline(never)]
+extern "C" fn jmp_target(value: usize) -> usize {
+    let mut total = black_box(value);
+    for i in 0..black_box(4) {
+        total = total.wrapping_mul(3).wrapping_add(i);
+    }
+    total
+}
+
+/// Function hooked by [`test_code_patcher`]
+///
+/// The loop keeps the function large enough for a 14-byte absolute jump
+#[cfg(feature = "trampolines")]
+#[inline(never)]
+extern "C" fn code_target(value: usize) -> usize {
+    let mut total = black_box(value);
+    for i in 0..black_box(4) {
+        total = total.wrapping_mul(5).wrapping_add(i);
+    }
+    total
+}
+
+/// Function hooked by [`test_detour_hook`]
+///
+/// The loop keeps the function large enough for a 14-byte absolute jump
+#[cfg(feature = "trampolines")]
+#[inline(never)]
+extern "C" fn detour_target(value: usize) -> usize {
+    let mut total = black_box(value);
+    for i in 0..black_box(4) {
+        total = total.wrapping_mul(7).wrapping_add(i);
+    }
+    total
+}
+
+/// Trampoline that [`calling_detour`] calls through to
+#[cfg(feature = "trampolines")]
+static ORIGINAL: AtomicUsize = AtomicUsize::new(0);
+/// Number of times [`calling_detour`] has run
+#[cfg(feature = "trampolines")]
+static DETOUR_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Detour that counts its calls and returns the original function's result
+#[cfg(feature = "trampolines")]
+extern "C" fn calling_detour(value: usize) -> usize {
+    DETOUR_CALLS.fetch_add(1, Ordering::Relaxed);
+    let original = ORIGINAL.load(Ordering::Acquire);
+    let original: extern "C" fn(usize) -> usize = unsafe { std::mem::transmute(original) };
+    original(value)
+}
+
+/// Detour that hooked functions are redirected to
+extern "C" fn detour(value: usize) -> usize {
+    value + 1000
+}
+
+#[test]
+/// Tests that a jmp hook redirects calls to the detour until it's unhooked
+fn test_jmp_hook() {
+    let _lock = LOCK.lock().unwrap();
+
+    // call through a black-boxed pointer so the call can't be inlined or folded
+    let target: extern "C" fn(usize) -> usize = black_box(jmp_target);
+    let expected = target(5);
+    assert_ne!(expected, detour(5));
+
+    let hook = JmpHook::new(PermissionWrapper::new(BytePatcher::new()));
+    let guard = unsafe { hook.hook(target as *const u8, detour as *const u8) }.unwrap();
+
+    assert_eq!(target(5), 1005);
+
+    guard.unhook();
+
+    assert_eq!(target(5), expected);
+}
+
+#[cfg(feature = "trampolines")]
+#[test]
+/// Tests that the original function is still callable while a code patch redirects it
+fn test_code_patcher() {
+    let _lock = LOCK.lock().unwrap();
+
+    let target: extern "C" fn(usize) -> usize = black_box(code_target);
+    let expected = target(5);
+    assert_ne!(expected, detour(5));
+
+    let patcher = unsafe {
+        X64Patcher::new(
+            BytePatcher::new(),
+            target as *const u8,
+            jmp_abs(detour as *const () as usize),
+        )
+    }
+    .unwrap();
+    let original = unsafe { UsizeFn::new(patcher.original().unwrap()) };
+
+    let guard = patcher.patch().unwrap();
+
+    assert_eq!(target(5), 1005);
+    // the trampoline runs the relocated prologue and jumps back into the function
+    assert_eq!(original.call(5), expected);
+
+    guard.restore();
+
+    assert_eq!(target(5), expected);
+    assert_eq!(original.call(5), expected);
+}
+
+#[cfg(feature = "trampolines")]
+#[test]
+/// Tests that a detour can call the original function through the hook's trampoline
+fn test_detour_hook() {
+    let _lock = LOCK.lock().unwrap();
+
+    let target: extern "C" fn(usize) -> usize = black_box(detour_target);
+    let expected = target(5);
+
+    let hook = DetourHook::new(BytePatcher::new());
+    let guard = unsafe { hook.hook(target as *const u8, calling_detour as *const u8) }.unwrap();
+    ORIGINAL.store(guard.original() as usize, Ordering::Release);
+
+    // the detour runs, and the original's result makes it back through it
+    assert_eq!(target(5), expected);
+    assert_eq!(DETOUR_CALLS.load(Ordering::Relaxed), 1);
+
+    guard.unhook();
+
+    assert_eq!(target(5), expected);
+    assert_eq!(DETOUR_CALLS.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(feature = "trampolines")]
+#[test]
+/// Tests that a detour hook can write a near jump, relocating only the instructions it overwrites
+fn test_detour_hook_near() {
+    let _lock = LOCK.lock().unwrap();
+
+    let target: extern "C" fn(usize) -> usize = black_box(detour_target);
+    let expected = target(5);
+
+    let hook = DetourHook::with_encoder(BytePatcher::new(), NearJumpEncoder);
+    let guard = unsafe { hook.hook(target as *const u8, detour as *const u8) }.unwrap();
+    // the detour is in the same binary, so it's in range of a `jmp rel32`
+    assert_eq!(unsafe { (target as *const u8).read() }, 0xe9);
+    assert_eq!(target(5), 1005);
+
+    let original: extern "C" fn(usize) -> usize = unsafe { std::mem::transmute(guard.original()) };
+    assert_eq!(original(5), expected);
+
+    guard.unhook();
+
+    assert_eq!(target(5), expected);
+}